@@ -0,0 +1,45 @@
+/* Size benchmark: counts the entries and adds up the costs of a cache holding
+ * 4M items, serially and with `Cache::len_parallel` and
+ * `Cache::cost_used_parallel`.
+ *
+ * Needs the `rayon` feature: cargo bench --features rayon --bench len_parallel
+ */
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use memory_cache_rust::cache::{Cache, Config};
+
+const SIZE: u64 = 4_000_000;
+
+fn len_parallel(c: &mut Criterion) {
+    let cache: Cache<u64, u64> = Cache::with_config(Config {
+        numb_counters: (SIZE * 10) as i64,
+        max_cost: SIZE as i64,
+        ..Default::default()
+    });
+    let guard = cache.guard();
+    for i in 0..SIZE {
+        cache.set(i, i, 1, &guard);
+    }
+
+    let mut group = c.benchmark_group("len");
+    group.sample_size(10);
+
+    group.bench_function("serial", |b| {
+        b.iter(|| cache.len(&guard));
+    });
+    group.bench_function("parallel", |b| {
+        b.iter(|| cache.len_parallel(&guard));
+    });
+    group.bench_function("cost_used", |b| {
+        b.iter(|| cache.cost_used(&guard));
+    });
+    group.bench_function("cost_used_parallel", |b| {
+        b.iter(|| cache.cost_used_parallel(&guard));
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, len_parallel);
+criterion_main!(benches);
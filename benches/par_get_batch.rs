@@ -0,0 +1,44 @@
+/* Bulk lookup benchmark: probes 1M keys of a full cache, once with a serial
+ * get_cloned loop and once with `Cache::par_get_batch`.
+ *
+ * Needs the `rayon` feature: cargo bench --features rayon --bench par_get_batch
+ */
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use memory_cache_rust::cache::{Cache, Config};
+
+const SIZE: u64 = 1_000_000;
+
+fn par_get_batch(c: &mut Criterion) {
+    let cache: Cache<u64, u64> = Cache::with_config(Config {
+        numb_counters: (SIZE * 10) as i64,
+        max_cost: SIZE as i64,
+        ..Default::default()
+    });
+    let guard = cache.guard();
+    for i in 0..SIZE {
+        cache.set(i, i, 1, &guard);
+    }
+    drop(guard);
+    let keys: Vec<u64> = (0..SIZE).collect();
+
+    let mut group = c.benchmark_group("get_batch");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(SIZE));
+
+    group.bench_function("serial", |b| {
+        b.iter(|| {
+            let guard = cache.guard();
+            keys.iter().map(|k| cache.get_cloned(k, &guard)).collect::<Vec<_>>()
+        });
+    });
+    group.bench_function("parallel", |b| {
+        b.iter(|| cache.par_get_batch(&keys));
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, par_get_batch);
+criterion_main!(benches);
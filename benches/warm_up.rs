@@ -0,0 +1,37 @@
+/* Warm-up benchmark: fills an empty cache with 1M keys, once with shard maps
+ * that grow from empty and once with them sized up front via
+ * `Config::initial_capacity`.
+ */
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use memory_cache_rust::cache::{Cache, Config};
+
+const SIZE: u64 = 1_000_000;
+
+fn warm_up(c: &mut Criterion) {
+    let mut group = c.benchmark_group("warm_up");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(SIZE));
+
+    for initial_capacity in [0, SIZE as usize] {
+        group.bench_function(BenchmarkId::from_parameter(initial_capacity), |b| {
+            b.iter(|| {
+                let cache: Cache<u64, u64> = Cache::with_config(Config {
+                    numb_counters: (SIZE * 10) as i64,
+                    initial_capacity,
+                    ..Default::default()
+                });
+                let guard = cache.guard();
+                for i in 0..SIZE {
+                    cache.set(i, i, 1, &guard);
+                }
+            });
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, warm_up);
+criterion_main!(benches);
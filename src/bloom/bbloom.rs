@@ -9,10 +9,11 @@
 
 
 
-use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-const MASK: [u8; 8] = [1, 2, 4, 8, 16, 32, 64, 128];
+use serde::{Deserialize, Serialize};
 
+#[derive(Clone)]
 pub struct Bloom {
     bitset: Vec<i64>,
     elem_num: u64,
@@ -86,23 +87,18 @@ impl Bloom {
         self.add(hash);
         true
     }
+    /// SetLocs returns the number of bits each hash sets.
+    pub fn set_locs(&self) -> u64 {
+        self.set_locs
+    }
     /// Clear resets the Bloom filter.
     pub fn clear(&mut self) {
         self.bitset = vec![0; self.bitset.len()]
     }
     /// Set sets the bit[idx] of bitset.
     pub fn set(&mut self, idx: u64) {
-        // let b = *self.bitset[(idx >> 6) as usize];
-
-        // let ptr:*mut [i64] =  self.bitset as *mut [i64];
-        let mut ptr: *mut i64 = self.bitset.as_mut_ptr();
-        unsafe {
-            let step = idx >> 6;//((idx >> 6) + ((idx % 64) >> 3));
-            ptr = ptr.wrapping_offset(step as isize);
-
-            *ptr |= MASK[(idx % 8) as usize] as i64;
-        };
-
+        // every bit of the word is used, not only the low byte
+        self.bitset[(idx >> 6) as usize] |= 1 << (idx % 64);
     }
     /// Size makes Bloom filter with as bitset of size sz.
     pub fn size(&mut self, sz: u64) {
@@ -113,7 +109,7 @@ impl Bloom {
     }
     /// Has checks if bit(s) for entry hash is/are set,
     /// returns true if the hash was added to the Bloom Filter.
-    pub fn has(&mut self, hash: u64) -> bool {
+    pub fn has(&self, hash: u64) -> bool {
         let h = hash >> self.shift;
         let l = hash << self.shift >> self.shift;
         for i in 0..self.set_locs {
@@ -125,18 +121,21 @@ impl Bloom {
         true
     }
     /// IsSet checks if bit[idx] of bitset is set, returns true/false.
-    pub fn isset(&mut self, idx: u64) -> bool {
-        let mut ptr: *mut i64 = self.bitset.as_mut_ptr();
-        // if ((idx >> 6) + ((idx % 64) >> 3)) as usize > self.bitset.len() {
-        //     return false;
-        // }
-        unsafe {
-            let step = idx >> 6 /*+ ((idx % 64) >> 3))*/;
-            ptr = ptr.wrapping_offset(step as isize);
+    pub fn isset(&self, idx: u64) -> bool {
+        self.bitset[(idx >> 6) as usize] >> (idx % 64) & 1 == 1
+    }
+    /// EstimatedFillRatio returns the fraction of the bits that are set.
+    pub fn estimated_fill_ratio(&self) -> f64 {
+        if self.bitset.is_empty() {
+            return 0.0;
         }
-
-        let r = unsafe { (*ptr >> (idx % 8)) & 1 };
-        r == 1
+        let ones: u64 = self.bitset.iter().map(|word| word.count_ones() as u64).sum();
+        ones as f64 / (self.bitset.len() << 6) as f64
+    }
+    /// EstimatedFpRate returns the chance that has reports a hash that was never
+    /// added: the chance that all set_locs bits it checks happen to be set.
+    pub fn estimated_fp_rate(&self) -> f64 {
+        self.estimated_fill_ratio().powi(self.set_locs as i32)
     }
     /*  fn json_decode(&mut self, dbData: &[u8]) -> Self {
           let data = serde_json::from_slice::<BloomJsonExport>(dbData);
@@ -160,6 +159,77 @@ impl Bloom {
     }
 }
 
+/// AtomicBloom is a Bloom filter that can be shared between threads: bits are set
+/// with atomic ors, so add and has only need `&self`. It is sized like Bloom.
+pub struct AtomicBloom {
+    bitset: Vec<AtomicU64>,
+    size: u64,
+    set_locs: u64,
+    shift: u64,
+}
+
+impl AtomicBloom {
+    ///  returns a new bloom filter.
+    pub fn new(num_entries: f64, wrongs: f64) -> Self {
+        let (entries, locs) = if wrongs < 1.0 {
+            calc_size_by_wrong_positives(num_entries, wrongs)
+        } else {
+            (num_entries as u64, wrongs as u64)
+        };
+
+        let (size, exponent) = getSize(entries);
+        AtomicBloom {
+            bitset: (0..size >> 6).map(|_| AtomicU64::new(0)).collect(),
+            size: size - 1,
+            set_locs: locs,
+            shift: 64 - exponent,
+        }
+    }
+
+    /// Add adds hash to the filter.
+    pub fn add(&self, hash: u64) {
+        let h = hash >> self.shift;
+        let l = hash << self.shift >> self.shift;
+        for i in 0..self.set_locs {
+            self.set((h + (i * l)) & self.size);
+        }
+    }
+
+    /// AddIfNotHas only Adds hash, if it's not present in the bloomfilter.
+    /// Returns true if hash was added. Two threads racing on the same hash may
+    /// both see true.
+    pub fn add_if_not_has(&self, hash: u64) -> bool {
+        if self.has(hash) {
+            return false;
+        }
+        self.add(hash);
+        true
+    }
+
+    /// Has checks if bit(s) for entry hash is/are set,
+    /// returns true if the hash was added to the Bloom Filter.
+    pub fn has(&self, hash: u64) -> bool {
+        let h = hash >> self.shift;
+        let l = hash << self.shift >> self.shift;
+        (0..self.set_locs).all(|i| self.isset((h + (i * l)) & self.size))
+    }
+
+    /// Clear resets the Bloom filter.
+    pub fn clear(&self) {
+        for word in self.bitset.iter() {
+            word.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn set(&self, idx: u64) {
+        self.bitset[(idx >> 6) as usize].fetch_or(1 << (idx % 64), Ordering::Relaxed);
+    }
+
+    fn isset(&self, idx: u64) -> bool {
+        self.bitset[(idx >> 6) as usize].load(Ordering::Relaxed) >> (idx % 64) & 1 == 1
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BloomJsonExport {
     filter_set: Vec<u8>,
@@ -184,6 +254,8 @@ fn getSize(mut u_i64: u64) -> (u64, u64) {
 #[cfg(test)]
 mod tests {
     use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
 
     use uuid::Uuid;
 
@@ -234,6 +306,25 @@ mod tests {
                  bf.bitset.len() << 6, cnt, (cnt) as f64 / (N) as f64)
     }
 
+    #[test]
+    fn test_estimated_fp_rate() {
+        let mut bf = Bloom::new(1000.0, 0.01);
+        assert_eq!(bf.estimated_fp_rate(), 0.0);
+
+        let mut last = 0.0;
+        for i in 0..1000u64 {
+            bf.add(mem_hash(&i.to_le_bytes()));
+            if i % 100 == 99 {
+                let rate = bf.estimated_fp_rate();
+                assert!(rate > last, "{} after {} hashes", rate, i + 1);
+                last = rate;
+            }
+        }
+        // the bitset is rounded up to a power of two, so it stays below the target
+        assert!(last <= 0.01, "{}", last);
+        assert!(bf.estimated_fill_ratio() > 0.0 && bf.estimated_fill_ratio() < 0.5);
+    }
+
     #[test]
     fn test_has() {
         let mut bf = Bloom::new((N * 10) as f64, 7.0);
@@ -247,6 +338,41 @@ mod tests {
         assert_eq!(v, true)
     }
 
+    #[test]
+    fn test_atomic_bloom() {
+        let bf = AtomicBloom::new((N * 10) as f64, 7.0);
+        assert!(!bf.has(18272025040905874063));
+        assert!(bf.add_if_not_has(18272025040905874063));
+        assert!(bf.has(18272025040905874063));
+        assert!(!bf.add_if_not_has(18272025040905874063));
+
+        bf.clear();
+        assert!(!bf.has(18272025040905874063));
+    }
+
+    #[test]
+    fn test_atomic_bloom_threads() {
+        let bf = Arc::new(AtomicBloom::new((N * 10) as f64, 7.0));
+        let word_list = Arc::new(worldlist());
+
+        let handles: Vec<_> = (0..8).map(|t| {
+            let bf = bf.clone();
+            let word_list = word_list.clone();
+            thread::spawn(move || {
+                for word in word_list.iter().skip(t).step_by(8) {
+                    bf.add(mem_hash(word));
+                }
+            })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        for word in word_list.iter() {
+            assert!(bf.has(mem_hash(word)));
+        }
+    }
+
     #[test]
     fn oprator_test() {
         //  1 2 4 8 16 32 64
@@ -1,98 +1,18 @@
 use std::any::TypeId;
+use std::hash::BuildHasher;
 use std::mem::size_of;
-use std::ops::Deref;
 
-use xxhash_rust::const_xxh3::xxh3_64 as const_xxh3;
-
-use crate::bloom::rutil::mem_hash;
+use crate::key::CacheKey;
 
 pub type KeyHash<T> = Box<dyn FnMut(T) -> (u64, i64)>;
 
 
 
-pub fn key_to_hash<T: Copy + 'static + std::fmt::Display>(key: & T) -> (u64, u64)
-    where
-
-{
-    let key = *key.deref();
-    if equals::<T, String>() {
-        let v = cast_ref::<_, String>(&key).unwrap();
-        let raw = v.as_bytes();
-        return (mem_hash(raw), const_xxh3(raw) as u64);
-    }
-
-
-    // if equals::<T, Vec<u8>>() {
-    //     let value = unsafe { std::mem::transmute::<T, Vec<u8>>(key) };
-    //
-    //     return (mem_hash(&value), const_xxh3(&value) as i64);
-    // }
-
-
-    if equals::<T, u64>() {
-        let value = cast_ref::<_, u64>(&key).unwrap();
-        return (*value, 0);
-    }
-
-    if equals::<T, usize>() {
-        let value = cast_ref::<_, usize>(&key).unwrap();
-        return (*value as u64, 0);
-    }
-
-
-    if equals::<T, i64>() {
-        let value = cast_ref::<_, i64>(&key).unwrap();
-        return (*value as u64, 0);
-    }
-
-
-    if equals::<T, i32>() {
-        let value = cast_ref::<_, i32>(&key).unwrap();
-
-        return (*value as u64, 0);
-    }
-
-    if equals::<T, u8>() {
-        let value = cast_ref::<_, u8>(&key).unwrap();
-        return (*value as u64, 0);
-    }
-    if equals::<T, u16>() {
-        let value = cast_ref::<_, u16>(&key).unwrap();
-
-        return (*value as u64, 0);
-    }
-    if equals::<T, u32>() {
-        let value = cast_ref::<_, u32>(&key).unwrap();
-
-        return (*value as u64, 0);
-    }
-    if equals::<T, i16>() {
-        let value = cast_ref::<_, i16>(&key).unwrap();
-
-        return (*value as u64, 0);
-    }
-
-    let f = format!("{}",key);
-
-    match cast_ref::<_, String>(&f) {
-        None => {
-            panic!("not suported");
-        }
-        Some(v) => {
-            let raw = v.as_bytes();
-            return (mem_hash(raw), const_xxh3(raw) as u64);
-        }
-    }
-
-
-    // panic!("! Key type not supported")
-
-
-    // if TypeId::of::<T>() == TypeId::of::<[u8]>() {
-    //     let raw = (key) as [u8];
-    //     return (mem_hash_byte(&raw), const_xxh3(&raw) as i64);
-    // }
-    // return (mem_hash(&mut key.into_bytes()), const_xxh3(&* key.into_bytes()) as i64);
+/// key_to_hash returns the key hash and conflict hash a cache with the given
+/// hasher and conflict hash function stores the key under. It is the one routine
+/// `Cache::hash` goes through, see `CacheKey::to_hash_with`.
+pub fn key_to_hash<T: ?Sized + CacheKey, S: BuildHasher>(key: &T, build_hasher: &S, conflict_hash: Option<fn(&[u8]) -> u64>) -> (u64, u64) {
+    key.to_hash_with(build_hasher, conflict_hash)
 }
 
 
@@ -168,9 +88,12 @@ mod tests {
 
     #[test]
     fn test_hash() {
+        let s = crate::DefaultHashBuilder::default();
         let a ="22";
-        let d = key_to_hash(&a);
-        ;
-        println!("{:?}", d);
+        let d = key_to_hash(&a, &s, None);
+        assert_eq!(d, a.to_hash_with(&s, None));
+        assert_eq!(key_to_hash(&String::from("22"), &s, None), d);
+        assert_eq!(key_to_hash(&7i32, &s, None), 7i32.to_hash_with(&s, None));
+        assert_eq!(key_to_hash(&a, &s, Some(|_| 7)).1, 7);
     }
-}
\ No newline at end of file
+}
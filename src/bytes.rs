@@ -0,0 +1,84 @@
+use crate::cache::{Cache, Config};
+
+/// ByteCache is a Cache of byte keys to byte values with a non-generic API: keys
+/// and values go in as slices and come out as owned copies, and guards are taken
+/// internally. It is meant for FFI and other simple callers.
+pub struct ByteCache {
+    cache: Cache<Vec<u8>, Vec<u8>>,
+}
+
+impl Default for ByteCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ByteCache {
+    pub fn new() -> Self {
+        Self::with_config(Config::default())
+    }
+
+    pub fn with_config(c: Config<Vec<u8>, Vec<u8>>) -> Self {
+        ByteCache {
+            cache: Cache::with_config(c),
+        }
+    }
+
+    /// Get returns a copy of the value stored for the key.
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let guard = self.cache.guard();
+        self.cache.get_cloned(key, &guard)
+    }
+
+    /// Set copies the key and value into the cache, see `Cache::set`.
+    pub fn set(&self, key: &[u8], value: &[u8], cost: i64) -> bool {
+        let guard = self.cache.guard();
+        self.cache.set(key.to_vec(), value.to_vec(), cost, &guard)
+    }
+
+    /// Del removes the key from the cache.
+    pub fn del(&self, key: &[u8]) {
+        let guard = self.cache.guard();
+        self.cache.del(key, &guard)
+    }
+
+    /// Inner returns the underlying cache, for the rest of its API.
+    pub fn inner(&self) -> &Cache<Vec<u8>, Vec<u8>> {
+        &self.cache
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytes::ByteCache;
+
+    #[test]
+    fn test_byte_cache() {
+        let cache = ByteCache::new();
+        assert_eq!(cache.get(b"key"), None);
+
+        assert!(cache.set(b"key", b"value", 1));
+        assert_eq!(cache.get(b"key"), Some(b"value".to_vec()));
+
+        assert!(cache.set(b"key", b"other", 1));
+        assert_eq!(cache.get(b"key"), Some(b"other".to_vec()));
+
+        cache.del(b"key");
+        assert_eq!(cache.get(b"key"), None);
+    }
+
+    #[test]
+    fn test_byte_cache_edges() {
+        let cache = ByteCache::new();
+        cache.set(b"", b"empty key", 1);
+        cache.set(b"empty value", b"", 1);
+        assert_eq!(cache.get(b""), Some(b"empty key".to_vec()));
+        assert_eq!(cache.get(b"empty value"), Some(vec![]));
+
+        let key = vec![7u8; 1 << 16];
+        let value: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+        cache.set(&key, &value, 1);
+        assert_eq!(cache.get(&key), Some(value));
+        assert_eq!(cache.get(&key[1..]), None);
+    }
+}
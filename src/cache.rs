@@ -1,20 +1,29 @@
-use std::{ptr, time};
-use std::any::TypeId;
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::ptr;
 use std::fmt::{Debug, Formatter};
-use std::hash::{BuildHasher, Hash, Hasher};
+use std::hash::BuildHasher;
 use std::marker::PhantomData;
-use std::ops::{Add, Deref};
-use std::sync::atomic::{AtomicIsize, Ordering};
+use std::ops::Add;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicIsize, AtomicU64, Ordering};
 use std::time::Duration;
 
+use parking_lot::{Condvar, Mutex};
 use seize::{Collector, Guard, Linked};
-use xxhash_rust::const_xxh3::xxh3_64 as const_xxh3;
+use serde::Serialize;
 
+use crate::bloom::haskey::key_to_hash;
 use crate::cache::ItemFlag::{ItemDelete, ItemNew, ItemUpdate};
-use crate::policy::{DefaultPolicy};
-use crate::reclaim::{Atomic, Shared};
+use crate::cmsketch::{CmSketch, CmSketchSnapshot, CounterWidth, SnapshotError};
+use crate::cost::Cost;
+use crate::frozen::FrozenView;
+use crate::key::CacheKey;
+use crate::policy::{DefaultPolicy, RecencyIndex};
+use crate::reclaim::{Atomic, RetireShared, Shared};
 use crate::ring::RingBuffer;
-use crate::store::{Node, Store};
+use crate::store::{next_generation, Node, Store};
+use crate::ttl;
 
 /// number shared element on store
 pub const NUM_SHARDS: usize = 256;
@@ -74,8 +83,89 @@ pub struct Config<K, V> {
     // major factor.
     pub metrics: bool,
 
+    // metrics_mode picks how the statistics are kept, see MetricsMode.
+    pub metrics_mode: MetricsMode,
+
+    // count_based makes the cache hold at most max_cost items: every item costs
+    // 1, whatever cost is passed to Set or returned by the cost function.
+    pub count_based: bool,
+
     pub key_to_hash: fn(&K) -> (u64, u64),
 
+    // conflict_hash computes the secondary hash used to tell apart keys whose
+    // primary hash collide. It is fed the raw bytes of the key, little-endian for
+    // integers, for every built-in key type; custom CacheKey impls that only
+    // define to_hash don't use it. When it is not set, xxh3 is used.
+    pub conflict_hash: Option<fn(&[u8]) -> u64>,
+
+    // on_conflict decides what a Set of a key that is already in the cache does:
+    // replace the stored value (the default) or keep the first one.
+    pub on_conflict: ConflictPolicy,
+
+    // on_pressure is called with the used cost and max_cost whenever an add
+    // takes the used cost past high_water (a fraction of max_cost), so callers
+    // can shed load before the cache starts evicting.
+    pub on_pressure: Option<fn(i64, i64)>,
+    pub high_water: f64,
+
+    // initial_capacity is the number of entries the store is sized for up front,
+    // spread over its shards, so a warm-up doesn't keep rehashing them. 0 lets
+    // the shards grow from empty.
+    pub initial_capacity: usize,
+
+    // recency_window is the number of recently accessed keys the policy keeps
+    // in order, to evict the least recently used of the candidates that tie on
+    // frequency. 0 turns it off. Every key of every get batch is stamped under
+    // the policy lock, at the cost of a map update each, and the index takes
+    // memory for up to twice this many accesses.
+    pub recency_window: usize,
+
+    // shard_stats makes the store count the gets and sets landing in each of
+    // its shards, see Cache::shard_stats.
+    pub shard_stats: bool,
+
+    // max_item_cost rejects new items costing more than it, even if they would
+    // fit, so a single huge item can't evict lots of small ones. None means no
+    // limit but max_cost.
+    pub max_item_cost: Option<i64>,
+
+    // max_entries caps the number of items however small their costs are, to
+    // bound the memory the maps take. Past it new items evict by sampled LFU,
+    // as they do past max_cost. None means no limit.
+    pub max_entries: Option<usize>,
+
+    // hit_window is the number of most recent gets recent_hit_ratio is taken
+    // over, so a drop in the hit ratio shows up even after a long good run. 0
+    // turns it off.
+    pub hit_window: usize,
+
+    // admit_all turns the admission policy off: new items are always stored,
+    // and only evict others to stay under max_cost. It suits small caches,
+    // where a new key would often be rejected the first time it's seen.
+    pub admit_all: bool,
+
+    // can_evict is asked before an item is evicted to make room for a new one.
+    // Items it returns false for are pinned: other candidates are evicted
+    // instead, and if there are none the new item is rejected.
+    pub can_evict: Option<fn(u64, &V) -> bool>,
+
+    // flush_on_drop makes dropping the cache hand the access hints still
+    // buffered to the policy first, so the metrics account for all of them.
+    pub flush_on_drop: bool,
+
+    // tiny_lfu tunes the admission policy and the eviction sampling, beyond the
+    // numb_counters the sketch and doorkeeper are sized for.
+    pub tiny_lfu: TinyLfuConfig,
+
+    // ttl_cost_bias derives the TTL an item is stored with from the one passed
+    // to set_with_ttl and the item's cost, e.g. to keep cheap items longer since
+    // evicting them frees little. Zero TTLs are left alone.
+    pub ttl_cost_bias: Option<fn(Duration, i64) -> Duration>,
+
+    // is_reclaimed tells clean_up which values have died on their own, e.g.
+    // SoftValue::is_dead, so it removes them along with the expired ones.
+    pub is_reclaimed: Option<fn(&V) -> bool>,
+
     pub on_evict: Option<fn(u64, u64, V, i64)>,
     pub cost: Option<fn(V) -> i64>,
 }
@@ -87,22 +177,159 @@ impl<K, V> Default for Config<K, V> {
             max_cost: 1 << 30,// maximum cost of cache
             buffer_items: 64,// number of keys per Get buffer.
             metrics: false,
+            metrics_mode: MetricsMode::Sharded,
+            count_based: false,
             key_to_hash: |_x| { (0, 0) },
+            conflict_hash: None,
+            on_conflict: ConflictPolicy::Overwrite,
+            on_pressure: None,
+            high_water: 0.9,
+            recency_window: 0,
+            initial_capacity: 0,
+            shard_stats: false,
+            max_item_cost: None,
+            max_entries: None,
+            hit_window: 0,
+            admit_all: false,
+            can_evict: None,
+            flush_on_drop: false,
+            tiny_lfu: TinyLfuConfig::default(),
+            ttl_cost_bias: None,
+            is_reclaimed: None,
             on_evict: None,
             cost: None,
         }
     }
 }
 
+impl<K, V> Config<K, V> {
+    /// Validate checks that the fields make sense together, returning the first
+    /// one that doesn't.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.numb_counters <= 0 {
+            return Err(ConfigError::NumCounters(self.numb_counters));
+        }
+        if self.max_cost <= 0 {
+            return Err(ConfigError::MaxCost(self.max_cost));
+        }
+        if self.buffer_items == 0 {
+            return Err(ConfigError::BufferItems);
+        }
+        if !(self.high_water > 0.0 && self.high_water <= 1.0) {
+            return Err(ConfigError::HighWater(self.high_water));
+        }
+        if let Some(max) = self.max_item_cost.filter(|max| *max <= 0) {
+            return Err(ConfigError::MaxItemCost(max));
+        }
+        if self.max_entries == Some(0) {
+            return Err(ConfigError::MaxEntries);
+        }
+        if self.tiny_lfu.adaptive_sample.is_some() && !self.metrics {
+            return Err(ConfigError::AdaptiveSampleMetrics);
+        }
+        self.tiny_lfu.validate()
+    }
+}
+
+/// TinyLfuConfig bundles the knobs of the TinyLFU admission policy, and the
+/// sample size of the eviction policy it works with. The defaults are the ones
+/// ristretto uses.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TinyLfuConfig {
+    // counter_width is the size of the count-min sketch counters. Admission.
+    pub counter_width: CounterWidth,
+    // conservative_update makes the sketch only bump the counters at a key's
+    // minimum, for estimates that suffer less from collisions. Admission.
+    pub conservative_update: bool,
+    // reset_at is the number of increments after which the counters are halved
+    // and the doorkeeper is cleared. None means numb_counters. Admission.
+    pub reset_at: Option<i64>,
+    // doorkeeper_fp is the false positive rate the doorkeeper Bloom filter is
+    // sized for. Admission.
+    pub doorkeeper_fp: f64,
+    // sample_size is the number of keys compared to pick each victim. Eviction.
+    pub sample_size: usize,
+    // adaptive_sample lets the sample size move with the hit ratio, starting
+    // from sample_size. It needs metrics. Eviction.
+    pub adaptive_sample: Option<AdaptiveSample>,
+}
+
+/// AdaptiveSample makes the eviction sample grow by one key every time a batch
+/// of gets had a hit ratio below low_hit_ratio, trading CPU for better victims,
+/// and shrink by one whenever it was above high_hit_ratio, within [min, max].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AdaptiveSample {
+    pub min: usize,
+    pub max: usize,
+    pub low_hit_ratio: f64,
+    pub high_hit_ratio: f64,
+}
+
+impl Default for AdaptiveSample {
+    fn default() -> Self {
+        AdaptiveSample {
+            min: 5,
+            max: 32,
+            low_hit_ratio: 0.5,
+            high_hit_ratio: 0.8,
+        }
+    }
+}
+
+impl Default for TinyLfuConfig {
+    fn default() -> Self {
+        TinyLfuConfig {
+            counter_width: CounterWidth::Four,
+            conservative_update: false,
+            reset_at: None,
+            doorkeeper_fp: 0.01,
+            sample_size: 5,
+            adaptive_sample: None,
+        }
+    }
+}
+
+impl TinyLfuConfig {
+    /// Validate checks the fields the same way Config::validate does.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(n) = self.reset_at.filter(|n| *n <= 0) {
+            return Err(ConfigError::ResetAt(n));
+        }
+        if !(self.doorkeeper_fp > 0.0 && self.doorkeeper_fp < 1.0) {
+            return Err(ConfigError::DoorkeeperFp(self.doorkeeper_fp));
+        }
+        if self.sample_size == 0 {
+            return Err(ConfigError::SampleSize);
+        }
+        if let Some(a) = self.adaptive_sample {
+            if a.min == 0 || a.min > a.max || !(0.0..=1.0).contains(&a.low_hit_ratio) || a.low_hit_ratio > a.high_hit_ratio || a.high_hit_ratio > 1.0 {
+                return Err(ConfigError::AdaptiveSample);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Marker bounds the auto traits of a Cache. The tables only hold pointers,
+/// which are always Send and Sync. Values are shared between handles and
+/// threads like an Arc<V> is, so they bound them the same way. Keys are only
+/// hashed and don't bound them.
+type Marker<K, V> = PhantomData<(fn() -> K, Arc<V>)>;
 
 /// Cache is a thread-safe implementation of a hashmap with a TinyLFU admission
 /// policy and a Sampled LFU eviction policy. You can use the same Cache instance
 /// from as many goroutines as you want.
+///
+/// Cache is `Send` when `V` is `Send + Sync` and `S` is `Send`, and `Sync` when `V`
+/// is `Send + Sync` and `S` is `Sync`, as with `Arc<V>`: values are moved in from
+/// one thread, handed out by reference to others, possibly through clones, and
+/// dropped on whichever thread tears the cache down. Keys are only hashed, never
+/// stored, so they don't constrain either.
 pub struct Cache<K, V, S = crate::DefaultHashBuilder> {
     pub(crate) store: Atomic<Store<V>>,
     pub(crate) policy: Atomic<DefaultPolicy<V>>,
-    pub(crate) get_buf: Atomic<RingBuffer<V>>,
-    collector: Collector,
+    pub(crate) get_buf: Atomic<RingBuffer<DefaultPolicy<V>>>,
+    collector: Arc<Collector>,
     // key_to_hash: fn(&K) -> (u64, u64),
 
     /// Table initialization and resizing control.  When negative, the
@@ -115,10 +342,30 @@ pub struct Cache<K, V, S = crate::DefaultHashBuilder> {
 
     size_buf_ctl: AtomicIsize,
     build_hasher: S,
+    conflict_hash: Option<fn(&[u8]) -> u64>,
+    on_conflict: ConflictPolicy,
     pub on_evict: Option<fn(u64, u64, &V, i64)>,
     cost: Option<fn(&V) -> i64>,
-
-    _marker: PhantomData<K>,
+    can_evict: Option<fn(u64, &V) -> bool>,
+    ttl_cost_bias: Option<fn(Duration, i64) -> Duration>,
+    is_reclaimed: Option<fn(&V) -> bool>,
+    // observer is told of every mutation, see with_observer.
+    observer: Option<Arc<dyn Fn(Event) + Send + Sync>>,
+    // pinned holds the hashes of the keys given to pin, which eviction skips.
+    // Clones share it, like the tables.
+    pinned: Arc<Mutex<HashSet<u64>>>,
+    // hit_window keeps whether each of the last gets hit, see recent_hit_ratio.
+    // Clones share it, like the metrics.
+    hit_window: Arc<HitWindow>,
+    // in_flight holds the loads started by get_or_insert_single_flight, by key hash.
+    // Clones share it, like the tables, so their loads are collapsed too.
+    in_flight: Arc<Mutex<HashMap<u64, Arc<Call<V>>>>>,
+    // forced_hashes maps the hashes of keys given to force_key_hash to the ones
+    // they are stored under instead.
+    #[cfg(any(test, feature = "testing"))]
+    forced_hashes: Mutex<HashMap<(u64, u64), (u64, u64)>>,
+
+    _marker: Marker<K, V>,
 
     pub numb_counters: i64,
     pub buffer_items: usize,
@@ -131,11 +378,25 @@ pub struct Cache<K, V, S = crate::DefaultHashBuilder> {
     // eviction process will take care of making room for the new item and not
     // overflowing the MaxCost value.
     pub max_cost: i64,
-
-    pub(crate) metrics: Option<Box<Metrics>>,
+    pub count_based: bool,
+    pub initial_capacity: usize,
+    pub shard_stats: bool,
+    pub flush_on_drop: bool,
+    tiny_lfu: TinyLfuConfig,
+    metrics_mode: MetricsMode,
+
+    pub(crate) metrics: Arc<OnceLock<Box<Metrics>>>,
+    // refs is shared by a cache and its clones, which share the tables too: only
+    // the last of them to be dropped frees the tables.
+    refs: Arc<()>,
 
 }
 
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Cache<u64, u64>>();
+};
+
 impl<K, V, S> Debug for Cache<K, V, S> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_tuple("Cache")
@@ -144,30 +405,52 @@ impl<K, V, S> Debug for Cache<K, V, S> {
     }
 }
 
+/// Clone returns another handle to the same cache, like cloning an Arc: the
+/// store, policy, get buffer and metrics are shared, so a set through one handle
+/// is seen through all of them. Use `Cache::deep_copy` for an independent copy.
 impl<K, V, S> Clone for Cache<K, V, S>
     where
-        K: Sync + Send + Clone + Hash + Ord,
-        V: Sync + Send + Clone,
-        S: BuildHasher + Clone,
+        S: Clone,
 {
     fn clone(&self) -> Cache<K, V, S> {
+        let guard = self.guard();
+        // allocate the store now, otherwise each handle would make its own
+        self.init_store(&guard);
         Self {
             store: self.store.clone(),
-            policy: Atomic::from(self.policy.load(Ordering::SeqCst, &self.guard())),
-            get_buf: Atomic::from(self.get_buf.load(Ordering::SeqCst, &self.guard())),
+            policy: Atomic::from(self.policy.load(Ordering::SeqCst, &guard)),
+            get_buf: Atomic::from(self.get_buf.load(Ordering::SeqCst, &guard)),
             collector: self.collector.clone(),
             size_ctl: AtomicIsize::from(self.size_ctl.load(Ordering::SeqCst)),
             size_buf_ctl: AtomicIsize::from(self.size_buf_ctl.load(Ordering::SeqCst)),
             build_hasher: self.build_hasher.clone(),
-            on_evict: None,
-            cost: None,
+            conflict_hash: self.conflict_hash,
+            on_conflict: self.on_conflict,
+            on_evict: self.on_evict,
+            cost: self.cost,
+            can_evict: self.can_evict,
+            ttl_cost_bias: self.ttl_cost_bias,
+            is_reclaimed: self.is_reclaimed,
+            observer: self.observer.clone(),
+            pinned: self.pinned.clone(),
+            hit_window: self.hit_window.clone(),
+            in_flight: self.in_flight.clone(),
+            #[cfg(any(test, feature = "testing"))]
+            forced_hashes: Mutex::new(HashMap::new()),
 
             _marker: Default::default(),
 
             numb_counters: self.numb_counters,
             buffer_items: self.buffer_items,
             max_cost: self.max_cost,
+            count_based: self.count_based,
+            initial_capacity: self.initial_capacity,
+            shard_stats: self.shard_stats,
+            flush_on_drop: self.flush_on_drop,
+            tiny_lfu: self.tiny_lfu,
+            metrics_mode: self.metrics_mode,
             metrics: self.metrics.clone(),
+            refs: self.refs.clone(),
 
         }
     }
@@ -181,6 +464,31 @@ impl<K, V> Cache<K, V, crate::DefaultHashBuilder> {
     pub fn with_config(c: Config<K, V>) -> Self {
         Self::with_hasher(crate::DefaultHashBuilder::default(), c)
     }
+
+    /// TryWithConfig works like with_config but returns an error for a config
+    /// that doesn't validate, instead of panicking or misbehaving later on.
+    pub fn try_with_config(c: Config<K, V>) -> Result<Self, ConfigError> {
+        c.validate()?;
+        Ok(Self::with_config(c))
+    }
+
+    /// WithCostedConfig works like with_config, but the values report their own
+    /// cost through the Cost trait, which sets with a cost of 0 use.
+    pub fn with_costed_config(c: Config<K, V>) -> Self
+        where V: Cost
+    {
+        let mut cache = Self::with_config(c);
+        cache.cost = Some(V::cost);
+        cache
+    }
+}
+
+impl<K, V> TryFrom<Config<K, V>> for Cache<K, V, crate::DefaultHashBuilder> {
+    type Error = ConfigError;
+
+    fn try_from(c: Config<K, V>) -> Result<Self, Self::Error> {
+        Self::try_with_config(c)
+    }
 }
 
 impl<K, V, S> Default for Cache<K, V, S>
@@ -194,14 +502,33 @@ impl<K, V, S> Default for Cache<K, V, S>
 
 impl<K, V, S> Drop for Cache<K, V, S> {
     fn drop(&mut self) {
+        // clones share the tables, leave them to the last one
+        if Arc::into_inner(std::mem::take(&mut self.refs)).is_none() {
+            return;
+        }
 
         let guard = unsafe { Guard::unprotected() };
 
+        if self.flush_on_drop {
+            let buf = self.get_buf.load(Ordering::SeqCst, &guard);
+            if !buf.is_null() {
+                unsafe { buf.deref() }.flush(&guard);
+            }
+        }
+
         let table = self.store.swap(Shared::null(), Ordering::SeqCst, &guard);
         if !table.is_null() {
             // table was never allocated!
             let mut table = unsafe { table.into_box() };
-            table.as_mut().data.clear()
+            for map in table.as_mut().data.iter_mut() {
+                for (_, node) in map.drain() {
+                    let value = node.value.load(Ordering::SeqCst, &guard);
+                    if !value.is_null() {
+                        // safety: we have &mut self, so no guard can still reference the value.
+                        let _ = unsafe { value.into_box() };
+                    }
+                }
+            }
         }
         let table = self.policy.swap(Shared::null(), Ordering::SeqCst, &guard);
         if !table.is_null() {
@@ -217,12 +544,97 @@ impl<K, V, S> Drop for Cache<K, V, S> {
     }
 }
 
+impl<K, V, S> FromIterator<(K, V)> for Cache<K, V, S>
+    where
+        K: Sync + Send + Clone + CacheKey + Ord + 'static,
+        V: Sync + Send,
+        S: BuildHasher + Default,
+{
+    /// Builds a cache with the default config, setting every pair with a cost of 1.
+    fn from_iter<I: IntoIterator<Item=(K, V)>>(iter: I) -> Self {
+        let cache = Self::default();
+        {
+            let guard = cache.guard();
+            for (key, value) in iter {
+                cache.set(key, value, 1, &guard);
+            }
+        }
+        cache
+    }
+}
+
+impl<K, V, S> Cache<K, V, S> {
+    /// DrainHashed empties the cache, yielding every stored value along with its
+    /// key hash, as `Cache::hash` gives it. The store does not keep the original
+    /// keys. While clones of the cache are alive they share the entries, so the
+    /// cache is handed back untouched instead.
+    #[allow(clippy::result_large_err)]
+    pub fn drain_hashed(self) -> Result<std::vec::IntoIter<(u64, V)>, Self> {
+        if Arc::strong_count(&self.refs) > 1 {
+            return Err(self);
+        }
+        let guard = unsafe { Guard::unprotected() };
+        let mut entries = Vec::new();
+
+        let table = self.store.swap(Shared::null(), Ordering::SeqCst, &guard);
+        if !table.is_null() {
+            let mut table = unsafe { table.into_box() };
+            for map in table.data.iter_mut() {
+                for (key, node) in map.drain() {
+                    let value = node.value.load(Ordering::SeqCst, &guard);
+                    if !value.is_null() {
+                        // safety: we own the cache, so no other reference to the value exists.
+                        let value = unsafe { value.into_box() };
+                        entries.push((key, Linked::into_inner(*value)));
+                    }
+                }
+            }
+        }
+        Ok(entries.into_iter())
+    }
+}
+
+impl<K, V, S> IntoIterator for Cache<K, V, S> {
+    type Item = (u64, V);
+    type IntoIter = std::vec::IntoIter<(u64, V)>;
+
+    /// Drains the cache like `Cache::drain_hashed`. It panics if clones of the
+    /// cache are still alive.
+    fn into_iter(self) -> Self::IntoIter {
+        match self.drain_hashed() {
+            Ok(entries) => entries,
+            Err(_) => panic!("into_iter on a cache that still has clones; use drain_hashed"),
+        }
+    }
+}
+
 impl<K, V, S> Cache<K, V, S>
 
 {
     pub fn with_hasher(hash_builder: S, c: Config<K, V>) -> Self {
-        let collector = Collector::new();
-        let mut ca = Cache {
+        Self::with_hasher_and_collector(hash_builder, Arc::new(Collector::new()), c)
+    }
+
+    /// WithObserver makes the cache call observer with an Event for every insert,
+    /// update, eviction, delete and rejected set, synchronously, from the thread
+    /// that made the change. It suits audit logs that need more than OnEvict.
+    pub fn with_observer(mut self, observer: Box<dyn Fn(Event) + Send + Sync>) -> Self {
+        self.observer = Some(Arc::from(observer));
+        self
+    }
+
+    // observe hands the event to the observer, if there is one.
+    fn observe(&self, event: Event) {
+        if let Some(observer) = &self.observer {
+            observer(event)
+        }
+    }
+
+    /// WithHasherAndCollector works like with_hasher but reclaims memory through
+    /// the given collector, so several caches can share one and be used with the
+    /// same guard.
+    pub fn with_hasher_and_collector(hash_builder: S, collector: Arc<Collector>, c: Config<K, V>) -> Self {
+        let ca = Cache {
             store: Atomic::null(),
             policy: Atomic::null(),
             get_buf: Atomic::null(),
@@ -230,37 +642,59 @@ impl<K, V, S> Cache<K, V, S>
             size_ctl: AtomicIsize::new(0),
             size_buf_ctl: AtomicIsize::new(0),
             build_hasher: hash_builder,
+            conflict_hash: c.conflict_hash,
+            on_conflict: c.on_conflict,
             on_evict: None,
             cost: None,
+            can_evict: c.can_evict,
+            ttl_cost_bias: c.ttl_cost_bias,
+            is_reclaimed: c.is_reclaimed,
+            observer: None,
+            pinned: Arc::new(Mutex::new(HashSet::new())),
+            hit_window: Arc::new(HitWindow::new(c.hit_window)),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            #[cfg(any(test, feature = "testing"))]
+            forced_hashes: Mutex::new(HashMap::new()),
             buffer_items: c.buffer_items,
             _marker: Default::default(),
 
             numb_counters: c.numb_counters,
             max_cost: c.max_cost,
-            metrics: None,
+            count_based: c.count_based,
+            initial_capacity: c.initial_capacity,
+            shard_stats: c.shard_stats,
+            flush_on_drop: c.flush_on_drop,
+            tiny_lfu: c.tiny_lfu,
+            metrics_mode: c.metrics_mode,
+            metrics: Arc::new(OnceLock::new()),
+            refs: Arc::new(()),
 
         };
 
         if c.metrics {
-            ca.metrics = Some(Box::new(Metrics::new(DO_NOT_USE, &ca.collector)));
+            let _ = ca.metrics.set(Box::new(Metrics::with_mode(DO_NOT_USE, ca.metrics_mode, &ca.collector)));
         }
 
 
-        if let Some(m) = &ca.metrics {
-            let v: *const Metrics = &**m;
-
-            let table = Shared::boxed(DefaultPolicy::new(ca.numb_counters, ca.max_cost, v), &ca.collector);
-            ca.policy.store(table, Ordering::SeqCst);
+        let metrics: *const Metrics = match ca.metrics.get() {
+            Some(m) => &**m,
+            None => ptr::null(),
+        };
+        let mut policy = DefaultPolicy::with_tiny_lfu(ca.numb_counters, ca.max_cost, metrics, &ca.tiny_lfu);
+        policy.on_pressure = c.on_pressure;
+        policy.high_water = c.high_water;
+        policy.max_item_cost = c.max_item_cost;
+        policy.max_entries = c.max_entries;
+        policy.admit_all = c.admit_all;
+        if c.recency_window > 0 {
+            policy.recency = Some(RecencyIndex::new(c.recency_window));
+        }
 
-            let table = Shared::boxed(RingBuffer::new(table, ca.buffer_items), &ca.collector);
-            ca.get_buf.store(table, Ordering::SeqCst);
-        } else {
-            let table = Shared::boxed(DefaultPolicy::new(ca.numb_counters, ca.max_cost, ptr::null()), &ca.collector);
-            ca.policy.store(table, Ordering::SeqCst);
+        let table = Shared::boxed(policy, &ca.collector);
+        ca.policy.store(table, Ordering::SeqCst);
 
-            let table = Shared::boxed(RingBuffer::new(table, ca.buffer_items), &ca.collector);
-            ca.get_buf.store(table, Ordering::SeqCst);
-        }
+        let table = Shared::boxed(RingBuffer::new(table, ca.buffer_items), &ca.collector);
+        ca.get_buf.store(table, Ordering::SeqCst);
         ca
     }
 
@@ -278,6 +712,27 @@ impl<K, V, S> Cache<K, V, S>
         }
     }
 
+    // can_evict_key asks the can_evict callback whether the policy may evict
+    // key_hash. Pinned keys never can; keys that are gone or expired from the
+    // store always can.
+    fn can_evict_key(&self, key_hash: u64, guard: &Guard<'_>) -> bool {
+        if self.pinned.lock().contains(&key_hash) {
+            return false;
+        }
+        let f = match self.can_evict {
+            Some(f) => f,
+            None => return true,
+        };
+        let store = self.store.load(Ordering::SeqCst, guard);
+        if store.is_null() {
+            return true;
+        }
+        match unsafe { store.deref() }.get(key_hash, None, guard) {
+            Some(v) => f(key_hash, v),
+            None => true,
+        }
+    }
+
 
     /*    fn init_metrics<'g>(&'g self, guard: &'g Guard<'_>) -> Shared<'g, Metrics> {
             loop {
@@ -320,7 +775,7 @@ impl<K, V, S> Cache<K, V, S>
                 }
             }
         }*/
-    fn init_ringbuf<'g>(&'g self, guard: &'g Guard<'_>) -> Shared<'g, RingBuffer<V>> {
+    fn init_ringbuf<'g>(&'g self, guard: &'g Guard<'_>) -> Shared<'g, RingBuffer<DefaultPolicy<V>>> {
         loop {
             let table = self.get_buf.load(Ordering::SeqCst, guard);
             // safety: we loaded the table while the thread was marked as active.
@@ -396,7 +851,12 @@ impl<K, V, S> Cache<K, V, S>
                     } else {
                         NUM_SHARDS
                     };
-                    table = Shared::boxed(Store::new(), &self.collector);
+                    let mut store = Store::with_capacity(self.initial_capacity);
+                    store.on_conflict = self.on_conflict;
+                    if self.shard_stats {
+                        store.enable_stats();
+                    }
+                    table = Shared::boxed(store, &self.collector);
                     self.store.store(table, Ordering::SeqCst);
                     sc = load_factor!(n as isize);
                 }
@@ -421,11 +881,11 @@ impl<K, V, S> Cache<K, V, S>
 
             //try to allocate the table
             // let metrics = Box::into_raw(Box::new(Metrics::new(DO_NOT_USE, &self.collector)));
-            if let Some(m) = &self.metrics {
+            if let Some(m) = self.metrics.get() {
                 let v: *const Metrics = &**m;
 
 
-                let p = DefaultPolicy::new(self.numb_counters, self.max_cost, v);
+                let p = DefaultPolicy::with_tiny_lfu(self.numb_counters, self.max_cost, v, &self.tiny_lfu);
 
 
                 table = Shared::boxed(p, &self.collector);
@@ -441,79 +901,77 @@ impl<K, V, S> Cache<K, V, S>
 
 
 impl<V, K, S> Cache<K, V, S>
-    where K: Hash + Ord,
+    where K: CacheKey + Ord,
           S: BuildHasher,
 {
-    pub fn hash<Q: ?Sized + Hash + 'static>(&self, key: &Q) -> (u64, u64) {
-        let t = TypeId::of::<&Q>();
-        if t == TypeId::of::<&i64>() {
-            let v = key as *const Q as *const i64;
-            let v = unsafe { v.as_ref().unwrap() };
-            if *v == 0 {
-                return (0, 0);
-            }
-            return (*v as u64, 0);
-        }
-        if t == TypeId::of::<&i32>() {
-            let v = key as *const Q as *const i32;
-            let v = unsafe { v.as_ref().unwrap() };
-            return (*v as u64, 0);
-        }
-
-        if t == TypeId::of::<&u64>() {
-            let v = key as *const Q as *const u64;
-            let v = unsafe { v.as_ref().unwrap() };
-
-            return (*v as u64, 0);
+    /// Hash returns the key hash and the conflict hash the key is stored under.
+    pub fn hash<Q: ?Sized + CacheKey>(&self, key: &Q) -> (u64, u64)
+        where K: Borrow<Q>
+    {
+        let hashes = key_to_hash(key, &self.build_hasher, self.conflict_hash);
+        #[cfg(any(test, feature = "testing"))]
+        if let Some(forced) = self.forced_hashes.lock().get(&hashes) {
+            return *forced;
         }
+        hashes
+    }
 
+    /// ForceKeyHash makes the cache store the key under the given key hash and
+    /// conflict hash from now on, e.g. to make two keys collide in a test. Only
+    /// with the testing feature.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn force_key_hash<Q: ?Sized + CacheKey>(&self, key: &Q, key_hash: u64, conflict: u64)
+        where K: Borrow<Q>
+    {
+        let hashes = key_to_hash(key, &self.build_hasher, self.conflict_hash);
+        self.forced_hashes.lock().insert(hashes, (key_hash, conflict));
+    }
 
-        if t == TypeId::of::<&u32>() {
-            let v = key as *const Q as *const u32;
-            let v = unsafe { v.as_ref().unwrap() };
-            return (*v as u64, 0);
-        }
+    /// ShardIndex returns the index of the store shard the key lands in. Only
+    /// with the testing feature.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn shard_index<Q: ?Sized + CacheKey>(&self, key: &Q) -> usize
+        where K: Borrow<Q>
+    {
+        let (key_hash, _) = self.hash(key);
+        let guard = self.guard();
+        let store = self.init_store(&guard);
+        unsafe { store.deref() }.bini(key_hash)
+    }
 
-        if t == TypeId::of::<&u8>() {
-            let v = key as *const Q as *const u8;
-            let v = unsafe { v.as_ref().unwrap() };
-            return (*v as u64, 0);
-        }
-        if t == TypeId::of::<&usize>() {
-            let v = key as *const Q as *const usize;
-            let v = unsafe { v.as_ref().unwrap() };
-            return (*v as u64, 0);
-        }
 
-        if t == TypeId::of::<&i16>() {
-            let v = key as *const Q as *const i16;
-            let v = unsafe { v.as_ref().unwrap() };
-            return (*v as u64, 0);
-        }
-        if t == TypeId::of::<&i8>() {
-            let v = key as *const Q as *const i8;
-            let v = unsafe { v.as_ref().unwrap() };
-            return (*v as u64, 0);
+    /// DebugLookup returns the key hash and conflict hash the key is looked up
+    /// by, and whether the cache holds a live entry under them. Unlike get it
+    /// doesn't count as an access, so it is safe to call when chasing collisions.
+    pub fn debug_lookup<'g, Q: ?Sized + CacheKey>(&'g self, key: &Q, guard: &'g Guard) -> (u64, u64, bool)
+        where K: Borrow<Q>
+    {
+        self.check_guard(guard);
+        let (key_hash, conflict) = self.hash(key);
+        let store = self.store.load(Ordering::SeqCst, guard);
+        if store.is_null() {
+            return (key_hash, conflict, false);
         }
-        let mut h = self.build_hasher.build_hasher();
-        key.hash(&mut h);
-
-        let slice = unsafe {
-            std::slice::from_raw_parts(key as *const Q as *const u8, std::mem::size_of_val(key))
-        };
-
-        let t = TypeId::of::<Q>();
-        if t == TypeId::of::<i64>() {}
-
-        (h.finish(), const_xxh3(slice))
+        let hit = unsafe { store.deref() }.get(key_hash, Some(conflict), guard).is_some();
+        (key_hash, conflict, hit)
     }
 
-
     /// Get returns the value (if any) and a boolean representing whether the
     /// value was found or not. The value can be nil and the boolean can be true at
     /// the same time.
-    pub fn get<'g, Q: ?Sized + Hash + 'static>(&'g self, key: &Q, guard: &'g Guard) -> Option<&'g V> {
+    pub fn get<'g, Q: ?Sized + CacheKey>(&'g self, key: &Q, guard: &'g Guard) -> Option<&'g V>
+        where K: Borrow<Q>
+    {
         let (key_hash, conflict) = self.hash(key);
+        self.get_prehashed(key_hash, conflict, guard)
+    }
+
+    /// GetPrehashed works like Get for a key the caller already hashed, skipping
+    /// the hash step. key_hash and conflict must be what `Cache::hash` returns
+    /// for the key, or at least come from one scheme used for every get and set
+    /// of the cache: mixing schemes makes keys miss or collide.
+    pub fn get_prehashed<'g>(&'g self, key_hash: u64, conflict: u64, guard: &'g Guard) -> Option<&'g V> {
+        self.check_guard(guard);
 
         let buf = self.get_buf.load(Ordering::SeqCst, guard);
         if buf.is_null() {
@@ -530,640 +988,4211 @@ impl<V, K, S> Cache<K, V, S>
         }
 
 
-        let result = unsafe { store.deref() }.get(key_hash, conflict, guard);
+        let result = unsafe { store.deref() }.get(key_hash, Some(conflict), guard);
         return match result {
             None => {
-                if let Some(metrics) = &self.metrics {
-                    metrics.add(HIT, key_hash, 1, guard);
-                }
+                self.record_get(false, key_hash, guard);
                 None
             }
             Some(ref _v) => {
-                if let Some(metrics) = &self.metrics {
-                    metrics.add(MISS, key_hash, 1, guard);
-                }
+                unsafe { store.as_ptr().as_mut().unwrap() }.refresh_idle(key_hash, conflict, guard);
+                self.record_get(true, key_hash, guard);
                 result
             }
         };
     }
-}
-
-impl<V, K, S> Cache<K, V, S>
-    where
-        K: Sync + Send + Clone + Hash + Ord + 'static,
-        V: Sync + Send,
-        S: BuildHasher,
-{
-    /*    fn init_metrics2<'g>(&'g self, guard: &'g Guard<'_>) -> Shared<'g, Metrics> {
-            loop {
-                let mut metrics = self.metrics.load(Ordering::SeqCst, guard);
-                if !metrics.is_null() {
-                    break metrics;
-                }
-
-                metrics = Shared::boxed(Metrics::new(, &self.collector), &self.collector);
-                self.metrics.store(metrics, Ordering::SeqCst);
-                break metrics;
-            }
-        }*/
-
 
+    /// record_get counts a get in the metrics and the hit window.
+    fn record_get(&self, hit: bool, key_hash: u64, guard: &Guard) {
+        if let Some(metrics) = self.metrics.get() {
+            metrics.add(if hit { HIT } else { MISS }, key_hash, 1, guard);
+        }
+        self.hit_window.record(hit);
+    }
 
-    /// Set attempts to add the key-value item to the cache. If it returns false,
-    /// then the Set was dropped and the key-value item isn't added to the cache. If
-    /// it returns true, there's still a chance it could be dropped by the policy if
-    /// its determined that the key-value item isn't worth keeping, but otherwise the
-    /// item will be added and other items will be evicted in order to make room.
-    ///
-    /// To dynamically evaluate the items cost using the Config.Coster function, set
-    /// the cost parameter to 0 and Coster will be ran when needed in order to find
-    /// the items true cost.
-    pub fn set<'g>(&'g self, key: K, value: V, cost: i64, guard: &'g Guard<'_>) -> bool {
+    /// RecentHitRatio returns the hit ratio of the last `Config::hit_window` gets,
+    /// or of all of them while there have been fewer. Unlike the lifetime ratio
+    /// of the metrics it shows a recent drop right away. It is 0 with the window
+    /// off or before the first get.
+    pub fn recent_hit_ratio(&self, guard: &Guard) -> f64 {
         self.check_guard(guard);
-        self.set_with_ttl(key, value, cost, Duration::from_millis(0), guard)
+        self.hit_window.ratio()
     }
 
-
-    /// SetWithTTL works like Set but adds a key-value pair to the cache that will expire
-    /// after the specified TTL (time to live) has passed. A zero value means the value never
-    /// expires, which is identical to calling Set. A negative value is a no-op and the value
-    /// is discarded.
-    pub fn set_with_ttl<'g>(&'g self, key: K, value: V, cost: i64, ttl: Duration, guard: &'g Guard) -> bool {
-        let mut expiration: Option<Duration> = None;
-        if ttl.as_millis() < 0 {
-            return false;
-        } else if ttl.is_zero() {
-            expiration = Some(ttl)
-        } else if ttl.as_millis() > 0 {
-            expiration = Some(ttl)
-        } else {
-            expiration = Some(time::SystemTime::now().elapsed().unwrap().checked_add(ttl).unwrap())
+    /// TryGet works like Get but tells a miss apart from a cache that isn't set
+    /// up. The store and the policy are created on first use, so it creates
+    /// them, and returns `CacheError::Uninitialized` if they or the get buffer
+    /// still aren't there.
+    pub fn try_get<'g, Q: ?Sized + CacheKey>(&'g self, key: &Q, guard: &'g Guard) -> Result<Option<&'g V>, CacheError>
+        where K: Borrow<Q>
+    {
+        self.check_guard(guard);
+        self.init_store(guard);
+        self.init_policy(guard);
+        if self.get_buf.load(Ordering::SeqCst, guard).is_null()
+            || self.store.load(Ordering::SeqCst, guard).is_null()
+            || self.policy.load(Ordering::SeqCst, guard).is_null() {
+            return Err(CacheError::Uninitialized);
         }
-        let (key_hash, conflict) = self.hash(&key);
+        Ok(self.get(key, guard))
+    }
 
-        let mut store = self.store.load(Ordering::SeqCst, guard);
-        let value = Shared::boxed(value, &self.collector);
-        // let mut old_value = None;
+    /// GetConsistent works like Get but first drains the pending gets in the ring
+    /// into the policy, so the read sees every set and access made before it.
+    /// Sets already reach the store before they return; the drain makes the
+    /// policy's view of the key current too, at the cost of a batch per call.
+    pub fn get_consistent<'g, Q: ?Sized + CacheKey>(&'g self, key: &Q, guard: &'g Guard) -> Option<&'g V>
+        where K: Borrow<Q>
+    {
+        self.check_guard(guard);
+        let buf = self.get_buf.load(Ordering::SeqCst, guard);
+        if buf.is_null() {
+            return None;
+        }
+        unsafe { buf.deref() }.flush(guard);
+        self.get(key, guard)
+    }
 
-        let policy = self.policy.load(Ordering::SeqCst, guard);
+    /// WaitUntilIdle drains the pending gets into the policy until none are left,
+    /// for a clean shutdown. Sets and deletes are applied before they return, so
+    /// the ring is the only outstanding work. It returns false if gets kept
+    /// coming for longer than timeout.
+    pub fn wait_until_idle<'g>(&'g self, timeout: Duration, guard: &'g Guard) -> bool {
+        self.check_guard(guard);
+        let buf = self.get_buf.load(Ordering::SeqCst, guard);
+        if buf.is_null() {
+            return true;
+        }
+        let buf = unsafe { buf.deref() };
+        let start = std::time::Instant::now();
         loop {
-            if store.is_null() {
-                store = self.init_store(guard);
-                continue;
+            buf.flush(guard);
+            if buf.pending(guard) == 0 {
+                return true;
             }
+            if start.elapsed() >= timeout {
+                return false;
+            }
+            std::thread::yield_now();
+        }
+    }
 
-            let dstore = unsafe { store.as_ptr() };
-            let dstore = unsafe { dstore.as_mut().unwrap() };
-
-            let mut item = Item {
-                flag: ItemNew,
-                key: key_hash,
-                conflict: conflict,
-                value: Atomic::null(),
-                cost,
-                expiration,
-            };
-            item.value.store(value, Ordering::SeqCst);
+    /// GetStale works like Get but also returns entries whose TTL has passed as
+    /// long as they haven't been removed by `clean_up` yet, so callers can serve
+    /// a stale value while they refresh it.
+    pub fn get_stale<'g, Q: ?Sized + CacheKey>(&'g self, key: &Q, guard: &'g Guard) -> GetResult<&'g V>
+        where K: Borrow<Q>
+    {
+        self.check_guard(guard);
+        let (key_hash, conflict) = self.hash(key);
 
-            if dstore.update(&item, guard) {
-                item.flag = ItemUpdate
-            };
+        let buf = self.get_buf.load(Ordering::SeqCst, guard);
+        if !buf.is_null() {
+            unsafe { buf.deref() }.push(key_hash, guard);
+        }
 
-            let node = Node {
-                key: key_hash,
-                conflict,
-                value: Atomic::null(),
-                expiration,
-            };
-            node.value.store(value, Ordering::SeqCst);
+        let store = self.store.load(Ordering::SeqCst, guard);
+        if store.is_null() {
+            return GetResult::Missing;
+        }
 
-            match item.flag {
-                ItemNew | ItemUpdate => unsafe {
-                    if item.cost == 0 && self.cost.is_some() {
-                        item.cost = (self.cost.unwrap())(item.value.load(Ordering::SeqCst, guard).deref());
-                    }
-                }
-                _ => {}
-            }
+        let result = unsafe { store.deref() }.lookup(key_hash, Some(conflict), guard);
+        self.record_get(matches!(result, GetResult::Fresh(_)), key_hash, guard);
+        result
+    }
 
+    /// ContainsKey reports whether the key is in the cache and hasn't expired. The
+    /// conflict hash is checked too, so another key that shares the primary hash
+    /// doesn't count. It is not recorded as an access.
+    pub fn contains_key<'g, Q: ?Sized + CacheKey>(&'g self, key: &Q, guard: &'g Guard) -> bool
+        where K: Borrow<Q>
+    {
+        self.check_guard(guard);
+        let (key_hash, conflict) = self.hash(key);
 
-            match item.flag {
-                ItemNew => {
-                    let (victims, added) = unsafe {
-                        let policy = policy.as_ptr();
-                        policy.as_mut().unwrap().add(item.key, item.cost, guard)
-                    };
+        let store = self.store.load(Ordering::SeqCst, guard);
+        if store.is_null() {
+            return false;
+        }
+        matches!(unsafe { store.deref() }.lookup(key_hash, Some(conflict), guard), GetResult::Fresh(_))
+    }
 
-                    if added {
-                        dstore.set(node, guard);
-                        if let Some(metrics) = &self.metrics {
-                            metrics.add(KEY_ADD, item.key, 1, guard)
-                        }
-                    }
+    /// GetCloned works like Get but returns a clone of the value, taken under the
+    /// store lock, so the result can outlive the guard.
+    pub fn get_cloned<'g, Q: ?Sized + CacheKey>(&'g self, key: &Q, guard: &'g Guard) -> Option<V>
+        where K: Borrow<Q>,
+              V: Clone
+    {
+        self.check_guard(guard);
+        let (key_hash, conflict) = self.hash(key);
 
+        let buf = self.get_buf.load(Ordering::SeqCst, guard);
+        if buf.is_null() {
+            return None;
+        }
+        unsafe { buf.deref() }.push(key_hash, guard);
 
-                    for i in 0..victims.len() {
-                        let delVal = dstore.del(&victims[i].key, &0, guard);
-                        match delVal {
-                            Some((_c, _v)) => {
-                                // victims[i].value = Some(v.clone());
-                                // victims[i].conflict = c;
+        let store = self.store.load(Ordering::SeqCst, guard);
+        if store.is_null() {
+            return None;
+        }
 
-                                if self.on_evict.is_some() {
-                                    let v = victims[i].value.load(Ordering::SeqCst, guard);
+        let result = unsafe { store.deref() }.get_cloned(key_hash, conflict, guard);
+        if result.is_some() {
+            unsafe { store.as_ptr().as_mut().unwrap() }.refresh_idle(key_hash, conflict, guard);
+        }
+        self.record_get(result.is_some(), key_hash, guard);
+        result
+    }
+
+    /// GetWithGeneration works like Get but also returns the generation of the
+    /// entry. Every write of the entry gives it a new, higher generation, so a
+    /// caller can read, compute a new value, and write it back with
+    /// set_if_generation only if nobody wrote in between.
+    pub fn get_with_generation<'g, Q: ?Sized + CacheKey>(&'g self, key: &Q, guard: &'g Guard) -> Option<(&'g V, u64)>
+        where K: Borrow<Q>
+    {
+        self.check_guard(guard);
+        let (key_hash, conflict) = self.hash(key);
+
+        let buf = self.get_buf.load(Ordering::SeqCst, guard);
+        if buf.is_null() {
+            return None;
+        }
+        unsafe { buf.deref() }.push(key_hash, guard);
+
+        let store = self.store.load(Ordering::SeqCst, guard);
+        if store.is_null() {
+            return None;
+        }
+
+        let result = unsafe { store.deref() }.get_with_generation(key_hash, conflict, guard);
+        if result.is_some() {
+            unsafe { store.as_ptr().as_mut().unwrap() }.refresh_idle(key_hash, conflict, guard);
+        }
+        self.record_get(result.is_some(), key_hash, guard);
+        result
+    }
+
+    /// ParGetBatch looks the keys up across the rayon pool, each worker pinning
+    /// its own guard, and returns clones of the values in the order of keys.
+    #[cfg(feature = "rayon")]
+    pub fn par_get_batch<Q: CacheKey + Sync>(&self, keys: &[Q]) -> Vec<Option<V>>
+        where K: Borrow<Q>,
+              V: Clone + Send + Sync,
+              S: Sync
+    {
+        use rayon::prelude::*;
+        keys.par_iter()
+            .map_init(|| self.guard(), |guard, key| self.get_cloned(key, guard))
+            .collect()
+    }
+
+    /// Len returns the number of entries in the cache, counting expired ones that
+    /// haven't been cleaned up yet.
+    pub fn len<'g>(&'g self, guard: &'g Guard) -> usize {
+        self.check_guard(guard);
+        let store = self.store.load(Ordering::SeqCst, guard);
+        if store.is_null() {
+            return 0;
+        }
+        unsafe { store.deref() }.len()
+    }
+
+    pub fn is_empty<'g>(&'g self, guard: &'g Guard) -> bool {
+        self.len(guard) == 0
+    }
+
+    /// LenParallel works like Len but counts the store shards in parallel on the
+    /// rayon pool, for caches with millions of entries.
+    #[cfg(feature = "rayon")]
+    pub fn len_parallel<'g>(&'g self, guard: &'g Guard) -> usize
+        where V: Send + Sync
+    {
+        self.check_guard(guard);
+        let store = self.store.load(Ordering::SeqCst, guard);
+        if store.is_null() {
+            return 0;
+        }
+        unsafe { store.deref() }.len_parallel()
+    }
+
+    /// CostUsed returns the total cost of the items in the cache, as the policy
+    /// keeps it.
+    pub fn cost_used<'g>(&'g self, guard: &'g Guard) -> i64 {
+        self.check_guard(guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if policy.is_null() {
+            return 0;
+        }
+        unsafe { policy.deref() }.used()
+    }
+
+    /// CostUsedParallel adds up the cost of every item in parallel on the rayon
+    /// pool instead of reading the running total, so it also checks CostUsed.
+    #[cfg(feature = "rayon")]
+    pub fn cost_used_parallel<'g>(&'g self, guard: &'g Guard) -> i64 {
+        self.check_guard(guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if policy.is_null() {
+            return 0;
+        }
+        unsafe { policy.deref() }.used_parallel()
+    }
+
+    /// Keys returns the key hashes of the live entries, as `Cache::hash` gives
+    /// them. The store does not keep the original keys.
+    pub fn keys<'g>(&'g self, guard: &'g Guard) -> impl Iterator<Item=u64> + 'g {
+        self.check_guard(guard);
+        let store = self.store.load(Ordering::SeqCst, guard);
+        let keys = if store.is_null() {
+            vec![]
+        } else {
+            unsafe { store.deref() }.keys()
+        };
+        keys.into_iter()
+    }
+
+    /// Values returns the values of the live entries, valid for as long as the
+    /// guard is held.
+    pub fn values<'g>(&'g self, guard: &'g Guard) -> impl Iterator<Item=&'g V> + 'g {
+        self.check_guard(guard);
+        let store = self.store.load(Ordering::SeqCst, guard);
+        let values = if store.is_null() {
+            vec![]
+        } else {
+            unsafe { store.deref() }.values(guard)
+        };
+        values.into_iter()
+    }
+
+    /// GetSliding works like Get, but a hit also pushes the entry's deadline to ttl
+    /// from now, so an entry that keeps being read never expires. An entry that
+    /// was set without a TTL gets one.
+    pub fn get_sliding<'g, Q: ?Sized + CacheKey>(&'g self, key: &Q, ttl: Duration, guard: &'g Guard) -> Option<&'g V>
+        where K: Borrow<Q>
+    {
+        self.check_guard(guard);
+        let (key_hash, conflict) = self.hash(key);
+
+        let buf = self.get_buf.load(Ordering::SeqCst, guard);
+        if buf.is_null() {
+            return None;
+        }
+        unsafe { buf.deref() }.push(key_hash, guard);
+
+        let store = self.store.load(Ordering::SeqCst, guard);
+        if store.is_null() {
+            return None;
+        }
+
+        let result = unsafe { store.as_ptr().as_mut().unwrap() }.touch(key_hash, conflict, ttl::now() + ttl, guard);
+        self.record_get(result.is_some(), key_hash, guard);
+        result
+    }
+
+    /// ExpiringWithin returns the key hashes of the entries whose TTL runs out in
+    /// the next window, soonest first, e.g. to refresh them ahead of time.
+    pub fn expiring_within<'g>(&'g self, window: Duration, guard: &'g Guard) -> Vec<u64> {
+        self.check_guard(guard);
+        let store = self.store.load(Ordering::SeqCst, guard);
+        if store.is_null() {
+            return vec![];
+        }
+        unsafe { store.deref() }.expiring_within(window, guard)
+    }
+
+    /// Freeze returns a point-in-time copy of the cache that later sets, deletes and
+    /// evictions don't affect. Every live value is cloned, so the view takes as
+    /// much memory as the cache itself; use it for consistent reads over a few
+    /// keys, not as a cheap handle.
+    pub fn freeze<'g>(&'g self, guard: &'g Guard) -> FrozenView<V, S>
+        where V: Clone, S: Clone
+    {
+        self.check_guard(guard);
+        let store = self.store.load(Ordering::SeqCst, guard);
+        let entries = if store.is_null() {
+            HashMap::new()
+        } else {
+            unsafe { store.deref() }.freeze(guard)
+        };
+        FrozenView::new(entries, self.build_hasher.clone(), self.conflict_hash)
+    }
+
+    /// DeepCopy returns an independent cache holding a clone of every entry, with
+    /// the policy's frequencies and costs and the metrics copied too. Unlike
+    /// clone, changes to either cache don't show in the other. The copy shares
+    /// this cache's collector, so the same guard works with both.
+    pub fn deep_copy<'g>(&'g self, guard: &'g Guard) -> Self
+        where V: Clone, S: Clone
+    {
+        self.check_guard(guard);
+        let store = self.init_store(guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+
+        let mut copy = self.clone();
+        copy.refs = Arc::new(());
+        copy.in_flight = Arc::new(Mutex::new(HashMap::new()));
+        copy.pinned = Arc::new(Mutex::new(self.pinned.lock().clone()));
+        copy.hit_window = Arc::new(self.hit_window.deep_clone());
+        copy.metrics = Arc::new(OnceLock::new());
+        let metrics: *const Metrics = match self.metrics.get() {
+            Some(m) => &**copy.metrics.get_or_init(|| Box::new(m.deep_clone(&self.collector, guard))),
+            None => ptr::null(),
+        };
+
+        let store = unsafe { store.deref() }.deep_clone(guard);
+        copy.store = Atomic::from(Shared::boxed(store, &self.collector));
+        let policy = Shared::boxed(unsafe { policy.deref() }.deep_clone(metrics), &self.collector);
+        copy.policy = Atomic::from(policy);
+        copy.get_buf = Atomic::from(Shared::boxed(RingBuffer::new(policy, self.buffer_items), &self.collector));
+        copy
+    }
+
+    /// ConsistencyReport compares what the store holds with what the policy
+    /// accounts for. The two should always agree; drift means an accounting bug.
+    pub fn consistency_report<'g>(&'g self, guard: &'g Guard) -> ConsistencyReport {
+        self.check_guard(guard);
+        let store = self.store.load(Ordering::SeqCst, guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if store.is_null() || policy.is_null() {
+            return ConsistencyReport::default();
+        }
+        let keys: Vec<u64> = unsafe { store.as_ptr().as_mut().unwrap() }
+            .with_lock(|data, _| data.iter().flat_map(|m| m.keys().copied()).collect());
+        let (policy_entries, cost_used, cost_sum_from_store) = unsafe { policy.deref() }.accounting(&keys);
+        ConsistencyReport {
+            store_entries: keys.len(),
+            policy_entries,
+            cost_used,
+            cost_sum_from_store,
+        }
+    }
+
+    /// ShardStats returns, for every shard of the store, how many gets and sets
+    /// landed in it. It's empty unless Config::shard_stats is set.
+    pub fn shard_stats<'g>(&'g self, guard: &'g Guard) -> Vec<u64> {
+        self.check_guard(guard);
+        let store = self.store.load(Ordering::SeqCst, guard);
+        if store.is_null() {
+            return Vec::new();
+        }
+        unsafe { store.deref() }.shard_stats()
+    }
+
+    /// MetricsEnabled reports whether the cache is keeping statistics.
+    pub fn metrics_enabled(&self) -> bool {
+        self.metrics.get().is_some()
+    }
+
+    /// EnableMetrics starts keeping statistics on a cache built without them.
+    /// Counting starts from zero; calling it again does nothing.
+    pub fn enable_metrics<'g>(&'g self, guard: &'g Guard) {
+        self.check_guard(guard);
+        if self.metrics.get().is_some() {
+            return;
+        }
+        let metrics: *const Metrics = &**self.metrics.get_or_init(|| Box::new(Metrics::with_mode(DO_NOT_USE, self.metrics_mode, &self.collector)));
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if policy.is_null() {
+            return;
+        }
+        unsafe { policy.as_ptr().as_mut().unwrap() }.set_metrics(metrics);
+    }
+
+    /// Evictions returns the number of keys evicted to make room since the cache
+    /// started, and their total cost. It is None when metrics are off.
+    pub fn evictions<'g>(&'g self, guard: &'g Guard) -> Option<(u64, u64)> {
+        self.check_guard(guard);
+        let metrics = self.metrics.get()?;
+        Some((metrics.get(KEY_EVICT, guard), metrics.get(COST_EVICT, guard)))
+    }
+
+    /// record_eviction counts an item evicted by the policy, when metrics are on,
+    /// and tells the observer.
+    fn record_eviction(&self, key_hash: u64, cost: i64, guard: &Guard<'_>) {
+        if let Some(metrics) = self.metrics.get() {
+            metrics.add(KEY_EVICT, key_hash, 1, guard);
+            metrics.add(COST_EVICT, key_hash, cost as u64, guard);
+        }
+        self.observe(Event::Evict { key: key_hash, cost });
+    }
+
+    /// PolicyCap returns the capacity still available in the cache, in the same
+    /// units as the cost passed to Set.
+    pub fn policy_cap<'g>(&'g self, guard: &'g Guard) -> i64 {
+        self.check_guard(guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if policy.is_null() {
+            return self.max_cost;
+        }
+        unsafe { policy.deref() }.cap()
+    }
+
+    /// KeyCost returns the cost the policy tracks for the key, or None if the
+    /// key isn't in the cache.
+    pub fn key_cost<'g, Q: ?Sized + CacheKey>(&'g self, key: &Q, guard: &'g Guard) -> Option<i64>
+        where K: Borrow<Q>
+    {
+        self.check_guard(guard);
+        let (key_hash, _conflict) = self.hash(key);
+
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if policy.is_null() {
+            return None;
+        }
+        match unsafe { policy.deref() }.cost(&key_hash, guard) {
+            -1 => None,
+            cost => Some(cost),
+        }
+    }
+
+    /// DecayFrequencies ages the access frequencies kept by the admission policy:
+    /// counters are halved and the doorkeeper is cleared. Stored values and their
+    /// costs are left alone.
+    pub fn decay_frequencies<'g>(&'g self, guard: &'g Guard) {
+        self.check_guard(guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if policy.is_null() {
+            return;
+        }
+        unsafe { policy.as_ptr().as_mut().unwrap() }.decay(guard)
+    }
+
+    /// EstimateFrequency returns how often the key has been accessed recently,
+    /// as seen by the TinyLFU admission policy.
+    pub fn estimate_frequency<'g, Q: ?Sized + CacheKey>(&'g self, key: &Q, guard: &'g Guard) -> i64
+        where K: Borrow<Q>
+    {
+        self.check_guard(guard);
+        let (key_hash, _conflict) = self.hash(key);
+
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if policy.is_null() {
+            return 0;
+        }
+        unsafe { policy.as_ptr().as_mut().unwrap() }.estimate(key_hash, guard)
+    }
+
+    /// FrequencyHistogram returns the hash of every key in the cache with how often
+    /// it has been accessed recently, as seen by the TinyLFU admission policy,
+    /// hottest first. Accesses still buffered aren't counted yet.
+    pub fn frequency_histogram<'g>(&'g self, guard: &'g Guard) -> Vec<(u64, i64)> {
+        self.check_guard(guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if policy.is_null() {
+            return vec![];
+        }
+        unsafe { policy.deref() }.frequency_histogram()
+    }
+
+    /// CostHistogram counts the items in the cache by cost, for sizing max_cost.
+    /// buckets are ascending upper bounds: item costs up to buckets[0] go in the
+    /// first count, and so on, with one more count at the end for costs above
+    /// the last bound.
+    pub fn cost_histogram<'g>(&'g self, buckets: &[i64], guard: &'g Guard) -> Vec<u64> {
+        self.check_guard(guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if policy.is_null() {
+            return vec![0; buckets.len() + 1];
+        }
+        unsafe { policy.deref() }.cost_histogram(buckets)
+    }
+
+    /// EntryAge returns how long ago the value of the key was set, or None if the
+    /// key isn't in the cache.
+    pub fn entry_age<'g, Q: ?Sized + CacheKey>(&'g self, key: &Q, guard: &'g Guard) -> Option<Duration>
+        where K: Borrow<Q>
+    {
+        self.check_guard(guard);
+        let (key_hash, conflict) = self.hash(key);
+        let store = self.store.load(Ordering::SeqCst, guard);
+        if store.is_null() {
+            return None;
+        }
+        unsafe { store.deref() }.entry_age(key_hash, conflict)
+    }
+
+    /// FrequencySnapshot copies the access frequencies kept by the admission
+    /// policy, e.g. to warm start another cache with them.
+    pub fn frequency_snapshot<'g>(&'g self, guard: &'g Guard) -> Option<CmSketchSnapshot> {
+        self.check_guard(guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if policy.is_null() {
+            return None;
+        }
+        Some(unsafe { policy.deref() }.frequencies())
+    }
+
+    /// DoorkeeperFpRate estimates how often the admission doorkeeper takes a key
+    /// seen for the first time for one seen before. It grows as the doorkeeper
+    /// fills up, until the counters are reset; see TinyLfuConfig::doorkeeper_fp.
+    pub fn doorkeeper_fp_rate<'g>(&'g self, guard: &'g Guard) -> f64 {
+        self.check_guard(guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if policy.is_null() {
+            return 0.0;
+        }
+        unsafe { policy.deref() }.admit.doorkeeper_fp_rate()
+    }
+
+    /// EvictionPreview returns the key hashes setting a new key of the given cost
+    /// would evict, without evicting anything. It is empty if the item would fit,
+    /// or be rejected. The new key is taken to be one the policy hasn't seen yet.
+    pub fn eviction_preview<'g>(&'g self, cost: i64, guard: &'g Guard) -> Vec<u64> {
+        self.check_guard(guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if policy.is_null() {
+            return vec![];
+        }
+        let cost = if self.count_based || cost == 0 { 1 } else { cost };
+        unsafe { policy.deref() }.preview_victims(0, cost, &|key| self.can_evict_key(key, guard))
+    }
+}
+
+impl<V, K, S> Cache<K, V, S>
+    where
+        K: Sync + Send + Clone + CacheKey + Ord + 'static,
+        V: Sync + Send,
+        S: BuildHasher,
+{
+    /*    fn init_metrics2<'g>(&'g self, guard: &'g Guard<'_>) -> Shared<'g, Metrics> {
+            loop {
+                let mut metrics = self.metrics.load(Ordering::SeqCst, guard);
+                if !metrics.is_null() {
+                    break metrics;
+                }
+
+                metrics = Shared::boxed(Metrics::new(, &self.collector), &self.collector);
+                self.metrics.store(metrics, Ordering::SeqCst);
+                break metrics;
+            }
+        }*/
+
+
+
+    /// WarmStart builds a cache from the config, restores the access frequencies
+    /// of the admission policy from the snapshot and then sets the entries, as
+    /// (key, value, cost). Keys that were hot when the snapshot was taken are
+    /// protected from eviction right away, instead of having to earn it again.
+    /// The frequencies are kept by key hash, so both caches need a hasher that
+    /// hashes alike in each, not a randomly seeded one like the default. It fails
+    /// if the snapshot isn't shaped like one a sketch could have produced.
+    pub fn warm_start(frequencies: CmSketchSnapshot, entries: Vec<(K, V, i64)>, config: Config<K, V>) -> Result<Self, SnapshotError>
+        where S: Default
+    {
+        let freq = CmSketch::from_snapshot(frequencies)?;
+        let cache = Self::with_hasher(S::default(), config);
+        let guard = cache.guard();
+        let policy = cache.policy.load(Ordering::SeqCst, &guard);
+        unsafe { policy.as_ptr().as_mut().unwrap() }.restore_frequencies(freq);
+        for (key, value, cost) in entries {
+            cache.set(key, value, cost, &guard);
+        }
+        drop(guard);
+        Ok(cache)
+    }
+
+    /// Set attempts to add the key-value item to the cache. If it returns false,
+    /// then the Set was dropped and the key-value item isn't added to the cache:
+    /// the policy rejected it, or its cost is over Config.max_item_cost. If it
+    /// returns true, the item was added and other items were evicted in order to
+    /// make room.
+    ///
+    /// To dynamically evaluate the items cost using the Config.Coster function, set
+    /// the cost parameter to 0 and Coster will be ran when needed in order to find
+    /// the items true cost. Without a Coster, a cost of 0 counts as 1.
+    pub fn set<'g>(&'g self, key: K, value: V, cost: i64, guard: &'g Guard<'_>) -> bool {
+        self.check_guard(guard);
+        self.set_with_ttl(key, value, cost, Duration::from_millis(0), guard)
+    }
+
+
+    /// SetWithTTL works like Set but adds a key-value pair to the cache that will expire
+    /// after the specified TTL (time to live) has passed. A zero value means the value never
+    /// expires, which is identical to calling Set. A negative value is a no-op and the value
+    /// is discarded.
+    pub fn set_with_ttl<'g>(&'g self, key: K, value: V, cost: i64, ttl: Duration, guard: &'g Guard) -> bool {
+        let ttl = self.biased_ttl(&value, cost, ttl);
+        self.insert(key, value, cost, ttl, None, guard).is_some()
+    }
+
+    /// SetPrehashed works like Set for a key the caller already hashed, skipping
+    /// the hash step. As with get_prehashed, key_hash and conflict must come from
+    /// the scheme `Cache::hash` uses, or one scheme used for every get and set of
+    /// the cache.
+    pub fn set_prehashed<'g>(&'g self, key_hash: u64, conflict: u64, value: V, cost: i64, guard: &'g Guard) -> bool {
+        self.check_guard(guard);
+        self.insert_hashed(key_hash, conflict, value, cost, Duration::from_millis(0), None, guard).is_some()
+    }
+
+    // biased_ttl applies Config.ttl_cost_bias to a non-zero TTL, with the cost
+    // the item will be charged.
+    fn biased_ttl(&self, value: &V, cost: i64, ttl: Duration) -> Duration {
+        match self.ttl_cost_bias {
+            Some(bias) if !ttl.is_zero() => bias(ttl, self.item_cost(value, cost)),
+            _ => ttl,
+        }
+    }
+
+    // item_cost returns the cost an item set with the given cost is charged.
+    fn item_cost(&self, value: &V, cost: i64) -> i64 {
+        if self.count_based {
+            1
+        } else if cost == 0 {
+            self.cost.map_or(1, |f| f(value))
+        } else {
+            cost
+        }
+    }
+
+    /// RecomputeCosts gives every entry the cost f returns for its value, e.g.
+    /// after the values were resized elsewhere, and then evicts until the total
+    /// is back under max_cost. Costs of 0 go through the Config's cost function,
+    /// as with Set; in a count based cache every entry keeps costing 1.
+    pub fn recompute_costs<'g, F: Fn(&V) -> i64>(&'g self, f: F, guard: &'g Guard) {
+        self.check_guard(guard);
+        let store = self.store.load(Ordering::SeqCst, guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if store.is_null() || policy.is_null() || self.count_based {
+            return;
+        }
+        let costs: Vec<(u64, i64)> = unsafe { store.as_ptr().as_mut().unwrap() }.with_lock(|data, _| {
+            data.iter()
+                .flat_map(|m| m.iter())
+                .filter_map(|(key, node)| {
+                    let value = unsafe { node.value.load(Ordering::SeqCst, guard).as_ref() }?;
+                    Some((*key, self.item_cost(value, f(value))))
+                })
+                .collect()
+        });
+        let victims = unsafe { policy.as_ptr().as_mut().unwrap() }.recost(&costs, &|key| self.can_evict_key(key, guard), guard);
+        for victim in victims {
+            if let Some((conflict, value)) = unsafe { store.as_ptr().as_mut().unwrap() }.del(&victim.key, None, guard) {
+                if let Some(on_evict) = self.on_evict {
+                    on_evict(victim.key, conflict, value, victim.cost)
+                }
+                self.record_eviction(victim.key, victim.cost, guard);
+            }
+        }
+    }
+
+    /// SetIfGeneration replaces the value of key, like Set, but only if the entry
+    /// is still at the expected generation from get_with_generation. The check
+    /// and the write happen under the store lock. It returns false, dropping the
+    /// value, if the generation moved on or the key isn't in the cache.
+    pub fn set_if_generation<'g>(&'g self, key: K, value: V, cost: i64, expected: u64, guard: &'g Guard) -> bool {
+        self.check_guard(guard);
+        let store = self.store.load(Ordering::SeqCst, guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if store.is_null() || policy.is_null() || self.on_conflict == ConflictPolicy::KeepExisting {
+            return false;
+        }
+        let (key_hash, conflict) = self.hash(&key);
+        let cost = self.item_cost(&value, cost);
+        let value = Shared::boxed(value, &self.collector);
+        let item = Item {
+            flag: ItemUpdate,
+            key: key_hash,
+            conflict,
+            value: Atomic::from(value),
+            cost,
+            expiration: None,
+        };
+        if !unsafe { store.as_ptr().as_mut().unwrap() }.update_if(&item, Some(expected), guard) {
+            // the store didn't take the value, nothing else references it
+            unsafe { guard.retire_shared(value) };
+            return false;
+        }
+        unsafe { policy.as_ptr().as_mut().unwrap() }.update(key_hash, cost, guard);
+        self.observe(Event::Update { key: key_hash, cost });
+        true
+    }
+
+    /// SetWithTTI works like Set but the item expires once it hasn't been read
+    /// for idle: every Get or GetCloned pushes its deadline back by idle, and
+    /// CleanUp removes it once that passes. A zero idle means it never expires.
+    /// It returns false if the policy rejected the item.
+    pub fn set_with_tti<'g>(&'g self, key: K, value: V, cost: i64, idle: Duration, guard: &'g Guard) -> bool {
+        self.check_guard(guard);
+        let (key_hash, conflict) = self.hash(&key);
+        if self.insert(key, value, cost, idle, None, guard).is_none() {
+            return false;
+        }
+        if !idle.is_zero() {
+            let store = self.store.load(Ordering::SeqCst, guard);
+            unsafe { store.as_ptr().as_mut().unwrap() }.set_idle(key_hash, conflict, idle);
+        }
+        true
+    }
+
+    /// SetBatchWithTTL sets every (key, value, cost, ttl) item in turn, as
+    /// set_with_ttl would, so each gets its own deadline. It returns whether each
+    /// item was stored, in order: false if the policy rejected it.
+    pub fn set_batch_with_ttl<'g>(&'g self, items: Vec<(K, V, i64, Duration)>, guard: &'g Guard) -> Vec<bool> {
+        self.check_guard(guard);
+        items.into_iter()
+            .map(|(key, value, cost, ttl)| {
+                let ttl = self.biased_ttl(&value, cost, ttl);
+                self.insert(key, value, cost, ttl, None, guard).is_some()
+            })
+            .collect()
+    }
+
+    /// SetAndGet works like Set but returns a reference to the stored value, saving
+    /// a lookup when the caller needs it right away. It returns None if the policy
+    /// rejected the item, or if the key was already there and the cache keeps
+    /// existing values. The reference stays valid for as long as the guard is
+    /// held, even if the key is overwritten or evicted in the meantime.
+    pub fn set_and_get<'g>(&'g self, key: K, value: V, cost: i64, guard: &'g Guard) -> Option<&'g V> {
+        self.check_guard(guard);
+        self.insert(key, value, cost, Duration::from_millis(0), None, guard)
+    }
+
+    /// SetWithVictims works like Set but also returns the items the policy evicted
+    /// to make room, as (key hash, value) pairs: the same ones OnEvict is called
+    /// with. The flag is false if the item was rejected.
+    pub fn set_with_victims<'g>(&'g self, key: K, value: V, cost: i64, guard: &'g Guard) -> (bool, Vec<(u64, V)>)
+        where V: Clone
+    {
+        self.check_guard(guard);
+        let mut victims = Vec::new();
+        let added = self.insert(key, value, cost, Duration::from_millis(0), Some(&mut victims), guard).is_some();
+        (added, victims.into_iter().map(|(k, v)| (k, v.clone())).collect())
+    }
+
+    /// insert does the work of set_with_ttl, returning the stored value, or None
+    /// if it was rejected. Evicted items are pushed to victims when given.
+    fn insert<'g>(&'g self, key: K, value: V, cost: i64, ttl: Duration, victims: Option<&mut Vec<(u64, &'g V)>>, guard: &'g Guard) -> Option<&'g V> {
+        let (key_hash, conflict) = self.hash(&key);
+        self.insert_hashed(key_hash, conflict, value, cost, ttl, victims, guard)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn insert_hashed<'g>(&'g self, key_hash: u64, conflict: u64, value: V, cost: i64, ttl: Duration, victims: Option<&mut Vec<(u64, &'g V)>>, guard: &'g Guard) -> Option<&'g V> {
+        match self.try_insert_hashed(key_hash, conflict, value, cost, ttl, victims, guard) {
+            Ok(v) => Some(v),
+            Err(rejected) => {
+                // nothing else references the rejected value
+                unsafe { guard.retire_shared(rejected) };
+                None
+            }
+        }
+    }
+
+    /// try_insert_hashed works like insert_hashed but hands a rejected value back,
+    /// for the caller to retire once nothing can reach it any more.
+    #[allow(clippy::too_many_arguments)]
+    fn try_insert_hashed<'g>(&'g self, key_hash: u64, conflict: u64, value: V, cost: i64, ttl: Duration, mut victims: Option<&mut Vec<(u64, &'g V)>>, guard: &'g Guard) -> Result<&'g V, Shared<'g, V>> {
+        let expiration = if ttl.is_zero() {
+            None
+        } else {
+            Some(ttl::now() + ttl)
+        };
+
+        let mut store = self.store.load(Ordering::SeqCst, guard);
+        let value = Shared::boxed(value, &self.collector);
+        // let mut old_value = None;
+
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        loop {
+            if store.is_null() {
+                store = self.init_store(guard);
+                continue;
+            }
+
+            let dstore = unsafe { store.as_ptr() };
+            let dstore = unsafe { dstore.as_mut().unwrap() };
+
+            let mut item = Item {
+                flag: ItemNew,
+                key: key_hash,
+                conflict: conflict,
+                value: Atomic::null(),
+                cost,
+                expiration,
+            };
+            item.value.store(value, Ordering::SeqCst);
+
+            if dstore.update(&item, guard) {
+                item.flag = ItemUpdate
+            };
+
+            let node = Node::new(key_hash, conflict, Atomic::null(), expiration);
+            node.value.store(value, Ordering::SeqCst);
+
+            match item.flag {
+                ItemNew | ItemUpdate => unsafe {
+                    if self.count_based {
+                        item.cost = 1;
+                    } else if item.cost == 0 && self.cost.is_some() {
+                        item.cost = (self.cost.unwrap())(item.value.load(Ordering::SeqCst, guard).deref());
+                    } else if item.cost == 0 {
+                        // without a cost function every item takes at least one unit of max_cost
+                        item.cost = 1;
+                    }
+                }
+                _ => {}
+            }
+
+
+            match item.flag {
+                ItemNew => {
+                    let (evicted, added) = unsafe {
+                        let policy = policy.as_ptr();
+                        policy.as_mut().unwrap().add_with_veto(item.key, item.cost, &|key| self.can_evict_key(key, guard), guard)
+                    };
+
+                    if added {
+                        dstore.set(node, guard);
+                        if let Some(metrics) = self.metrics.get() {
+                            metrics.add(KEY_ADD, item.key, 1, guard)
+                        }
+                        self.observe(Event::Insert { key: item.key, cost: item.cost });
+                    } else {
+                        self.observe(Event::Reject { key: item.key, cost: item.cost, reason: RejectReason::Admission });
+                    }
+
+
+                    for victim in &evicted {
+                        let Some((c, v)) = unsafe { store.as_ptr().as_mut().unwrap() }.del(&victim.key, None, guard) else {
+                            continue;
+                        };
+                        // the policy only knows the key, the store hands back the rest
+                        if let Some(on_evict) = self.on_evict {
+                            on_evict(victim.key, c, v, victim.cost)
+                        }
+                        if let Some(victims) = victims.as_mut() {
+                            victims.push((victim.key, v));
+                        }
+                        self.record_eviction(victim.key, victim.cost, guard);
+                    }
+                    if !added {
+                        break Err(value);
+                    }
+                    break Ok(unsafe { &**value.deref() });
+                }
+                ItemDelete => {
+                    unsafe {
+                        let policy = policy.as_ptr();
+                        policy.as_mut().unwrap().del(&item.key, guard)
+                    }
+                    if dstore.del(&item.key, Some(item.conflict), guard).is_some() {
+                        self.observe(Event::Delete { key: item.key });
+                    }
+                }
+                ItemUpdate => {
+                    if self.on_conflict == ConflictPolicy::KeepExisting {
+                        // the store kept the first value, nothing references this one
+                        self.observe(Event::Reject { key: item.key, cost: item.cost, reason: RejectReason::KeepExisting });
+                        break Err(value);
+                    }
+                    unsafe {
+                        let policy = policy.as_ptr();
+                        policy.as_mut().unwrap().update(item.key, item.cost, guard);
+                    }
+                    self.observe(Event::Update { key: item.key, cost: item.cost });
+                    // unsafe { policy.deref() }.update(item2.key, item2.cost, guard);
+                }
+            }
+
+
+            // self.process_items(node, item2, cost, guard);
+
+            break Ok(unsafe { &**value.deref() });
+        }
+    }
+
+
+    /// GetOrDefault returns the value of key, setting it to `V::default()` with a
+    /// cost of 1 on a miss. If the policy rejects the default it is still
+    /// returned, it just isn't kept, so the reference is good for as long as the
+    /// guard is held either way.
+    pub fn get_or_default<'g>(&'g self, key: K, guard: &'g Guard) -> &'g V
+        where V: Default
+    {
+        self.check_guard(guard);
+        if let Some(v) = self.get(&key, guard) {
+            return v;
+        }
+        if let Some(v) = self.insert(key.clone(), V::default(), 1, Duration::from_millis(0), None, guard) {
+            return v;
+        }
+        // the cache kept a value set in the meantime
+        if let Some(v) = self.get(&key, guard) {
+            return v;
+        }
+        let value = Shared::boxed(V::default(), &self.collector);
+        // safety: the value is only freed once the guard is released.
+        unsafe {
+            guard.retire_shared(value);
+            value.deref()
+        }
+    }
+
+    /// GetOrInsertWith returns the value of key, calling f to compute it (and its
+    /// cost) on a miss. The computed value is only stored if the policy admits
+    /// it; if it doesn't, the caller still gets it back, owned, in Err, and the
+    /// cache is left as it was.
+    pub fn get_or_insert_with<'g, F>(&'g self, key: K, f: F, guard: &'g Guard) -> Result<&'g V, V>
+        where F: FnOnce() -> (V, i64), V: Clone
+    {
+        self.check_guard(guard);
+        if let Some(v) = self.get(&key, guard) {
+            return Ok(v);
+        }
+        let (value, cost) = f();
+        if let Some(v) = self.insert(key.clone(), value.clone(), cost, Duration::from_millis(0), None, guard) {
+            return Ok(v);
+        }
+        // the cache kept a value set in the meantime
+        match self.get(&key, guard) {
+            Some(v) => Ok(v),
+            None => Err(value),
+        }
+    }
+
+    /// GetOrInsertSingleFlight returns the value of key, calling f to load it (and
+    /// its cost) on a miss. Concurrent misses on the same key, through this cache
+    /// or any of its clones, are collapsed: only the first caller runs f, the
+    /// others block until it has set the value and then read it from the cache.
+    /// If the policy rejected the loaded value, it isn't kept but is still handed
+    /// to every caller, valid for as long as their guards are held. It only
+    /// returns None if the loaded value was admitted and then evicted before a
+    /// waiter could read it.
+    pub fn get_or_insert_single_flight<'g, F>(&'g self, key: K, f: F, guard: &'g Guard) -> Option<&'g V>
+        where F: FnOnce() -> (V, i64)
+    {
+        self.check_guard(guard);
+        if let Some(v) = self.get(&key, guard) {
+            return Some(v);
+        }
+
+        let (key_hash, conflict) = self.hash(&key);
+        let call = {
+            let mut in_flight = self.in_flight.lock();
+            match in_flight.get(&key_hash) {
+                Some(call) => Err(call.clone()),
+                None => {
+                    let call = Arc::new(Call::new());
+                    in_flight.insert(key_hash, call.clone());
+                    Ok(call)
+                }
+            }
+        };
+
+        match call {
+            Err(call) => {
+                call.wait();
+                let rejected = call.rejected.load(Ordering::SeqCst, guard);
+                let v = if rejected.is_null() {
+                    self.get(&key, guard)
+                } else {
+                    // safety: the value is retired by the last to release the call,
+                    // and only freed once this guard is released.
+                    Some(unsafe { &**rejected.deref() })
+                };
+                Call::release(call, guard);
+                v
+            }
+            Ok(call) => {
+                // waiters are released even if f panics
+                let done = CallDone { cache: self, key_hash, call: Some(call), guard };
+
+                // a previous flight may have finished between our miss and taking the lock
+                if let Some(v) = self.get(&key, guard) {
+                    return Some(v);
+                }
+                let (value, cost) = f();
+                match self.try_insert_hashed(key_hash, conflict, value, cost, Duration::from_millis(0), None, guard) {
+                    Ok(v) => Some(v),
+                    Err(rejected) => {
+                        done.call.as_ref().unwrap().rejected.store(rejected, Ordering::SeqCst);
+                        Some(unsafe { &**rejected.deref() })
+                    }
+                }
+            }
+        }
+    }
+
+    /// Del deletes the key-value item from the cache if it exists.
+    pub fn del<'g, Q: ?Sized + CacheKey>(&'g self, key: &Q, guard: &'g Guard)
+        where K: Borrow<Q>
+    {
+        self.check_guard(guard);
+        let (key_hash, conflict) = self.hash(key);
+        let item = Item {
+            flag: ItemDelete,
+            key: key_hash,
+            conflict: conflict,
+            value: Atomic::null(),
+            cost: 0,
+            expiration: None,
+        };
+
+        let node = Node::new(0, 0, Atomic::null(), None);
+
+
+        self.process_items(node, item, 0, guard);
+        // self.set_buf.send(item);
+    }
+
+
+    /// Pin keeps the key from being evicted to make room, like a can_evict that
+    /// returns false for it, until unpin is called. Its TTL still applies, and a
+    /// pin can be placed before the key is set. It stays through deletes.
+    pub fn pin<'g, Q: ?Sized + CacheKey>(&'g self, key: &Q, guard: &'g Guard)
+        where K: Borrow<Q>
+    {
+        self.check_guard(guard);
+        let (key_hash, _conflict) = self.hash(key);
+        self.pinned.lock().insert(key_hash);
+    }
+
+    /// Unpin lets the key be evicted again.
+    pub fn unpin<'g, Q: ?Sized + CacheKey>(&'g self, key: &Q, guard: &'g Guard)
+        where K: Borrow<Q>
+    {
+        self.check_guard(guard);
+        let (key_hash, _conflict) = self.hash(key);
+        self.pinned.lock().remove(&key_hash);
+    }
+
+    /// RemoveIf deletes the key only if f returns true for its current value, and
+    /// reports whether it did. The check and the delete happen under the store
+    /// lock, so no set can slip in between.
+    pub fn remove_if<'g, Q: ?Sized + CacheKey, F: FnOnce(&V) -> bool>(&'g self, key: &Q, f: F, guard: &'g Guard) -> bool
+        where K: Borrow<Q>
+    {
+        self.check_guard(guard);
+        let (key_hash, conflict) = self.hash(key);
+
+        let store = self.store.load(Ordering::SeqCst, guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if store.is_null() || policy.is_null() {
+            return false;
+        }
+        if !unsafe { store.as_ptr().as_mut().unwrap() }.remove_if(key_hash, conflict, f, guard) {
+            return false;
+        }
+        unsafe { policy.as_ptr().as_mut().unwrap() }.del(&key_hash, guard);
+        self.observe(Event::Delete { key: key_hash });
+        true
+    }
+
+    /// Refresh replaces the value of key with the one f computes from it, along
+    /// with its cost, keeping the TTL. Unlike set it needs an existing value, and
+    /// returns false without calling f if the key isn't in the cache. f runs
+    /// under the store lock, so concurrent refreshes of a key are applied one
+    /// after the other; it must not call back into the cache.
+    pub fn refresh<'g, Q: ?Sized + CacheKey, F: FnOnce(&V) -> (V, i64)>(&'g self, key: &Q, f: F, guard: &'g Guard) -> bool
+        where K: Borrow<Q>
+    {
+        self.check_guard(guard);
+        let (key_hash, conflict) = self.hash(key);
+
+        let store = self.store.load(Ordering::SeqCst, guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if store.is_null() || policy.is_null() {
+            return false;
+        }
+        let refresh = |old: &V| {
+            let (value, cost) = f(old);
+            let cost = self.item_cost(&value, cost);
+            (value, cost)
+        };
+        let cost = match unsafe { store.as_ptr().as_mut().unwrap() }.refresh(key_hash, conflict, refresh, guard) {
+            Some(cost) => cost,
+            None => return false,
+        };
+        unsafe { policy.as_ptr().as_mut().unwrap() }.update(key_hash, cost, guard);
+        self.observe(Event::Update { key: key_hash, cost });
+        true
+    }
+
+    /// WithEntries runs f with exclusive access to the entries of the given keys:
+    /// the store stays locked until f returns, so other callers see either none
+    /// or all of the changes f makes. The store has a single lock, so there is no
+    /// lock order to get wrong. f must not call back into the cache.
+    pub fn with_entries<'g, R, F>(&'g self, keys: &[K], f: F, guard: &'g Guard) -> R
+        where F: FnOnce(&mut EntryAccessor<'_, 'g, V, S>) -> R
+    {
+        self.check_guard(guard);
+        let keys = keys.iter().map(|k| self.hash(k)).collect();
+        let mut store = self.store.load(Ordering::SeqCst, guard);
+        if store.is_null() {
+            store = self.init_store(guard);
+        }
+        unsafe { store.as_ptr().as_mut().unwrap() }.with_lock(|data, generation| {
+            f(&mut EntryAccessor {
+                data,
+                generation,
+                keys,
+                build_hasher: &self.build_hasher,
+                conflict_hash: self.conflict_hash,
+                guard,
+            })
+        })
+    }
+
+    /// SwapContents replaces everything in the cache with new_entries, as
+    /// (key, value, cost), so readers see either the old set or the new one and
+    /// never a mix. The new entries are admitted by a fresh policy, then the
+    /// store and the policy are swapped while both their locks are held, so no
+    /// set or del lands between the two. The tables are swapped in place rather
+    /// than through the Atomic pointers since clones of the cache share them.
+    pub fn swap_contents<'g>(&'g self, new_entries: Vec<(K, V, i64)>, guard: &'g Guard) {
+        self.check_guard(guard);
+        let store = self.init_store(guard);
+        let policy = self.init_policy(guard);
+
+        let mut next = Store::with_capacity(self.initial_capacity);
+        next.on_conflict = self.on_conflict;
+        let mut next_policy = unsafe { policy.deref() }.fresh(&self.tiny_lfu);
+        for (key, value, cost) in new_entries {
+            let (key_hash, conflict) = self.hash(&key);
+            let cost = self.item_cost(&value, cost);
+            let value = Shared::boxed(value, &self.collector);
+            if next.update(&Item {
+                flag: ItemUpdate,
+                key: key_hash,
+                conflict,
+                value: Atomic::from(value),
+                cost,
+                expiration: None,
+            }, guard) {
+                next_policy.update(key_hash, cost, guard);
+                continue;
+            }
+            let (evicted, added) = next_policy.add(key_hash, cost, guard);
+            for victim in evicted {
+                next.del(&victim.key, None, guard);
+            }
+            if added {
+                let node = Node::new(key_hash, conflict, Atomic::null(), None);
+                node.value.store(value, Ordering::SeqCst);
+                next.set(node, guard);
+            } else {
+                unsafe { guard.retire_shared(value) };
+            }
+        }
+
+        let store = unsafe { store.as_ptr().as_mut().unwrap() };
+        // the policy lock is taken first: eviction checks the store while it
+        // holds the policy lock, and nothing holds the store lock waiting for it
+        unsafe { policy.as_ptr().as_mut().unwrap() }.replace(next_policy, || store.swap_data(next, guard));
+    }
+
+    /// Transaction pins a single guard and runs f with a Txn whose operations all
+    /// use it, saving the cost of pinning one per call in hot loops. References
+    /// f gets stay valid until it returns.
+    pub fn transaction<R, F>(&self, f: F) -> R
+        where F: FnOnce(&Txn<'_, K, V, S>) -> R
+    {
+        f(&Txn { cache: self, guard: self.guard() })
+    }
+
+    /// Clear empties the hashmap and zeroes all policy counters. Note that this is
+    /// not an atomic operation (but that shouldn't be a problem as it's assumed that
+    /// Set/Get calls won't be occurring until after this).
+    pub fn clear<'g>(&'g self, guard: &'g Guard) {
+        // block until processItems  is returned
+        let store = self.store.load(Ordering::SeqCst, guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+
+
+        unsafe {
+            if !policy.is_null() {
+                let policy = policy.as_ptr();
+                policy.as_mut().unwrap().clear(guard);
+            }
+        }
+        if !store.is_null() {
+            unsafe {
+                let p = store.as_ptr();
+                p.as_mut().unwrap().clear(guard);
+            };
+        }
+
+        self.clear(guard);
+
+
+        /* let (tx, rx) = crossbeam_channel::unbounded();
+         self.set_buf = tx;
+         self.receiver_buf = rx;*/
+
+        //TODO fix thead after clear
+        /* thread::spawn( || {
+             let guard = crossbeam::epoch::pin();
+             self.process_items(&guard);
+         });*/
+    }
+
+    /// Reserve sizes the store shards and the policy cost map for n more items,
+    /// to avoid rehash pauses while a cache is warming up.
+    pub fn reserve<'g>(&'g self, n: usize, guard: &'g Guard) {
+        self.check_guard(guard);
+        let store = self.init_store(guard);
+        let policy = self.init_policy(guard);
+        unsafe { store.as_ptr().as_mut().unwrap() }.reserve(n, guard);
+        unsafe { policy.as_ptr().as_mut().unwrap() }.reserve(n, guard);
+    }
+
+    /// Compact shrinks the store shards and the policy cost map to fit their
+    /// current contents. Useful after deleting a large share of the keys.
+    pub fn compact<'g>(&'g self, guard: &'g Guard) {
+        self.check_guard(guard);
+        let store = self.store.load(Ordering::SeqCst, guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+
+        if !store.is_null() {
+            unsafe { store.as_ptr().as_mut().unwrap() }.compact(guard);
+        }
+        if !policy.is_null() {
+            unsafe { policy.as_ptr().as_mut().unwrap() }.compact(guard);
+        }
+    }
+
+    pub fn process_items<'g>(&'g self, node: Node<V>, mut item: Item<V>, cost: i64, guard: &'g Guard) {
+        let _cost = cost;
+        match item.flag {
+            ItemNew | ItemUpdate => unsafe {
+                if self.count_based {
+                    item.cost = 1;
+                } else if item.cost == 0 && self.cost.is_some() {
+                    item.cost = (self.cost.unwrap())(item.value.load(Ordering::SeqCst, guard).deref());
+                } else if item.cost == 0 {
+                    // without a cost function every item takes at least one unit of max_cost
+                    item.cost = 1;
+                }
+            }
+            _ => {}
+        }
+
+        match item.flag {
+            ItemNew => {
+                let mut policy = self.policy.load(Ordering::SeqCst, guard);
+                loop {
+                    if policy.is_null() {
+                        policy = self.init_policy(guard);
+                        continue;
+                    }
+                    let (victims, added) = unsafe {
+                        let p = policy.as_ptr();
+                        let p = p.as_mut().unwrap();
+                        p.add_with_veto(item.key, item.cost, &|key| self.can_evict_key(key, guard), guard)
+                    };
+
+                    let store = self.store.load(Ordering::SeqCst, guard);
+                    if added {
+                        let store = unsafe { store.as_ptr() };
+                        let store = unsafe { store.as_mut().unwrap() };
+                        store.set(node, guard);
+                        self.observe(Event::Insert { key: item.key, cost: item.cost });
+                    } else {
+                        self.observe(Event::Reject { key: item.key, cost: item.cost, reason: RejectReason::Admission });
+                    }
+
+                    for i in 0..victims.len() {
+                        let store = unsafe { store.as_ptr() };
+                        let store = unsafe { store.as_mut().unwrap() };
+                        let delVal = store.del(&victims[i].key, None, guard);
+                        match delVal {
+                            Some((c, v)) => {
+                                // the policy only knows the key, the store hands back the rest
+                                if let Some(on_evict) = self.on_evict {
+                                    on_evict(victims[i].key, c, v, victims[i].cost)
+                                }
+                                self.record_eviction(victims[i].key, victims[i].cost, guard);
+                            }
+                            None => { continue; }
+                        }
+                    }
+                    break;
+                }
+            }
+            ItemDelete => {
+                let policy = self.policy.load(Ordering::SeqCst, guard);
+                if policy.is_null() {
+                    return;
+                }
+
+                let store = self.store.load(Ordering::SeqCst, guard);
+                unsafe {
+                    let p = policy.as_ptr();
+                    let p = p.as_mut().unwrap();
+                    p.del(&item.key, guard)
+                }
+
+                let store = unsafe { store.as_ptr() };
+                let store = unsafe { store.as_mut().unwrap() };
+                if store.del(&item.key, Some(item.conflict), guard).is_some() {
+                    self.observe(Event::Delete { key: item.key });
+                }
+            }
+            ItemFlag::ItemUpdate => {
+                let policy = self.policy.load(Ordering::SeqCst, guard);
+                if policy.is_null() {
+                    return;
+                }
+                unsafe {
+                    let p = policy.as_ptr();
+                    let p = p.as_mut().unwrap();
+                    p.update(item.key, item.cost, guard);
+                }
+                self.observe(Event::Update { key: item.key, cost: item.cost });
+            }
+        }
+    }
+
+    /// CleanUp removes the items whose TTL has passed, and with
+    /// `Config::is_reclaimed` set, the items whose values have died.
+    pub fn clean_up<'g>(&'g self, guard: &'g Guard<'_>) {
+        self.check_guard(guard);
+        let store = self.store.load(Ordering::SeqCst, guard);
+        let policy = self.policy.load(Ordering::SeqCst, guard);
+        if store.is_null() || policy.is_null() {
+            return;
+        }
+        let store = unsafe { store.as_ptr().as_mut().unwrap() };
+        let policy = unsafe { policy.as_ptr().as_mut().unwrap() };
+        store.clean_up(policy, guard);
+        if let Some(is_reclaimed) = self.is_reclaimed {
+            for key in store.del_where(is_reclaimed, guard) {
+                policy.del(&key, guard);
+            }
+        }
+    }
+
+    /// compact_ttl drops the expiration buckets that deletes and TTL changes left
+    /// empty, so a long-running cache doesn't keep one for every deadline it has
+    /// ever seen. It returns how many buckets are left.
+    pub fn compact_ttl<'g>(&'g self, guard: &'g Guard<'_>) -> usize {
+        self.check_guard(guard);
+        let store = self.store.load(Ordering::SeqCst, guard);
+        if store.is_null() {
+            return 0;
+        }
+        unsafe { store.deref() }.compact_ttl(guard)
+    }
+}
+
+/// Call is a get_or_insert_single_flight load that other callers can wait on.
+struct Call<V> {
+    done: Mutex<bool>,
+    cond: Condvar,
+    // rejected holds the loaded value if the policy didn't admit it, so the
+    // waiters get it too. The last one to release the call retires it.
+    rejected: Atomic<V>,
+}
+
+impl<V> Call<V> {
+    fn new() -> Self {
+        Call {
+            done: Mutex::new(false),
+            cond: Condvar::new(),
+            rejected: Atomic::null(),
+        }
+    }
+
+    fn wait(&self) {
+        let mut done = self.done.lock();
+        while !*done {
+            self.cond.wait(&mut done);
+        }
+    }
+
+    /// release drops a handle to the call. The last one retires the rejected
+    /// value, if any, with its guard: by then no one can load it any more.
+    fn release(call: Arc<Self>, guard: &Guard<'_>) {
+        if let Some(call) = Arc::into_inner(call) {
+            let rejected = call.rejected.load(Ordering::SeqCst, guard);
+            if !rejected.is_null() {
+                unsafe { guard.retire_shared(rejected) };
+            }
+        }
+    }
+}
+
+/// CallDone ends a flight when dropped: it forgets the call and wakes its waiters.
+struct CallDone<'a, 'g, K, V, S> {
+    cache: &'a Cache<K, V, S>,
+    key_hash: u64,
+    call: Option<Arc<Call<V>>>,
+    guard: &'a Guard<'g>,
+}
+
+impl<'a, 'g, K, V, S> Drop for CallDone<'a, 'g, K, V, S> {
+    fn drop(&mut self) {
+        self.cache.in_flight.lock().remove(&self.key_hash);
+        if let Some(call) = self.call.take() {
+            *call.done.lock() = true;
+            call.cond.notify_all();
+            Call::release(call, self.guard);
+        }
+    }
+}
+
+type MetricType = usize;
+
+pub const HIT: MetricType = 0;
+pub const MISS: MetricType = 1;
+// The following 3 keep track of number of keys added, updated and evicted.
+const KEY_ADD: MetricType = 2;
+pub const KEY_UPDATE: MetricType = 3;
+pub const KEY_EVICT: MetricType = 4;
+// The following 2 keep track of cost of keys added and evicted.
+pub const COST_ADD: MetricType = 5;
+pub const COST_EVICT: MetricType = 6;
+// The following keep track of how many sets were dropped or rejected later.
+
+pub const DROP_SETS: MetricType = 7;
+pub const REJECT_SETS: MetricType = 8;
+// The following 2 keep track of how many gets were kept and dropped on the
+// floor.
+pub const DROP_GETS: MetricType = 9;
+pub const KEEP_GETS: MetricType = 10;
+// This should be the final enum. Other enums should be set before this.
+pub const DO_NOT_USE: MetricType = 11;
+
+/// MetricsMode picks how Metrics keeps its counters.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub enum MetricsMode {
+    /// Sharded keeps a row of 256 counters per metric type, to lower contention
+    /// between threads updating the same metric.
+    #[default]
+    Sharded,
+    /// Compact keeps a single counter per metric type. It takes far less memory,
+    /// and suits caches used from one thread or a few.
+    Compact,
+}
+
+/// MetricsRow is the row of counters of one metric type.
+type MetricsRow = [AtomicU64; 256];
+
+fn metrics_row(counts: impl Fn(usize) -> u64) -> MetricsRow {
+    std::array::from_fn(|i| AtomicU64::new(counts(i)))
+}
+
+/// HitWindow is a ring buffer of one bit per get, set for a hit, holding the
+/// last `len` of them.
+pub(crate) struct HitWindow {
+    bits: Box<[AtomicU64]>,
+    len: usize,
+    // next counts the gets recorded, the slot of the next one is next % len.
+    next: AtomicU64,
+}
+
+impl HitWindow {
+    pub(crate) fn new(len: usize) -> Self {
+        HitWindow {
+            bits: (0..len.div_ceil(64)).map(|_| AtomicU64::new(0)).collect(),
+            len,
+            next: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn deep_clone(&self) -> Self {
+        HitWindow {
+            bits: self.bits.iter().map(|w| AtomicU64::new(w.load(Ordering::Relaxed))).collect(),
+            len: self.len,
+            next: AtomicU64::new(self.next.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub(crate) fn record(&self, hit: bool) {
+        if self.len == 0 {
+            return;
+        }
+        let i = (self.next.fetch_add(1, Ordering::Relaxed) % self.len as u64) as usize;
+        let mask = 1u64 << (i % 64);
+        if hit {
+            self.bits[i / 64].fetch_or(mask, Ordering::Relaxed);
+        } else {
+            self.bits[i / 64].fetch_and(!mask, Ordering::Relaxed);
+        }
+    }
+
+    /// ratio returns the share of the recorded gets, at most len, that hit.
+    pub(crate) fn ratio(&self) -> f64 {
+        let n = self.next.load(Ordering::Relaxed).min(self.len as u64);
+        if n == 0 {
+            return 0.0;
+        }
+        // slots past n haven't been written yet, so they are still 0
+        let hits: u32 = self.bits.iter().map(|w| w.load(Ordering::Relaxed).count_ones()).sum();
+        hits as f64 / n as f64
+    }
+}
+
+/// Metrics keeps a row of 256 counters per metric type. Updates are spread over
+/// the row by key hash to lower contention, and reads sum the whole row. The
+/// counters are atomic, so threads landing on the same one don't lose updates.
+/// In compact mode there is a single counter per metric type instead.
+pub struct Metrics {
+    pub(crate) all: Box<[Atomic<MetricsRow>]>,
+    compact: Box<[AtomicU64]>,
+}
+
+impl Clone for Metrics {
+    fn clone(&self) -> Self {
+        Self {
+            all: self.all.clone(),
+            compact: self.compact.iter().map(|n| AtomicU64::new(n.load(Ordering::Relaxed))).collect(),
+        }
+    }
+}
+
+impl Metrics {
+    pub(crate) fn new(n: usize, collector: &Collector) -> Self {
+        Self::with_mode(n, MetricsMode::Sharded, collector)
+    }
+
+    pub(crate) fn with_mode(n: usize, mode: MetricsMode, collector: &Collector) -> Self {
+        if mode == MetricsMode::Compact {
+            return Metrics {
+                all: Box::new([]),
+                compact: (0..n).map(|_| AtomicU64::new(0)).collect(),
+            };
+        }
+        // every metric type needs its own row, so don't clone a single Atomic
+        let data: Vec<_> = (0..n)
+            .map(|_| Atomic::from(Shared::boxed(metrics_row(|_| 0), collector)))
+            .collect();
+        Metrics {
+            all: data.into_boxed_slice(),
+            compact: Box::new([]),
+        }
+    }
+
+    /// deep_clone copies the counters into rows of their own, where clone shares
+    /// them.
+    pub(crate) fn deep_clone(&self, collector: &Collector, guard: &Guard) -> Self {
+        Self {
+            all: self.all.iter().map(|row| {
+                let row = row.load(Ordering::SeqCst, guard);
+                let counts = unsafe { row.as_ref() }.map_or(metrics_row(|_| 0), |r| metrics_row(|i| r[i].load(Ordering::Relaxed)));
+                Atomic::from(Shared::boxed(counts, collector))
+            }).collect(),
+            compact: self.compact.iter().map(|n| AtomicU64::new(n.load(Ordering::Relaxed))).collect(),
+        }
+    }
+
+    /// memory returns the number of bytes the counters take.
+    #[cfg(test)]
+    pub(crate) fn memory(&self) -> usize {
+        self.all.len() * std::mem::size_of::<MetricsRow>() + self.compact.len() * std::mem::size_of::<AtomicU64>()
+    }
+
+    pub(crate) fn get<'g>(&'g self, t: MetricType, guard: &'g Guard) -> u64 {
+        if let Some(n) = self.compact.get(t) {
+            return n.load(Ordering::Relaxed);
+        }
+        let all = self.all[t].load(Ordering::SeqCst, guard);
+        if all.is_null() {
+            return 0;
+        }
+
+        unsafe { all.deref() }.iter().fold(0u64, |total, n| total.wrapping_add(n.load(Ordering::Relaxed)))
+    }
+    pub(crate) fn SetsDropped<'g>(&'g self, guard: &'g Guard) -> u64 {
+        self.get(DROP_SETS, guard)
+    }
+    pub(crate) fn add<'g>(&self, t: MetricType, hash: u64, delta: u64, guard: &'g Guard) {
+        if let Some(n) = self.compact.get(t) {
+            n.fetch_add(delta, Ordering::Relaxed);
+            return;
+        }
+        let all = self.all[t].load(Ordering::SeqCst, guard);
+        if all.is_null() {
+            panic!("metric all is null");
+        }
+        let data = unsafe { all.deref() };
+
+        let idx = (hash % data.len() as u64) as usize;
+        data[idx].fetch_add(delta, Ordering::Relaxed);
+    }
+
+    pub fn clear<'g>(&self, guard: &'g Guard) {
+        let _data = vec![Atomic::from(Shared::boxed(metrics_row(|_| 0), guard.collector().unwrap())); DO_NOT_USE];
+        // self.all.as_mut() = &mut *data.into_boxed_slice();
+    }
+}
+
+/// ConflictPolicy decides what setting a key that is already in the cache does.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub enum ConflictPolicy {
+    /// Overwrite replaces the stored value.
+    #[default]
+    Overwrite,
+    /// KeepExisting keeps the stored value and drops the new one.
+    KeepExisting,
+}
+
+/// Event is a mutation of the cache, as reported to the observer given to
+/// `Cache::with_observer`. Keys are reported by hash, costs as the policy saw them.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Serialize)]
+pub enum Event {
+    /// Insert is a new key stored.
+    Insert { key: u64, cost: i64 },
+    /// Update is a new value stored for a key already in the cache.
+    Update { key: u64, cost: i64 },
+    /// Evict is a key removed by the policy to make room.
+    Evict { key: u64, cost: i64 },
+    /// Delete is a key removed by the caller.
+    Delete { key: u64 },
+    /// Reject is a set that was dropped.
+    Reject { key: u64, cost: i64, reason: RejectReason },
+}
+
+/// RejectReason tells why a set was dropped.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Serialize)]
+pub enum RejectReason {
+    /// Admission is the policy finding the key less valuable than the ones it
+    /// would have to evict, or too costly to fit.
+    Admission,
+    /// KeepExisting is the key being there already, under ConflictPolicy::KeepExisting.
+    KeepExisting,
+}
+
+/// Txn is handed to the closure of `Cache::transaction`. Its operations are the
+/// cache's, run under the guard the transaction pinned.
+pub struct Txn<'a, K, V, S> {
+    cache: &'a Cache<K, V, S>,
+    guard: Guard<'a>,
+}
+
+impl<'a, K, V, S> Txn<'a, K, V, S>
+    where
+        K: Sync + Send + Clone + CacheKey + Ord + 'static,
+        V: Sync + Send,
+        S: BuildHasher,
+{
+    /// Get works like Cache::get.
+    pub fn get<Q: ?Sized + CacheKey>(&self, key: &Q) -> Option<&V>
+        where K: Borrow<Q>
+    {
+        self.cache.get(key, &self.guard)
+    }
+
+    /// Set works like Cache::set.
+    pub fn set(&self, key: K, value: V, cost: i64) -> bool {
+        self.cache.set(key, value, cost, &self.guard)
+    }
+
+    /// Del works like Cache::del.
+    pub fn del<Q: ?Sized + CacheKey>(&self, key: &Q)
+        where K: Borrow<Q>
+    {
+        self.cache.del(key, &self.guard)
+    }
+
+    /// Guard returns the transaction's guard, for the cache methods Txn lacks.
+    pub fn guard(&self) -> &Guard<'a> {
+        &self.guard
+    }
+}
+
+/// EntryAccessor is handed to the closure of `Cache::with_entries`. It reads and
+/// replaces the values of the keys given to with_entries, and no others.
+pub struct EntryAccessor<'a, 'g, V, S> {
+    data: &'a mut Vec<HashMap<u64, Node<V>>>,
+    generation: &'a AtomicU64,
+    keys: Vec<(u64, u64)>,
+    build_hasher: &'a S,
+    conflict_hash: Option<fn(&[u8]) -> u64>,
+    guard: &'g Guard<'g>,
+}
+
+impl<'a, 'g, V, S: BuildHasher> EntryAccessor<'a, 'g, V, S> {
+    fn node<Q: ?Sized + CacheKey>(&mut self, key: &Q) -> Option<&mut Node<V>> {
+        let (key_hash, conflict) = key_to_hash(key, self.build_hasher, self.conflict_hash);
+        if !self.keys.contains(&(key_hash, conflict)) {
+            return None;
+        }
+        match self.data[(key_hash % NUM_SHARDS as u64) as usize].get_mut(&key_hash) {
+            Some(node) if node.conflict == conflict && !matches!(node.expiration, Some(e) if e <= ttl::now()) => Some(node),
+            _ => None,
+        }
+    }
+
+    /// Get returns the value of the key, or None if it isn't in the cache or
+    /// wasn't given to with_entries.
+    pub fn get<Q: ?Sized + CacheKey>(&mut self, key: &Q) -> Option<&'g V> {
+        let guard = self.guard;
+        let value = self.node(key)?.value.load(Ordering::SeqCst, guard);
+        unsafe { value.as_ref() }.map(|v| &**v)
+    }
+
+    /// Set replaces the value of the key, keeping its cost and TTL. It returns
+    /// false, dropping the value, if the key isn't in the cache or wasn't given
+    /// to with_entries.
+    pub fn set<Q: ?Sized + CacheKey>(&mut self, key: &Q, value: V) -> bool {
+        let guard = self.guard;
+        let generation = self.generation;
+        let node = match self.node(key) {
+            Some(node) => node,
+            None => return false,
+        };
+        let value = Shared::boxed(value, guard.collector().unwrap());
+        let old = node.value.swap(value, Ordering::SeqCst, guard);
+        node.generation = next_generation(generation);
+        if !old.is_null() {
+            unsafe { guard.retire_shared(old) };
+        }
+        true
+    }
+}
+
+/// GetResult is returned by `Cache::get_stale`. It tells apart a live entry, an
+/// entry whose TTL has passed but that hasn't been cleaned up yet, and a missing one.
+#[derive(Eq, PartialEq, Debug)]
+pub enum GetResult<T> {
+    Fresh(T),
+    Stale(T),
+    Missing,
+}
+
+/// ConsistencyReport is returned by `Cache::consistency_report`. In a healthy
+/// cache store_entries equals policy_entries and cost_used equals
+/// cost_sum_from_store.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct ConsistencyReport {
+    /// store_entries is the number of entries in the store, expired or not.
+    pub store_entries: usize,
+    /// policy_entries is the number of keys the policy charges a cost for.
+    pub policy_entries: usize,
+    /// cost_used is the total cost the policy thinks the cache holds.
+    pub cost_used: i64,
+    /// cost_sum_from_store adds up the costs the policy has for the keys in the store.
+    pub cost_sum_from_store: i64,
+}
+
+/// ConfigError tells which field of a Config is invalid, and its value.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConfigError {
+    /// NumCounters must be positive.
+    NumCounters(i64),
+    /// MaxCost must be positive.
+    MaxCost(i64),
+    /// BufferItems must not be 0.
+    BufferItems,
+    /// HighWater must be a fraction in (0, 1].
+    HighWater(f64),
+    /// MaxItemCost must be positive when set.
+    MaxItemCost(i64),
+    /// MaxEntries must not be 0 when set.
+    MaxEntries,
+    /// TinyLfuConfig::reset_at must be positive when set.
+    ResetAt(i64),
+    /// TinyLfuConfig::doorkeeper_fp must be a fraction in (0, 1).
+    DoorkeeperFp(f64),
+    /// TinyLfuConfig::sample_size must not be 0.
+    SampleSize,
+    /// TinyLfuConfig::adaptive_sample needs 0 < min <= max and hit ratios with
+    /// 0 <= low_hit_ratio <= high_hit_ratio <= 1.
+    AdaptiveSample,
+    /// TinyLfuConfig::adaptive_sample follows the hit ratio, so it needs metrics.
+    AdaptiveSampleMetrics,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::NumCounters(n) => write!(f, "numb_counters must be positive, got {}", n),
+            ConfigError::MaxCost(n) => write!(f, "max_cost must be positive, got {}", n),
+            ConfigError::BufferItems => write!(f, "buffer_items must not be 0"),
+            ConfigError::HighWater(n) => write!(f, "high_water must be in (0, 1], got {}", n),
+            ConfigError::MaxItemCost(n) => write!(f, "max_item_cost must be positive, got {}", n),
+            ConfigError::MaxEntries => write!(f, "max_entries must not be 0"),
+            ConfigError::ResetAt(n) => write!(f, "tiny_lfu.reset_at must be positive, got {}", n),
+            ConfigError::DoorkeeperFp(n) => write!(f, "tiny_lfu.doorkeeper_fp must be in (0, 1), got {}", n),
+            ConfigError::SampleSize => write!(f, "tiny_lfu.sample_size must not be 0"),
+            ConfigError::AdaptiveSample => write!(f, "tiny_lfu.adaptive_sample needs 0 < min <= max and 0 <= low_hit_ratio <= high_hit_ratio <= 1"),
+            ConfigError::AdaptiveSampleMetrics => write!(f, "tiny_lfu.adaptive_sample needs metrics"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// CacheError is returned by the methods that tell failures apart from misses.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum CacheError {
+    /// Uninitialized means the store, the policy or the get buffer of the cache
+    /// isn't there.
+    Uninitialized,
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheError::Uninitialized => write!(f, "cache is not initialized"),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum PutResult<'a, T> {
+    Inserted {
+        new: &'a T,
+    },
+    Replaced {
+        old: &'a T,
+        new: &'a T,
+    },
+    Exists {
+        current: &'a T,
+        not_inserted: Box<Linked<T>>,
+    },
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{BuildHasher, BuildHasherDefault, Hasher};
+    use std::sync::{Arc, Barrier};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use hashbrown::HashSet;
+    use rayon;
+    use rayon::prelude::*;
+
+    use seize::{Collector, Guard};
+
+    use crate::bloom::haskey::key_to_hash;
+    use crate::cache::{AdaptiveSample, Cache, CacheError, Config, ConfigError, ConflictPolicy, DO_NOT_USE, Event, RejectReason, GetResult, HIT, Item, KEEP_GETS, KEY_ADD, Metrics, MetricsMode, MISS, NUM_SHARDS, REJECT_SETS, TinyLfuConfig};
+    use crate::cache::ItemFlag::ItemUpdate;
+    use crate::cmsketch::SnapshotError;
+    use crate::cost::Cost;
+    use crate::key::CacheKey;
+    use crate::reclaim::{Atomic, Shared};
+    use crate::soft::SoftValue;
+    use crate::store::Node;
+    use crate::StdHashBuilder;
+
+    const ITER: u64 = 32 * 1024;
+
+    #[test]
+    fn check() {
+        let _v = 1e7 as i64;
+        let _s = 1 << 30;
+        let _v2 = 1e6 as i64;
+        let _s2 = 1 << 20;
+        println!("")
+    }
+
+    #[test]
+    fn test_cache_key_to_hash() {
+        let _key_to_hash_count = 0;
+        let cache = Cache::new();
+
+        let guard = cache.guard();
+        cache.set(1, 2, 1, &guard);
+        cache.set(2, 2, 1, &guard);
+        println!("{:?}", cache.get(&1, &guard));
+        println!("{:?}", cache.get(&2, &guard));
+    }
+
+    #[test]
+    fn test_cache_insert_thread() {
+        let map = Cache::<u64, u64>::new();
+        // let map1 = Arc::clone(&map);
+        let mut hashet = HashSet::new();
+
+        let guard = map.guard();
+        for i in 0..300_u64 {
+            if i == 256 {
+                println!("")
+            }
+            let (key_hash, _conflict) = map.hash(&i);
+
+            hashet.insert(key_hash);
+        }
+
+        let _size = hashet.len();
+
+        // let map2 = Arc::clone(&map);
+        for i in 0..300 {
+            if let Some(v) = map.get(&i, &guard) {
+                if &i != v {
+                    panic!("i not equel {i}", )
+                }
+                println!("key:{i}, value: {:?}", map.get(&i, &guard));
+            } else {
+                println!("key:{i}, value: None");
+            }
+        };
+    }
+
+
+    #[test]
+    fn test_cache_hash_matches_store() {
+        fn check<K: CacheKey + Clone + Sync + Send + Ord + 'static>(key: K) {
+            let cache = Cache::<K, u64>::new();
+            let guard = cache.guard();
+            assert!(cache.set(key.clone(), 1, 1, &guard));
+            let (key_hash, conflict) = cache.hash(&key);
+            let store = cache.store.load(Ordering::SeqCst, &guard);
+            let store = unsafe { store.deref() };
+            let node = store.data[store.bini(key_hash)].get(&key_hash).unwrap();
+            assert_eq!(node.conflict, conflict);
+            assert_eq!(cache.debug_lookup(&key, &guard), (key_hash, conflict, true));
+            assert_eq!(key_to_hash(&key, &cache.build_hasher, cache.conflict_hash), (key_hash, conflict));
+        }
+
+
+        check(7i8);
+        check(-7i16);
+        check(7i32);
+        check(-7i64);
+        check(7isize);
+        check(7u8);
+        check(7u16);
+        check(7u32);
+        check(7u64);
+        check(7usize);
+        check((3u128 << 64) | 7);
+        check(-7i128);
+        check(String::from("key"));
+        check("key");
+        check(b"key".to_vec());
+
+        // borrowed forms of a key find what the owned one set
+        let cache = Cache::<String, u64>::new();
+        let guard = cache.guard();
+        cache.set(String::from("key"), 1, 1, &guard);
+        assert_eq!(cache.hash("key"), cache.hash(&String::from("key")));
+        assert_eq!(cache.get("key", &guard), Some(&1));
+    }
+
+    #[test]
+    fn test_cache_try_get() {
+        let cache = Cache::new();
+        let guard = cache.guard();
+        assert!(cache.store.load(Ordering::SeqCst, &guard).is_null());
+
+        // the first call sets the cache up, so an empty cache is a plain miss
+        assert_eq!(cache.try_get(&1, &guard), Ok(None));
+        assert!(!cache.store.load(Ordering::SeqCst, &guard).is_null());
+
+        cache.set(1, 10, 1, &guard);
+        assert_eq!(cache.try_get(&1, &guard), Ok(Some(&10)));
+        assert_eq!(cache.try_get(&2, &guard), Ok(None));
+        assert_eq!(CacheError::Uninitialized.to_string(), "cache is not initialized");
+    }
+
+    #[test]
+    fn test_cache_get_with_generation() {
+        let cache = Cache::new();
+        let guard = cache.guard();
+        assert_eq!(cache.get_with_generation(&1, &guard), None);
+        assert!(!cache.set_if_generation(1, 10, 1, 0, &guard));
+
+        cache.set(1, 10, 1, &guard);
+        let (v, gen) = cache.get_with_generation(&1, &guard).unwrap();
+        assert_eq!(*v, 10);
+
+        // a concurrent writer gets in between the read and the write back
+        let writer = cache.clone();
+        thread::spawn(move || {
+            let guard = writer.guard();
+            writer.set(1, 20, 1, &guard);
+        }).join().unwrap();
+
+        assert!(!cache.set_if_generation(1, *v + 1, 1, gen, &guard));
+        let (v, newer) = cache.get_with_generation(&1, &guard).unwrap();
+        assert_eq!(*v, 20);
+        assert!(newer > gen);
+
+        // retrying from a fresh read succeeds
+        assert!(cache.set_if_generation(1, *v + 1, 1, newer, &guard));
+        let (v, latest) = cache.get_with_generation(&1, &guard).unwrap();
+        assert_eq!(*v, 21);
+        assert!(latest > newer);
+        assert!(!cache.set_if_generation(1, 0, 1, newer, &guard));
+
+        // a key set again after a delete doesn't repeat a generation
+        cache.del(&1, &guard);
+        cache.set(1, 30, 1, &guard);
+        assert!(cache.get_with_generation(&1, &guard).unwrap().1 > latest);
+    }
+
+    #[test]
+    fn test_cache_compact_ttl() {
+        let cache = Cache::new();
+        let guard = cache.guard();
+        assert_eq!(cache.compact_ttl(&guard), 0);
+
+        for round in 0..5u64 {
+            for i in 0..200u64 {
+                let key = round * 1000 + i;
+                cache.set_with_ttl(key, i, 1, Duration::from_secs(60 + i), &guard);
+                cache.set_with_ttl(key, i, 1, Duration::from_secs(600 + i), &guard);
+                cache.del(&key, &guard);
+            }
+            cache.clean_up(&guard);
+        }
+        for i in 0..3u64 {
+            cache.set_with_ttl(i, i, 1, Duration::from_secs(60), &guard);
+        }
+
+        assert!(cache.compact_ttl(&guard) <= 3);
+        for i in 0..3u64 {
+            assert_eq!(cache.get(&i, &guard), Some(&i));
+        }
+    }
+
+    #[test]
+    fn test_cache_borrowed_keys() {
+        let cache = Cache::<String, u64>::new();
+        let guard = cache.guard();
+        for (i, k) in ["a", "b", "c"].iter().enumerate() {
+            assert!(cache.set(k.to_string(), i as u64, 1, &guard));
+        }
+
+        assert_eq!(cache.get("a", &guard), Some(&0));
+        assert_eq!(cache.get_cloned("b", &guard), Some(1));
+        assert!(cache.contains_key("c", &guard));
+        assert_eq!(cache.get("d", &guard), None);
+
+        cache.del("a", &guard);
+        assert_eq!(cache.get("a", &guard), None);
+
+        let bytes = Cache::<Vec<u8>, u64>::new();
+        let guard = bytes.guard();
+        bytes.set(b"key".to_vec(), 7, 1, &guard);
+        assert_eq!(bytes.get(&b"key"[..], &guard), Some(&7));
+    }
+
+    #[test]
+    fn test_cache_key_to_hash_thread() {
+        let _key_to_hash_count = 0;
+        let cache = Cache::new();
+
+        let arcc = Arc::new(cache);
+        let c1 = Arc::clone(&arcc);
+        let c2 = Arc::clone(&arcc);
+        let c3 = Arc::clone(&arcc);
+
+        let t1 = thread::spawn(move || {
+            let guard = c1.guard();
+            for i in 0..100000 {
+                c1.set(i, i + 7, 1, &guard);
+            }
+        });
+
+        let t2 = thread::spawn(move || {
+            let guard = c2.guard();
+            for i in 0..100000 {
+                c2.set(i, i + 7, 1, &guard);
+            }
+        });
+
+        let t3 = thread::spawn(move || {
+            let guard = c3.guard();
+            for i in 0..100000 {
+                c3.set(i, i + 7, 1, &guard);
+            }
+        });
+        let c41 = Arc::clone(&arcc);
+        let t4 = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(1000));
+            let guard = c41.guard();
+            for i in 0..300 {
+                println!("{:?}", c41.get(&i, &guard))
+            }
+        });
+
+        t1.join();
+        t2.join();
+        t3.join();
+        t4.join();
+        let c4 = Arc::clone(&arcc);
+        let guard = c4.guard();
+        c4.set(1, 2, 1, &guard);
+        c4.set(2, 2, 1, &guard);
+        println!("{:?}", c4.get(&1, &guard));
+        println!("{:?}", c4.get(&2, &guard));
+    }
+
+
+    #[test]
+    fn test_cache_with_ttl2() {
+        let _key_to_hash_count = 0;
+        let cache = Cache::new();
+
+        (0..ITER).into_par_iter().for_each(|i| {
+            let guard = cache.guard();
+            cache.set(i, i + 7, 1, &guard);
+        });
+    }
+
+    #[test]
+    fn test_cache_with_ttl() {
+        let _key_to_hash_count = 0;
+        let cache = Cache::new();
+
+        let guard = cache.guard();
+
+        let key = 1;
+        let value = 1;
+        let _cost = 1;
+        let _ttl = Duration::from_millis(0);
+
+        loop {
+            if !cache.set_with_ttl(1, 1, 1, Duration::from_millis(0), &guard) {
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            thread::sleep(Duration::from_millis(50));
+            match cache.get(&key, &guard) {
+                None => {
+                    assert!(false)
+                }
+                Some(v) => {
+                    assert_eq!(v, &value)
+                }
+            }
+            break;
+        }
+
+
+        cache.set(1, 2, 2, &guard);
+        let (key_hash, confilictha) = cache.hash(&1);
+        let store = cache.store.load(Ordering::SeqCst, &guard);
+        assert_eq!(store.is_null(), false);
+        let some = unsafe { store.deref() }.get(key_hash, Some(confilictha), &guard);
+        assert_eq!(some.is_some(), true);
+        assert_eq!(some.unwrap(), &2);
+
+
+        thread::sleep(Duration::from_millis(10));
+
+        for _i in 0..1000 {
+            let (key_hash, conflict) = cache.hash(&1);
+            cache.process_items(Node::new(0, conflict, Atomic::null(), None), Item {
+                flag: ItemUpdate,
+                key: key_hash,
+                conflict: conflict,
+                value: Atomic::from(Shared::boxed(1, &cache.collector)),
+                cost: 1,
+                expiration: Some(Duration::from_millis(200u64)),
+            }, 1, &guard);
+        }
+        thread::sleep(Duration::from_millis(50));
+        let v = cache.set(2, 2, 1, &guard);
+        assert_eq!(v, true);
+
+        /*        assert_eq!(cache.metrics.is_none(), false);
+                assert_eq!(cache.metrics.unwrap().SetsDropped(&guard), 0)*/
+    }
+
+    #[test]
+    fn test_cache_estimate_frequency() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+
+        cache.set(1, 1, 1, &guard);
+        cache.set(2, 2, 1, &guard);
+        for _i in 0..100 {
+            cache.get(&1, &guard);
+        }
+        cache.get(&2, &guard);
+
+        assert!(cache.estimate_frequency(&1, &guard) > cache.estimate_frequency(&2, &guard));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_cache_get_foreign_guard() {
+        let a = Cache::<u64, u64>::new();
+        let b = Cache::<u64, u64>::new();
+
+        let guard = a.guard();
+        b.get(&1, &guard);
+    }
+
+    #[test]
+    fn test_cache_reserve() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        cache.reserve(100_000, &guard);
+        let policy = cache.policy.load(Ordering::SeqCst, &guard);
+        let cost_map = || unsafe { policy.deref() }.evict.key_costs.capacity();
+        let store = cache.store.load(Ordering::SeqCst, &guard);
+        let shards = || unsafe { store.deref() }.data.iter().map(|m| m.capacity()).sum::<usize>();
+
+        let before = cost_map();
+        assert!(before >= 100_000);
+        assert!(shards() >= 100_000);
+
+        for i in 0..100_000u64 {
+            cache.set(i, i, 1, &guard);
+        }
+        assert_eq!(unsafe { policy.deref() }.evict.key_costs.len(), 100_000);
+        assert_eq!(cost_map(), before);
+    }
+
+    #[test]
+    fn test_cache_compact() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+
+        let footprint = |cache: &Cache<u64, u64>| {
+            let store = cache.store.load(Ordering::SeqCst, &guard);
+            let policy = cache.policy.load(Ordering::SeqCst, &guard);
+            let shards: usize = unsafe { store.deref() }.data.iter().map(|m| m.capacity()).sum();
+            shards + unsafe { policy.deref() }.evict.key_costs.capacity()
+        };
+
+        for i in 0..100_000u64 {
+            cache.set(i, i, 1, &guard);
+        }
+        for i in 0..90_000u64 {
+            cache.del(&i, &guard);
+        }
+
+        let before = footprint(&cache);
+        cache.compact(&guard);
+        let after = footprint(&cache);
+        assert!(after * 2 < before, "footprint {} -> {}", before, after);
+
+        for i in 90_000..100_000u64 {
+            assert_eq!(cache.get(&i, &guard), Some(&i));
+        }
+    }
+
+    struct CollidingState;
+
+    struct CollidingHasher;
+
+    impl Hasher for CollidingHasher {
+        fn finish(&self) -> u64 {
+            42
+        }
+
+        fn write(&mut self, _bytes: &[u8]) {}
+    }
+
+    impl BuildHasher for CollidingState {
+        type Hasher = CollidingHasher;
+
+        fn build_hasher(&self) -> CollidingHasher {
+            CollidingHasher
+        }
+    }
+
+    #[test]
+    fn test_cache_conflict_hash() {
+        let cache = Cache::<u128, u64, _>::with_hasher(CollidingState, Config {
+            conflict_hash: Some(|b| seahash::hash(b)),
+            ..Default::default()
+        });
+        let guard = cache.guard();
+
+        assert_eq!(cache.hash(&1u128), (42, seahash::hash(&1u128.to_le_bytes())));
+        assert_eq!(cache.hash(&2u128).0, 42);
+        assert_ne!(cache.hash(&1u128).1, cache.hash(&2u128).1);
+
+        cache.set(1, 10, 1, &guard);
+        cache.set(2, 20, 1, &guard);
+        assert_eq!(cache.get(&1u128, &guard), Some(&10));
+        assert_eq!(cache.get(&2u128, &guard), None);
+
+        let cache = Cache::<&str, u64, _>::with_hasher(CollidingState, Config {
+            conflict_hash: Some(|b| seahash::hash(b)),
+            ..Default::default()
+        });
+        let guard = cache.guard();
+
+        assert_eq!(cache.hash("a"), (42, seahash::hash(b"a")));
+        assert_eq!(cache.hash("b").0, 42);
+        assert_ne!(cache.hash("a").1, cache.hash("b").1);
+
+        cache.set("a", 10, 1, &guard);
+        cache.set("b", 20, 1, &guard);
+        assert_eq!(cache.get("a", &guard), Some(&10));
+        assert_eq!(cache.get("b", &guard), None);
+    }
+
+    #[test]
+    fn test_cache_from_iter() {
+        let cache: Cache<u64, u64> = (0..100).map(|i| (i, i + 7)).collect();
+        let guard = cache.guard();
+        for i in 0..100 {
+            assert_eq!(cache.get(&i, &guard), Some(&(i + 7)));
+        }
+    }
+
+    #[test]
+    fn test_cache_drain_hashed() {
+        let cache: Cache<u64, u64> = (0..100).map(|i| (i, i + 7)).collect();
+
+        let other = cache.clone();
+        let cache = cache.drain_hashed().unwrap_err();
+        {
+            let guard = cache.guard();
+            assert_eq!(cache.get(&1, &guard), Some(&8));
+        }
+        drop(cache);
+
+        let mut want: Vec<(u64, u64)> = (0..100).map(|i| (other.hash(&i).0, i + 7)).collect();
+        want.sort();
+        let mut entries: Vec<(u64, u64)> = other.drain_hashed().unwrap().collect();
+        entries.sort();
+        assert_eq!(entries, want);
+    }
+
+    #[test]
+    fn test_cache_into_iter() {
+        let cache: Cache<u64, u64> = (0..10).map(|i| (i, i + 7)).collect();
+        let mut want: Vec<(u64, u64)> = (0..10).map(|i| (cache.hash(&i).0, i + 7)).collect();
+        want.sort();
+
+        let mut entries: Vec<(u64, u64)> = cache.into_iter().collect();
+        entries.sort();
+        assert_eq!(entries, want);
+    }
+
+    #[test]
+    #[should_panic(expected = "still has clones")]
+    fn test_cache_into_iter_shared() {
+        let cache: Cache<u64, u64> = (0..10).map(|i| (i, i)).collect();
+        let _other = cache.clone();
+        let _ = cache.into_iter();
+    }
+
+    #[test]
+    fn test_cache_get_stale() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+
+        cache.set_with_ttl(1, 10, 1, Duration::from_millis(50), &guard);
+        assert_eq!(cache.get_stale(&1, &guard), GetResult::Fresh(&10));
+        assert_eq!(cache.get_stale(&2, &guard), GetResult::Missing);
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.get(&1, &guard), None);
+        assert_eq!(cache.get_stale(&1, &guard), GetResult::Stale(&10));
+
+        cache.clean_up(&guard);
+        assert_eq!(cache.get_stale(&1, &guard), GetResult::Missing);
+    }
+
+    #[test]
+    fn test_cache_policy_cap() {
+        let cache = Cache::<u64, u64>::with_config(Config {
+            max_cost: 100,
+            ..Default::default()
+        });
+        let guard = cache.guard();
+
+        assert_eq!(cache.policy_cap(&guard), 100);
+        cache.set(1, 1, 10, &guard);
+        assert_eq!(cache.policy_cap(&guard), 90);
+        cache.set(2, 2, 15, &guard);
+        assert_eq!(cache.policy_cap(&guard), 75);
+    }
+
+    #[test]
+    fn test_cache_key_cost() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+
+        cache.set(1, 1, 10, &guard);
+        assert_eq!(cache.key_cost(&1u64, &guard), Some(10));
+        assert_eq!(cache.key_cost(&2u64, &guard), None);
+
+        cache.del(&1u64, &guard);
+        assert_eq!(cache.key_cost(&1u64, &guard), None);
+    }
+
+    #[test]
+    fn test_cache_shard_stats() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        cache.set(1, 1, 1, &guard);
+        assert!(cache.shard_stats(&guard).is_empty());
 
-                                    (self.on_evict.unwrap())(victims[i].key, victims[i].conflict, unsafe { v.deref().deref().deref() }, victims[i].cost)
-                                }
-                                // if !self.metrics.is_null() {
-                                //     unsafe {
-                                //         self.metrics.as_mut().unwrap().add(KEY_EVICT, victims[i].key, 1);
-                                //         self.metrics.as_mut().unwrap().add(COST_EVICT, victims[i].key, victims[i].cost as u64);
-                                //     };
-                                // }
-                            }
-                            None => { continue; }
-                        }
-                    }
-                    break true;
+        let cache = Cache::<u64, u64>::with_config(Config {
+            shard_stats: true,
+            ..Default::default()
+        });
+        let guard = cache.guard();
+        // 50 keys that land in shard 3 and 50 in shard 7
+        let cache = &cache;
+        let in_shard = |shard: usize| (0u64..).filter(move |key| cache.shard_index(key) == shard);
+        for (i, key) in in_shard(3).take(50).chain(in_shard(7).take(50)).enumerate() {
+            cache.set(key, i as u64, 1, &guard);
+            cache.get(&key, &guard);
+        }
+        cache.get(&in_shard(5).next().unwrap(), &guard);
+
+        let stats = cache.shard_stats(&guard);
+        assert_eq!(stats.len(), NUM_SHARDS);
+        // every get counts, sets may take more than one trip to the store
+        assert!(stats[3] >= 100);
+        assert!(stats[7] >= 100);
+        assert_eq!(stats[5], 1);
+        assert_eq!(stats.iter().sum::<u64>(), stats[3] + stats[7] + 1);
+    }
+
+    #[test]
+    fn test_cache_max_item_cost() {
+        let cache = Cache::<u64, u64>::with_config(Config {
+            max_cost: 1000,
+            max_item_cost: Some(10),
+            metrics: true,
+            ..Default::default()
+        });
+        let guard = cache.guard();
+        assert!(!cache.set(1, 1, 11, &guard));
+        assert!(cache.set(2, 2, 10, &guard));
+        assert_eq!(cache.get_cloned(&1u64, &guard), None);
+        assert_eq!(cache.get_cloned(&2u64, &guard), Some(2));
+        assert_eq!(cache.metrics.get().unwrap().get(REJECT_SETS, &guard), 1);
+    }
+
+    #[test]
+    fn test_cache_max_entries() {
+        let cache = Cache::<u64, u64>::with_config(Config {
+            max_cost: 1 << 30,
+            max_entries: Some(1000),
+            admit_all: true,
+            ..Default::default()
+        });
+        let guard = cache.guard();
+        for i in 0..5000u64 {
+            cache.set(i, i, 1, &guard);
+            assert!(cache.len(&guard) <= 1000);
+        }
+        assert_eq!(cache.len(&guard), 1000);
+        let report = cache.consistency_report(&guard);
+        assert_eq!(report.policy_entries, 1000);
+        assert_eq!(report.cost_used, 1000);
+
+        // without admit_all the new keys may be rejected instead, the cap holds either way
+        let cache = Cache::<u64, u64>::with_config(Config {
+            max_entries: Some(100),
+            ..Default::default()
+        });
+        let guard = cache.guard();
+        for i in 0..1000u64 {
+            cache.set(i % 300, i, 1, &guard);
+        }
+        assert!(cache.len(&guard) <= 100);
+    }
+
+    #[test]
+    fn test_cache_try_with_config() {
+        let err = |c: Config<u64, u64>| Cache::try_with_config(c).err();
+        assert_eq!(err(Config { numb_counters: 0, ..Default::default() }), Some(ConfigError::NumCounters(0)));
+        assert_eq!(err(Config { max_cost: -1, ..Default::default() }), Some(ConfigError::MaxCost(-1)));
+        assert_eq!(err(Config { buffer_items: 0, ..Default::default() }), Some(ConfigError::BufferItems));
+        assert_eq!(err(Config { high_water: 1.5, ..Default::default() }), Some(ConfigError::HighWater(1.5)));
+        assert!(matches!(err(Config { high_water: f64::NAN, ..Default::default() }), Some(ConfigError::HighWater(_))));
+        assert_eq!(err(Config { max_item_cost: Some(0), ..Default::default() }), Some(ConfigError::MaxItemCost(0)));
+        assert_eq!(err(Config { max_entries: Some(0), ..Default::default() }), Some(ConfigError::MaxEntries));
+        let tiny_lfu = |tiny_lfu| Config { tiny_lfu, ..Default::default() };
+        assert_eq!(err(tiny_lfu(TinyLfuConfig { reset_at: Some(0), ..Default::default() })), Some(ConfigError::ResetAt(0)));
+        assert_eq!(err(tiny_lfu(TinyLfuConfig { doorkeeper_fp: 1.0, ..Default::default() })), Some(ConfigError::DoorkeeperFp(1.0)));
+        assert_eq!(err(tiny_lfu(TinyLfuConfig { sample_size: 0, ..Default::default() })), Some(ConfigError::SampleSize));
+        assert_eq!(ConfigError::BufferItems.to_string(), "buffer_items must not be 0");
+
+        let cache = Cache::<u64, u64>::try_from(Config { max_cost: 10, ..Default::default() }).unwrap();
+        let guard = cache.guard();
+        assert!(cache.set_and_get(1, 1, 1, &guard).is_some());
+    }
+
+    #[test]
+    fn test_cache_warm_start() {
+        type Fixed = BuildHasherDefault<DefaultHasher>;
+        let config = || Config::<u64, u64> { max_cost: 1, ..Default::default() };
+        let hot = Cache::<u64, u64, Fixed>::with_hasher(Fixed::default(), config());
+        let guard = hot.guard();
+        hot.set(1, 1, 1, &guard);
+        let policy = hot.policy.load(Ordering::SeqCst, &guard);
+        for _ in 0..10 {
+            unsafe { policy.as_ptr().as_mut().unwrap() }.push(vec![hot.hash(&1u64).0; 64], &guard);
+        }
+        let snapshot = hot.frequency_snapshot(&guard).unwrap();
+
+        // a warm cache keeps key 1 over a newcomer
+        let cache = Cache::<u64, u64, Fixed>::warm_start(snapshot.clone(), vec![(1, 1, 1)], config()).unwrap();
+        let guard = cache.guard();
+        assert!(cache.estimate_frequency(&1u64, &guard) > 0);
+        assert!(cache.set_and_get(2, 2, 1, &guard).is_none());
+        assert_eq!(cache.get_cloned(&1u64, &guard), Some(1));
+
+        // a cold one lets the newcomer in
+        let cache = Cache::<u64, u64>::with_config(config());
+        let guard = cache.guard();
+        cache.set(1, 1, 1, &guard);
+        assert!(cache.set_and_get(2, 2, 1, &guard).is_some());
+        assert_eq!(cache.get_cloned(&1u64, &guard), None);
+
+        // a snapshot that would index out of its rows is refused up front
+        let mut json = serde_json::to_value(&snapshot).unwrap();
+        json["rows"][1] = serde_json::json!([]);
+        let bad = serde_json::from_value(json).unwrap();
+        assert!(matches!(Cache::<u64, u64, Fixed>::warm_start(bad, vec![], config()), Err(SnapshotError::RowLen { row: 1, len: 0, .. })));
+    }
+
+    #[test]
+    fn test_cache_with_entries() {
+        let cache = Arc::new(Cache::<u64, u64>::new());
+        let guard = cache.guard();
+        cache.set(1, 1, 1, &guard);
+        cache.set(2, 2, 1, &guard);
+        thread::sleep(Duration::from_millis(10));
+        drop(guard);
+
+        let swapper = {
+            let cache = cache.clone();
+            thread::spawn(move || {
+                let guard = cache.guard();
+                for _ in 0..1000 {
+                    cache.with_entries(&[1, 2], |e| {
+                        let a = *e.get(&1u64).unwrap();
+                        let b = *e.get(&2u64).unwrap();
+                        assert!(e.set(&1u64, b));
+                        assert!(e.set(&2u64, a));
+                    }, &guard);
                 }
-                ItemDelete => {
-                    unsafe {
-                        let policy = policy.as_ptr();
-                        policy.as_mut().unwrap().del(&item.key, guard)
-                    }
-                    dstore.del(&item.key, &item.conflict, guard);
+            })
+        };
+        let readers: Vec<_> = (0..4).map(|_| {
+            let cache = cache.clone();
+            thread::spawn(move || {
+                let guard = cache.guard();
+                for _ in 0..1000 {
+                    let (a, b) = cache.with_entries(&[1, 2], |e| {
+                        (e.get(&1u64).copied(), e.get(&2u64).copied())
+                    }, &guard);
+                    // never both the same value halfway through a swap
+                    assert!(matches!((a, b), (Some(1), Some(2)) | (Some(2), Some(1))));
                 }
-                ItemUpdate => {
-                    unsafe {
-                        let policy = policy.as_ptr();
-                        policy.as_mut().unwrap().update(item.key, item.cost, guard);
+            })
+        }).collect();
+        swapper.join().unwrap();
+        for r in readers {
+            r.join().unwrap();
+        }
+
+        let guard = cache.guard();
+        assert_eq!(cache.get_cloned(&1u64, &guard), Some(1));
+        assert_eq!(cache.get_cloned(&2u64, &guard), Some(2));
+        // keys that weren't asked for are out of reach
+        cache.with_entries(&[1], |e| {
+            assert_eq!(e.get(&2u64), None);
+            assert!(!e.set(&2u64, 5));
+            assert!(!e.set(&3u64, 5));
+        }, &guard);
+    }
+
+    static EVICTED: parking_lot::Mutex<Vec<(u64, u64)>> = parking_lot::Mutex::new(Vec::new());
+
+    #[test]
+    fn test_cache_set_with_victims() {
+        let mut cache = Cache::<u64, u64>::with_config(Config {
+            max_cost: 3,
+            ..Default::default()
+        });
+        cache.on_evict = Some(|key, _conflict, value, _cost| EVICTED.lock().push((key, *value)));
+        let guard = cache.guard();
+        for i in 1..=3 {
+            assert_eq!(cache.set_with_victims(i, i * 10, 1, &guard), (true, vec![]));
+        }
+
+        // make 10 hot enough to push one of the others out
+        let policy = cache.policy.load(Ordering::SeqCst, &guard);
+        unsafe { policy.as_ptr().as_mut().unwrap() }.push(vec![cache.hash(&10u64).0; 64], &guard);
+        let (added, victims) = cache.set_with_victims(10, 100, 1, &guard);
+        assert!(added);
+        assert_eq!(victims.len(), 1);
+        let victim = victims[0].1 / 10;
+        assert_eq!(victims[0].0, cache.hash(&victim).0);
+        assert_eq!(*EVICTED.lock(), victims);
+        assert_eq!(cache.get_cloned(&victim, &guard), None);
+    }
+
+    #[test]
+    fn test_cache_zero_cost() {
+        let cache = Cache::<u64, u64>::with_config(Config {
+            max_cost: 10,
+            ..Default::default()
+        });
+        let guard = cache.guard();
+        for i in 0..100 {
+            cache.set(i, i, 0, &guard);
+        }
+        assert_eq!(cache.len(&guard), 10);
+        assert_eq!(cache.policy_cap(&guard), 0);
+        let key = (0..100u64).find(|key| cache.contains_key(key, &guard)).unwrap();
+        assert_eq!(cache.key_cost(&key, &guard), Some(1));
+    }
+
+    #[test]
+    fn test_cache_refresh() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        assert!(!cache.refresh(&1, |v| (*v + 1, 1), &guard));
+        assert_eq!(cache.get(&1, &guard), None);
+
+        cache.set(1, 0, 1, &guard);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    let guard = cache.guard();
+                    for _ in 0..1000 {
+                        assert!(cache.refresh(&1, |v| (*v + 1, 2), &guard));
                     }
-                    // unsafe { policy.deref() }.update(item2.key, item2.cost, guard);
+                });
+            }
+        });
+        assert_eq!(cache.get(&1, &guard), Some(&8000));
+        assert_eq!(cache.key_cost(&1, &guard), Some(2));
+
+        // the TTL stays what it was
+        cache.set_with_ttl(2, 0, 1, Duration::from_millis(50), &guard);
+        assert!(cache.refresh(&2, |v| (*v + 1, 1), &guard));
+        assert_eq!(cache.get(&2, &guard), Some(&1));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.get(&2, &guard), None);
+        assert!(!cache.refresh(&2, |v| (*v + 1, 1), &guard));
+    }
+
+    #[test]
+    fn test_cache_frequency_histogram() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        assert!(cache.frequency_histogram(&guard).is_empty());
+
+        for i in 0..20u64 {
+            cache.set(i, i, 1, &guard);
+        }
+        for _ in 0..10 {
+            for hot in [3u64, 7, 11] {
+                cache.get(&hot, &guard);
+            }
+        }
+        cache.get(&5, &guard);
+        assert!(cache.wait_until_idle(Duration::from_secs(1), &guard));
+
+        let histogram = cache.frequency_histogram(&guard);
+        assert_eq!(histogram.len(), 20);
+        assert!(histogram.windows(2).all(|w| w[0].1 >= w[1].1));
+        let mut top: Vec<_> = histogram[..3].iter().map(|(key, _)| *key).collect();
+        top.sort_unstable();
+        let mut hot: Vec<_> = [3u64, 7, 11].iter().map(|key| cache.hash(key).0).collect();
+        hot.sort_unstable();
+        assert_eq!(top, hot);
+        let first = [3u64, 7, 11].into_iter().find(|key| cache.hash(key).0 == histogram[0].0).unwrap();
+        assert_eq!(histogram[0].1, cache.estimate_frequency(&first, &guard));
+    }
+
+    #[test]
+    fn test_cache_cost_histogram() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        assert_eq!(cache.cost_histogram(&[5, 50], &guard), vec![0, 0, 0]);
+
+        cache.set(1, 1, 1, &guard);
+        cache.set(2, 2, 10, &guard);
+        cache.set(3, 3, 100, &guard);
+        cache.set(4, 4, 5, &guard);
+        assert_eq!(cache.cost_histogram(&[5, 50], &guard), vec![2, 1, 1]);
+        assert_eq!(cache.cost_histogram(&[1, 10, 100], &guard), vec![1, 2, 1, 0]);
+        assert_eq!(cache.cost_histogram(&[], &guard), vec![4]);
+    }
+
+    #[test]
+    fn test_cache_get_or_default() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        assert_eq!(cache.get_or_default(1, &guard), &0);
+        assert_eq!(cache.get_cloned(&1u64, &guard), Some(0));
+        assert_eq!(cache.key_cost(&1u64, &guard), Some(1));
+
+        cache.set(1, 5, 1, &guard);
+        assert_eq!(cache.get_or_default(1, &guard), &5);
+
+        let first = cache.get_or_default(2, &guard) as *const u64;
+        assert_eq!(cache.get_or_default(2, &guard) as *const u64, first);
+    }
+
+    #[test]
+    fn test_cache_get_consistent() {
+        let cache = Cache::<u64, u64>::with_config(Config { max_cost: 1 << 20, ..Default::default() });
+        let guard = cache.guard();
+        for i in 0..10_000 {
+            assert!(cache.set(i % 100, i, 1, &guard));
+            assert_eq!(cache.get_consistent(&(i % 100), &guard), Some(&i));
+        }
+    }
+
+    #[test]
+    fn test_cache_observer() {
+        let events = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let log = events.clone();
+        let cache = Cache::<u64, u64>::with_config(Config { max_cost: 1, admit_all: true, ..Default::default() })
+            .with_observer(Box::new(move |e| log.lock().push(e)));
+        let guard = cache.guard();
+        let key = |k: u64| cache.hash(&k).0;
+
+        cache.set(1, 1, 1, &guard);
+        cache.set(1, 2, 1, &guard);
+        cache.set(2, 2, 1, &guard);
+        cache.set(3, 3, 5, &guard);
+        cache.del(&2, &guard);
+        cache.del(&2, &guard);
+
+        assert_eq!(*events.lock(), vec![
+            Event::Insert { key: key(1), cost: 1 },
+            Event::Update { key: key(1), cost: 1 },
+            Event::Insert { key: key(2), cost: 1 },
+            Event::Evict { key: key(1), cost: 1 },
+            Event::Reject { key: key(3), cost: 5, reason: RejectReason::Admission },
+            Event::Delete { key: key(2) },
+        ]);
+        assert_eq!(serde_json::to_string(&Event::Delete { key: 7 }).unwrap(), r#"{"Delete":{"key":7}}"#);
+    }
+
+    #[test]
+    fn test_cache_wait_until_idle() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        for key in 1..=20u64 {
+            cache.set(key, key, 1, &guard);
+        }
+        // 15 hints in all, too few to fill a stripe and drain on their own
+        for key in 1..=5u64 {
+            for _ in 0..key {
+                cache.get(&key, &guard);
+            }
+        }
+        assert_eq!(cache.estimate_frequency(&5u64, &guard), 0);
+
+        assert!(cache.wait_until_idle(Duration::from_secs(1), &guard));
+        let buf = cache.get_buf.load(Ordering::SeqCst, &guard);
+        assert_eq!(unsafe { buf.deref() }.pending(&guard), 0);
+        for key in 1..=5u64 {
+            assert_eq!(cache.estimate_frequency(&key, &guard), key as i64);
+        }
+    }
+
+    #[test]
+    fn test_cache_consistency_report() {
+        let cache = Cache::<u64, u64>::with_config(Config { max_cost: 200, admit_all: true, ..Default::default() });
+        let guard = cache.guard();
+        for i in 0..300u64 {
+            match i % 6 {
+                0 | 1 => { cache.set(i, i, 1 + (i % 5) as i64, &guard); }
+                2 => { cache.set(i - 2, i, 3, &guard); }
+                3 => cache.del(&(i - 1), &guard),
+                4 => { cache.set_with_ttl(i, i, 2, Duration::from_millis(20), &guard); }
+                _ => { cache.remove_if(&(i - 5), |v| v % 2 == 0, &guard); }
+            }
+        }
+        thread::sleep(Duration::from_millis(40));
+        cache.clean_up(&guard);
+
+        let report = cache.consistency_report(&guard);
+        assert!(report.store_entries > 0);
+        assert_eq!(report.store_entries, report.policy_entries, "{:?}", report);
+        assert_eq!(report.cost_used, report.cost_sum_from_store, "{:?}", report);
+        assert!(report.cost_used <= 200);
+        assert_eq!(report.store_entries, cache.len(&guard));
+    }
+
+    #[test]
+    fn test_cache_deep_copy() {
+        let cache = Cache::<u64, String>::with_config(Config { metrics: true, ..Default::default() });
+        let guard = cache.guard();
+        for key in 0..10 {
+            cache.set(key, key.to_string(), 1, &guard);
+        }
+        for _ in 0..3 {
+            cache.get(&1, &guard);
+        }
+        cache.wait_until_idle(Duration::from_secs(1), &guard);
+
+        let copy = cache.deep_copy(&guard);
+        assert_eq!(copy.len(&guard), 10);
+        assert_eq!(copy.get_cloned(&3, &guard), Some("3".to_string()));
+        assert_eq!(copy.estimate_frequency(&1u64, &guard), 3);
+        assert_eq!(copy.consistency_report(&guard), cache.consistency_report(&guard));
+
+        copy.set(3, "three".to_string(), 1, &guard);
+        copy.del(&4, &guard);
+        cache.set(10, "10".to_string(), 1, &guard);
+        assert_eq!(cache.get_cloned(&3, &guard), Some("3".to_string()));
+        assert_eq!(cache.get_cloned(&4, &guard), Some("4".to_string()));
+        assert_eq!(copy.get_cloned(&3, &guard), Some("three".to_string()));
+        assert_eq!(copy.get_cloned(&10, &guard), None);
+        assert_eq!((cache.len(&guard), copy.len(&guard)), (11, 9));
+
+        let before = copy.metrics.get().unwrap().get(KEY_ADD, &guard);
+        cache.set(11, "11".to_string(), 1, &guard);
+        assert_eq!(copy.metrics.get().unwrap().get(KEY_ADD, &guard), before);
+
+        drop(guard);
+        drop(cache);
+        let guard = copy.guard();
+        assert_eq!(copy.get_cloned(&5, &guard), Some("5".to_string()));
+    }
+
+    #[test]
+    fn test_cache_clone_shares() {
+        let cache = Cache::<u64, u64>::new();
+        let handle = cache.clone();
+        let guard = cache.guard();
+        cache.set(1, 1, 1, &guard);
+        assert_eq!(handle.get_cloned(&1, &guard), Some(1));
+        handle.del(&1, &guard);
+        handle.set(2, 2, 1, &guard);
+        assert_eq!(cache.get_cloned(&1, &guard), None);
+        assert_eq!(cache.get_cloned(&2, &guard), Some(2));
+        assert_eq!(cache.policy_cap(&guard), handle.policy_cap(&guard));
+    }
+
+    #[test]
+    fn test_cache_adaptive_sample() {
+        let adaptive = AdaptiveSample { min: 3, max: 12, ..Default::default() };
+        let cache = Cache::<u64, u64>::with_config(Config {
+            metrics: true,
+            tiny_lfu: TinyLfuConfig { adaptive_sample: Some(adaptive), ..Default::default() },
+            ..Default::default()
+        });
+        let guard = cache.guard();
+        let sample_size = || unsafe { cache.policy.load(Ordering::SeqCst, &guard).deref() }.evict.sample_size;
+        assert_eq!(sample_size(), 5);
+        for key in 0..10u64 {
+            cache.set(key, key, 1, &guard);
+        }
+
+        // every get misses: the sample grows a key per batch, up to max
+        for key in 0..64 * 4 {
+            cache.get(&(1000 + key), &guard);
+        }
+        assert!(sample_size() > 5, "{}", sample_size());
+        for key in 0..64 * 10 {
+            cache.get(&(1000 + key), &guard);
+        }
+        assert_eq!(sample_size(), 12);
+
+        // every get hits: it shrinks back down to min
+        for i in 0..64 * 12 {
+            cache.get(&(i % 10), &guard);
+        }
+        assert_eq!(sample_size(), 3);
+
+        let bad = TinyLfuConfig { adaptive_sample: Some(AdaptiveSample { min: 8, max: 4, ..Default::default() }), ..Default::default() };
+        assert_eq!(bad.validate(), Err(ConfigError::AdaptiveSample));
+        let bad = Config::<u64, u64> { tiny_lfu: TinyLfuConfig { adaptive_sample: Some(adaptive), ..Default::default() }, ..Default::default() };
+        assert_eq!(bad.validate(), Err(ConfigError::AdaptiveSampleMetrics));
+    }
+
+    #[test]
+    fn test_cache_evictions() {
+        let cache = Cache::<u64, u64>::with_config(Config { max_cost: 100, admit_all: true, metrics: true, ..Default::default() });
+        let guard = cache.guard();
+        for i in 0..10 {
+            cache.set(i, i, 10, &guard);
+        }
+        assert_eq!(cache.evictions(&guard), Some((0, 0)));
+
+        for i in 10..15 {
+            cache.set(i, i, 10, &guard);
+        }
+        assert_eq!(cache.evictions(&guard), Some((5, 50)));
+
+        // one item worth three
+        cache.set(15, 15, 30, &guard);
+        assert_eq!(cache.evictions(&guard), Some((8, 80)));
+        assert_eq!(cache.len(&guard), 8);
+
+        let cache = Cache::<u64, u64>::new();
+        assert_eq!(cache.evictions(&cache.guard()), None);
+    }
+
+    #[test]
+    fn test_cache_get_or_insert_with() {
+        let cache = Cache::<u64, u64>::with_config(Config { max_cost: 3, ..Default::default() });
+        let guard = cache.guard();
+        for key in 1..=3 {
+            cache.set(key, key, 1, &guard);
+        }
+        let policy = cache.policy.load(Ordering::SeqCst, &guard);
+        let hot: Vec<u64> = (1..=3).flat_map(|k| vec![cache.hash(&k).0; 20]).collect();
+        unsafe { policy.as_ptr().as_mut().unwrap() }.push(hot, &guard);
+
+        // hits don't run f
+        assert_eq!(cache.get_or_insert_with(2, || unreachable!(), &guard), Ok(&2));
+
+        // a cold value loses to the hot keys but is still handed back
+        assert_eq!(cache.get_or_insert_with(4, || (40, 1), &guard), Err(40));
+        assert_eq!(cache.get_cloned(&4, &guard), None);
+        for key in 1..=3 {
+            assert_eq!(cache.get_cloned(&key, &guard), Some(key));
+        }
+
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        assert_eq!(cache.get_or_insert_with(4, || (40, 1), &guard), Ok(&40));
+        assert_eq!(cache.get_cloned(&4, &guard), Some(40));
+    }
+
+    #[test]
+    fn test_cache_admit_all() {
+        let config = |admit_all| Config::<u64, u64> { max_cost: 1, admit_all, ..Default::default() };
+        let hot = |cache: &Cache<u64, u64>, guard: &Guard| {
+            cache.set(1, 1, 1, guard);
+            let policy = cache.policy.load(Ordering::SeqCst, guard);
+            unsafe { policy.as_ptr().as_mut().unwrap() }.push(vec![cache.hash(&1u64).0; 64], guard);
+        };
+
+        // a cold key loses to the hot one by default
+        let cache = Cache::with_config(config(false));
+        let guard = cache.guard();
+        hot(&cache, &guard);
+        assert!(cache.set_and_get(2, 2, 1, &guard).is_none());
+        assert_eq!(cache.get_cloned(&1u64, &guard), Some(1));
+
+        let cache = Cache::with_config(config(true));
+        let guard = cache.guard();
+        hot(&cache, &guard);
+        assert!(cache.set_and_get(2, 2, 1, &guard).is_some());
+        assert_eq!(cache.get_cloned(&1u64, &guard), None);
+        assert_eq!(cache.policy_cap(&guard), 0);
+    }
+
+    #[test]
+    fn test_cache_can_evict() {
+        let cache = Cache::with_config(Config::<u64, u64> {
+            max_cost: 4,
+            admit_all: true,
+            can_evict: Some(|_key, value| *value != 1),
+            ..Default::default()
+        });
+        let guard = cache.guard();
+        for key in 1..100 {
+            cache.set(key, key, 1, &guard);
+            assert_eq!(cache.get_cloned(&1u64, &guard), Some(1));
+        }
+        assert_eq!(cache.policy_cap(&guard), 0);
+
+        // with everything pinned there is nothing to make room with
+        let cache = Cache::with_config(Config::<u64, u64> {
+            max_cost: 2,
+            admit_all: true,
+            can_evict: Some(|_key, _value| false),
+            ..Default::default()
+        });
+        let guard = cache.guard();
+        cache.set(1, 1, 1, &guard);
+        cache.set(2, 2, 1, &guard);
+        assert!(cache.set_and_get(3, 3, 1, &guard).is_none());
+        assert_eq!(cache.get_cloned(&1u64, &guard), Some(1));
+        assert_eq!(cache.get_cloned(&2u64, &guard), Some(2));
+    }
+
+    #[test]
+    fn test_cache_prehashed() {
+        let cache = Cache::<String, u64>::new();
+        let guard = cache.guard();
+        for i in 0..50u64 {
+            cache.set(format!("key-{}", i), i, 1, &guard);
+        }
+        for i in 50..100u64 {
+            let (key_hash, conflict) = cache.hash(format!("key-{}", i).as_str());
+            assert!(cache.set_prehashed(key_hash, conflict, i, 1, &guard));
+        }
+        for i in 0..100u64 {
+            let key = format!("key-{}", i);
+            let (key_hash, conflict) = cache.hash(&key);
+            assert_eq!(cache.get_prehashed(key_hash, conflict, &guard), Some(&i));
+            assert_eq!(cache.get(&key, &guard), Some(&i));
+        }
+
+        // a key hash stored under another conflict is a different key
+        let (key_hash, conflict) = cache.hash("key-1");
+        assert_eq!(cache.get_prehashed(key_hash, conflict ^ 1, &guard), None);
+        let (key_hash, conflict) = cache.hash("key-100");
+        assert_eq!(cache.get_prehashed(key_hash, conflict, &guard), None);
+    }
+
+    #[test]
+    fn test_cache_recent_hit_ratio() {
+        let cache = Cache::<u64, u64>::with_config(Config { metrics: true, hit_window: 100, ..Default::default() });
+        let guard = cache.guard();
+        assert_eq!(cache.recent_hit_ratio(&guard), 0.0);
+
+        cache.set(1, 1, 1, &guard);
+        for _ in 0..900 {
+            assert_eq!(cache.get(&1, &guard), Some(&1));
+        }
+        assert_eq!(cache.recent_hit_ratio(&guard), 1.0);
+
+        // the misses push the hits out of the window, not out of the metrics
+        for i in 0..90 {
+            assert_eq!(cache.get(&(i + 2), &guard), None);
+        }
+        assert!((cache.recent_hit_ratio(&guard) - 0.1).abs() < 1e-9);
+        for i in 0..10 {
+            cache.get(&(i + 2), &guard);
+        }
+        assert_eq!(cache.recent_hit_ratio(&guard), 0.0);
+        let metrics = cache.metrics.get().unwrap();
+        let (hits, misses) = (metrics.get(HIT, &guard), metrics.get(MISS, &guard));
+        assert_eq!((hits, misses), (900, 100));
+        assert!(hits as f64 / (hits + misses) as f64 > 0.8);
+
+        // with the window off there is nothing to report
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        cache.set(1, 1, 1, &guard);
+        cache.get(&1, &guard);
+        assert_eq!(cache.recent_hit_ratio(&guard), 0.0);
+    }
+
+    #[test]
+    fn test_cache_recompute_costs() {
+        let cache = Cache::<u64, Vec<u8>>::with_config(Config {
+            max_cost: 100,
+            metrics: true,
+            ..Default::default()
+        });
+        let guard = cache.guard();
+        for i in 0..9u64 {
+            assert!(cache.set(i, vec![0; 10], 10, &guard));
+        }
+        assert_eq!(cache.cost_used(&guard), 90);
+
+        // nothing changed size, nothing goes
+        cache.recompute_costs(|v| v.len() as i64, &guard);
+        assert_eq!(cache.len(&guard), 9);
+        assert_eq!(cache.cost_used(&guard), 90);
+
+        // the values grew elsewhere: each now weighs 25
+        cache.recompute_costs(|v| v.len() as i64 * 5 / 2, &guard);
+        assert_eq!(cache.len(&guard), 4);
+        assert_eq!(cache.cost_used(&guard), 100);
+        assert_eq!(cache.evictions(&guard), Some((5, 125)));
+        let report = cache.consistency_report(&guard);
+        assert_eq!(report.cost_used, report.cost_sum_from_store);
+        for key in (0..9u64).filter(|key| cache.contains_key(key, &guard)) {
+            assert_eq!(cache.key_cost(&key, &guard), Some(25));
+        }
+    }
+
+    #[test]
+    fn test_cache_pin() {
+        let cache = Cache::with_config(Config::<u64, u64> {
+            max_cost: 4,
+            admit_all: true,
+            ..Default::default()
+        });
+        let guard = cache.guard();
+        cache.pin(&1u64, &guard);
+        cache.set(1, 1, 1, &guard);
+        for key in 2..100 {
+            cache.set(key, key, 1, &guard);
+            assert!(cache.contains_key(&1u64, &guard));
+        }
+        assert_eq!(cache.len(&guard), 4);
+
+        // once unpinned the key, never read, is the one to go
+        for key in (2..100u64).filter(|key| cache.contains_key(key, &guard)).collect::<Vec<_>>() {
+            for _ in 0..3 {
+                cache.get(&key, &guard);
+            }
+        }
+        assert!(cache.wait_until_idle(Duration::from_secs(1), &guard));
+        // clones share the pins
+        cache.clone().unpin(&1u64, &guard);
+        cache.set(100, 100, 1, &guard);
+        assert!(!cache.contains_key(&1u64, &guard));
+        assert!(cache.contains_key(&100u64, &guard));
+
+        // the TTL of a pinned key still applies
+        cache.pin(&500u64, &guard);
+        cache.set_with_ttl(500, 500, 1, Duration::from_millis(10), &guard);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get(&500, &guard), None);
+    }
+
+    #[test]
+    fn test_cache_del_races_clean_up() {
+        for _ in 0..20 {
+            let cache = Arc::new(Cache::<u64, u64>::with_config(Config { max_cost: 100, ..Default::default() }));
+            let guard = cache.guard();
+            cache.set(1, 1, 3, &guard);
+            cache.set_with_ttl(2, 2, 5, Duration::from_millis(10), &guard);
+            drop(guard);
+            thread::sleep(Duration::from_millis(30));
+
+            let barrier = Arc::new(Barrier::new(2));
+            let handles: Vec<_> = (0..2).map(|i| {
+                let cache = cache.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let guard = cache.guard();
+                    barrier.wait();
+                    if i == 0 {
+                        cache.del(&2u64, &guard);
+                    } else {
+                        cache.clean_up(&guard);
+                    }
+                })
+            }).collect();
+            for h in handles {
+                h.join().unwrap();
+            }
+
+            let guard = cache.guard();
+            assert_eq!(cache.policy_cap(&guard), 97);
+            assert_eq!(cache.get_cloned(&2u64, &guard), None);
+        }
+    }
+
+    #[test]
+    fn test_cache_set_batch_with_ttl() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        let ttl = |i: u64| match i % 3 {
+            0 => Duration::ZERO,
+            1 => Duration::from_millis(50),
+            _ => Duration::from_secs(60),
+        };
+        let added = cache.set_batch_with_ttl((0..30).map(|i| (i, i, 1, ttl(i))).collect(), &guard);
+        assert_eq!(added, vec![true; 30]);
+
+        assert_eq!(cache.expiring_within(Duration::from_secs(1), &guard).len(), 10);
+        assert_eq!(cache.expiring_within(Duration::from_secs(120), &guard).len(), 20);
+
+        thread::sleep(Duration::from_millis(100));
+        for i in 0..30 {
+            let expected = if i % 3 == 1 { None } else { Some(i) };
+            assert_eq!(cache.get_cloned(&i, &guard), expected, "key {}", i);
+        }
+        cache.clean_up(&guard);
+        assert_eq!(cache.len(&guard), 20);
+    }
+
+    #[test]
+    fn test_cache_swap_contents() {
+        let keys: Vec<u64> = (0..50).collect();
+        let cache = Arc::new(Cache::<u64, u64>::with_config(Config { max_cost: 1000, ..Default::default() }));
+        let guard = cache.guard();
+        cache.swap_contents(keys.iter().map(|&k| (k, 1, 1)).collect(), &guard);
+        drop(guard);
+
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let readers: Vec<_> = (0..4).map(|_| {
+            let cache = cache.clone();
+            let done = done.clone();
+            let keys = keys.clone();
+            thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    let guard = cache.guard();
+                    let seen: Vec<Option<u64>> = cache.with_entries(&keys, |entries| {
+                        keys.iter().map(|k| entries.get(k).copied()).collect()
+                    }, &guard);
+                    assert!(seen.iter().all(|v| v.is_some() && *v == seen[0]), "mixed snapshot: {:?}", seen);
                 }
+            })
+        }).collect();
+
+        for generation in 2..20u64 {
+            let guard = cache.guard();
+            cache.swap_contents(keys.iter().map(|&k| (k, generation, 1)).collect(), &guard);
+        }
+        done.store(true, Ordering::SeqCst);
+        for r in readers {
+            r.join().unwrap();
+        }
+
+        let guard = cache.guard();
+        assert_eq!(cache.get_cloned(&7, &guard), Some(19));
+        assert_eq!(cache.policy_cap(&guard), 950);
+    }
+
+    #[test]
+    fn test_cache_transaction() {
+        let ops = |i: u64| i % 4;
+        let txn_cache = Cache::<u64, u64>::new();
+        let in_txn: Vec<Option<u64>> = txn_cache.transaction(|txn| {
+            (0..48).map(|i| match ops(i) {
+                0 | 1 => txn.set(i / 4, i, 1).then_some(i),
+                2 => txn.get(&(i / 8)).copied(),
+                _ => {
+                    txn.del(&(i / 6));
+                    txn_cache.get(&(i / 6), txn.guard()).copied()
+                }
+            }).collect()
+        });
+
+        let cache = Cache::<u64, u64>::new();
+        let per_op: Vec<Option<u64>> = (0..48).map(|i| match ops(i) {
+            0 | 1 => cache.set(i / 4, i, 1, &cache.guard()).then_some(i),
+            2 => cache.get_cloned(&(i / 8), &cache.guard()),
+            _ => {
+                cache.del(&(i / 6), &cache.guard());
+                cache.get_cloned(&(i / 6), &cache.guard())
             }
+        }).collect();
+
+        assert_eq!(in_txn, per_op);
+        assert!(in_txn.iter().any(|r| r.is_some()));
+        let guard = txn_cache.guard();
+        assert_eq!(txn_cache.len(&guard), cache.len(&cache.guard()));
+    }
 
+    #[test]
+    fn test_cache_eviction_preview() {
+        let cache = Cache::<u64, u64>::with_config(Config { max_cost: 10, ..Default::default() });
+        let guard = cache.guard();
+        for i in 0..10 {
+            cache.set(i, i, 1, &guard);
+        }
+        assert!(cache.eviction_preview(0, &guard).len() == 1);
+        assert!(cache.eviction_preview(11, &guard).is_empty());
+
+        let mut preview = cache.eviction_preview(3, &guard);
+        assert_eq!(preview.len(), 3);
+        assert_eq!(cache.len(&guard), 10);
+
+        let (added, victims) = cache.set_with_victims(100, 100, 3, &guard);
+        assert!(added);
+        let mut evicted: Vec<u64> = victims.into_iter().map(|(key, _)| key).collect();
+        preview.sort();
+        evicted.sort();
+        assert_eq!(preview, evicted);
+
+        cache.del(&100u64, &guard);
+        assert!(cache.eviction_preview(3, &guard).is_empty());
+    }
 
-            // self.process_items(node, item2, cost, guard);
+    #[test]
+    fn test_cache_costed_config() {
+        struct Blob(usize);
 
-            break true;
+        impl Cost for Blob {
+            fn cost(&self) -> i64 {
+                self.0 as i64 * 2
+            }
         }
+
+        let cache = Cache::<u64, Blob>::with_costed_config(Config { max_cost: 100, ..Default::default() });
+        let guard = cache.guard();
+        cache.set(1, Blob(10), 0, &guard);
+        assert_eq!(cache.policy_cap(&guard), 80);
+        // an explicit cost wins over the trait
+        cache.set(2, Blob(10), 5, &guard);
+        assert_eq!(cache.policy_cap(&guard), 75);
+
+        let cache = Cache::<u64, String>::with_costed_config(Config { max_cost: 100, ..Default::default() });
+        let guard = cache.guard();
+        cache.set(1, "hello".to_string(), 0, &guard);
+        assert_eq!(cache.policy_cap(&guard), 95);
     }
 
+    #[test]
+    fn test_cache_force_key_hash() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
 
-    /// Del deletes the key-value item from the cache if it exists.
-    pub fn del<'g, Q: ?Sized + Hash + 'static>(&'g self, key: &Q, guard: &'g Guard) {
-        let (key_hash, conflict) = self.hash(key);
-        let item = Item {
-            flag: ItemDelete,
-            key: key_hash,
-            conflict: conflict,
-            value: Atomic::null(),
-            cost: 0,
-            expiration: None,
-        };
+        // different key hashes in one shard are told apart
+        cache.force_key_hash(&1u64, 7, 0);
+        cache.force_key_hash(&2u64, 7 + NUM_SHARDS as u64, 0);
+        assert_eq!(cache.shard_index(&1u64), cache.shard_index(&2u64));
+        cache.set(1, 10, 1, &guard);
+        cache.set(2, 20, 1, &guard);
+        assert_eq!(cache.get_cloned(&1u64, &guard), Some(10));
+        assert_eq!(cache.get_cloned(&2u64, &guard), Some(20));
+
+        // the same key hash with another conflict hash doesn't replace the first
+        cache.force_key_hash(&3u64, 42, 1);
+        cache.force_key_hash(&4u64, 42, 2);
+        cache.set(3, 30, 1, &guard);
+        cache.set(4, 40, 1, &guard);
+        assert_eq!(cache.get_cloned(&3u64, &guard), Some(30));
+        assert_eq!(cache.get_cloned(&4u64, &guard), None);
+        cache.del(&4u64, &guard);
+        assert_eq!(cache.get_cloned(&3u64, &guard), Some(30));
+    }
 
-        let node = Node {
-            key: 0,
-            conflict: 0,
-            value: Atomic::null(),
-            expiration: None,
+    #[test]
+    fn test_cache_debug_lookup() {
+        let cache = Cache::<String, u64>::with_config(Config { metrics: true, ..Default::default() });
+        let guard = cache.guard();
+        let (key_hash, conflict) = cache.hash("a");
+        assert_eq!(cache.debug_lookup("a", &guard), (key_hash, conflict, false));
+
+        cache.set("a".to_string(), 1, 1, &guard);
+        assert_eq!(cache.debug_lookup("a", &guard), (key_hash, conflict, true));
+        assert!(!cache.debug_lookup("b", &guard).2);
+
+        // lookups aren't accesses
+        let metrics = cache.metrics.get().unwrap();
+        assert_eq!(metrics.get(HIT, &guard) + metrics.get(MISS, &guard), 0);
+    }
 
+    #[test]
+    fn test_cache_doorkeeper_fp_rate() {
+        let cache = Cache::<u64, u64>::with_config(Config { numb_counters: 1000, ..Default::default() });
+        let guard = cache.guard();
+        assert_eq!(cache.doorkeeper_fp_rate(&guard), 0.0);
+        // only gets are counted, a batch at a time
+        for i in 0..256u64 {
+            cache.get(&i, &guard);
+        }
+        assert!(cache.doorkeeper_fp_rate(&guard) > 0.0);
+    }
+
+    #[test]
+    fn test_cache_flush_on_drop() {
+        let kept = |flush_on_drop| {
+            let cache = Cache::with_config(Config::<u64, u64> { metrics: true, flush_on_drop, ..Default::default() });
+            let guard = cache.guard();
+            cache.set(1, 1, 1, &guard);
+            for _ in 0..3 {
+                cache.get(&1u64, &guard);
+            }
+            drop(guard);
+            let metrics = cache.metrics.clone();
+            drop(cache);
+            let guard = unsafe { Guard::unprotected() };
+            metrics.get().unwrap().get(KEEP_GETS, &guard)
         };
+        assert_eq!(kept(false), 0);
+        assert_eq!(kept(true), 3);
+    }
 
+    #[test]
+    fn test_cache_entry_age() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        assert_eq!(cache.entry_age(&1u64, &guard), None);
 
-        self.process_items(node, item, 0, guard);
-        // self.set_buf.send(item);
+        cache.set(1, 1, 1, &guard);
+        thread::sleep(Duration::from_millis(100));
+        assert!(cache.entry_age(&1u64, &guard).unwrap() >= Duration::from_millis(100));
+
+        // setting it again starts over
+        cache.set(1, 2, 1, &guard);
+        assert!(cache.entry_age(&1u64, &guard).unwrap() < Duration::from_millis(100));
     }
 
+    #[test]
+    fn test_cache_shared_collector() {
+        let collector = Arc::new(Collector::new());
+        let a = Cache::<u64, u64>::with_hasher_and_collector(Default::default(), collector.clone(), Config::default());
+        let b = Cache::<u64, String>::with_hasher_and_collector(Default::default(), collector.clone(), Config::default());
+
+        let guard = collector.enter();
+        a.set(1, 10, 1, &guard);
+        b.set(1, "one".to_string(), 1, &guard);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(a.get(&1u64, &guard), Some(&10));
+        assert_eq!(b.get(&1u64, &guard).map(String::as_str), Some("one"));
 
-    /// Clear empties the hashmap and zeroes all policy counters. Note that this is
-    /// not an atomic operation (but that shouldn't be a problem as it's assumed that
-    /// Set/Get calls won't be occurring until after this).
-    pub fn clear<'g>(&'g self, guard: &'g Guard) {
-        // block until processItems  is returned
-        let store = self.store.load(Ordering::SeqCst, guard);
-        let policy = self.policy.load(Ordering::SeqCst, guard);
+        // a guard of one cache is good for the other
+        let guard = a.guard();
+        assert_eq!(b.get(&1u64, &guard).map(String::as_str), Some("one"));
+    }
 
+    #[test]
+    #[should_panic]
+    fn test_cache_foreign_guard() {
+        let a = Cache::<u64, u64>::new();
+        let b = Cache::<u64, u64>::new();
+        b.get(&1u64, &a.guard());
+    }
 
-        unsafe {
-            if !policy.is_null() {
-                let policy = policy.as_ptr();
-                policy.as_mut().unwrap().clear(guard);
-            }
+    #[test]
+    fn test_cache_enable_metrics() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        assert!(!cache.metrics_enabled());
+
+        cache.enable_metrics(&guard);
+        assert!(cache.metrics_enabled());
+        assert!(!unsafe { cache.policy.load(Ordering::SeqCst, &guard).deref() }.metrics.is_null());
+
+        cache.set(1, 1, 1, &guard);
+        thread::sleep(Duration::from_millis(10));
+        cache.get(&1u64, &guard);
+        cache.get(&2u64, &guard);
+
+        let metrics = cache.metrics.get().unwrap();
+        assert_eq!(metrics.get(HIT, &guard) + metrics.get(MISS, &guard), 2);
+        assert!(metrics.get(KEY_ADD, &guard) > 0);
+    }
+
+    #[test]
+    fn test_cache_std_hasher() {
+        let cache = Cache::<String, u64, _>::with_hasher(StdHashBuilder::default(), Config::default());
+        let guard = cache.guard();
+        for i in 0..100u64 {
+            assert!(cache.set(format!("key-{}", i), i, 1, &guard));
         }
-        if !store.is_null() {
-            unsafe {
-                let p = store.as_ptr();
-                p.as_mut().unwrap().clear(guard);
-            };
+        for i in 0..100u64 {
+            assert_eq!(cache.get(format!("key-{}", i).as_str(), &guard), Some(&i));
+        }
+        assert_eq!(cache.get("key-100", &guard), None);
+        cache.del("key-7", &guard);
+        assert_eq!(cache.get("key-7", &guard), None);
+
+        // a fixed hasher gives the same hashes in every cache, and only its own
+        let fixed = || Cache::<&str, u64, _>::with_hasher(BuildHasherDefault::<DefaultHasher>::default(), Config::default());
+        assert_eq!(fixed().hash("key"), fixed().hash("key"));
+        let mut h = DefaultHasher::new();
+        h.write(b"key");
+        assert_eq!(fixed().hash("key").0, h.finish());
+        let (_, conflict) = fixed().hash("key");
+        assert_eq!(conflict, xxhash_rust::const_xxh3::xxh3_64(b"key"));
+
+        // integer keys go through the hasher too
+        let cache = Cache::<u64, u64, _>::with_hasher(StdHashBuilder::default(), Config::default());
+        let guard = cache.guard();
+        for i in 0..100u64 {
+            assert!(cache.set(i, i, 1, &guard));
+        }
+        for i in 0..100u64 {
+            assert_eq!(cache.get(&i, &guard), Some(&i));
         }
+        let mut h = DefaultHasher::new();
+        h.write(&7u64.to_le_bytes());
+        assert_eq!(Cache::<u64, u64, _>::with_hasher(BuildHasherDefault::<DefaultHasher>::default(), Config::default()).hash(&7u64).0, h.finish());
+    }
 
-        self.clear(guard);
+    #[test]
+    fn test_cache_contains_key() {
+        let cache = Cache::<&str, u64, _>::with_hasher(CollidingState, Config::default());
+        let guard = cache.guard();
+        assert!(!cache.contains_key("a", &guard));
 
+        cache.set("a", 1, 1, &guard);
+        assert_eq!(cache.hash("a").0, cache.hash("b").0);
+        assert!(cache.contains_key("a", &guard));
+        assert!(!cache.contains_key("b", &guard));
 
-        /* let (tx, rx) = crossbeam_channel::unbounded();
-         self.set_buf = tx;
-         self.receiver_buf = rx;*/
+        let cache = Cache::<&str, u64>::new();
+        let guard = cache.guard();
+        cache.set_with_ttl("c", 3, 1, Duration::from_millis(1), &guard);
+        assert!(cache.contains_key("c", &guard));
+        thread::sleep(Duration::from_millis(5));
+        assert!(!cache.contains_key("c", &guard));
+    }
 
-        //TODO fix thead after clear
-        /* thread::spawn( || {
-             let guard = crossbeam::epoch::pin();
-             self.process_items(&guard);
-         });*/
+    #[test]
+    fn test_cache_zero_conflict() {
+        // 1 hashes to a conflict of 0, 2 to 5, and both share the same primary hash
+        let cache = Cache::<u128, u64, _>::with_hasher(CollidingState, Config {
+            conflict_hash: Some(|b| if b[0] == 1 { 0 } else { 5 }),
+            ..Default::default()
+        });
+        let guard = cache.guard();
+        assert_eq!(cache.hash(&1u128), (42, 0));
+        assert_eq!(cache.hash(&2u128), (42, 5));
+
+        cache.set(2, 20, 1, &guard);
+        cache.set(1, 10, 1, &guard);
+        assert_eq!(cache.get(&2u128, &guard), Some(&20));
+        assert_eq!(cache.get(&1u128, &guard), None);
+
+        cache.del(&1u128, &guard);
+        assert_eq!(cache.get(&2u128, &guard), Some(&20));
+
+        // "a" hashes to a conflict of 0, "b" to 5, and both share the same primary hash
+        let cache = Cache::<&str, u64, _>::with_hasher(CollidingState, Config {
+            conflict_hash: Some(|b| if b == b"a" { 0 } else { 5 }),
+            ..Default::default()
+        });
+        let guard = cache.guard();
+        assert_eq!(cache.hash("a"), (42, 0));
+        assert_eq!(cache.hash("b"), (42, 5));
+
+        cache.set("b", 20, 1, &guard);
+        cache.set("a", 10, 1, &guard);
+        assert_eq!(cache.get("b", &guard), Some(&20));
+        assert_eq!(cache.get("a", &guard), None);
+
+        cache.del("a", &guard);
+        assert_eq!(cache.get("b", &guard), Some(&20));
     }
 
-    pub fn process_items<'g>(&'g self, node: Node<V>, mut item: Item<V>, cost: i64, guard: &'g Guard) {
-        let _cost = cost;
-        match item.flag {
-            ItemNew | ItemUpdate => unsafe {
-                if item.cost == 0 && self.cost.is_some() {
-                    item.cost = (self.cost.unwrap())(item.value.load(Ordering::SeqCst, guard).deref());
-                }
-            }
-            _ => {}
-        }
+    #[test]
+    fn test_cache_count_based() {
+        let cache = Cache::<u64, u64>::with_config(Config {
+            max_cost: 100,
+            count_based: true,
+            ..Default::default()
+        });
+        let guard = cache.guard();
 
-        match item.flag {
-            ItemNew => {
-                let mut policy = self.policy.load(Ordering::SeqCst, guard);
-                loop {
-                    if policy.is_null() {
-                        policy = self.init_policy(guard);
-                        continue;
-                    }
-                    let (victims, added) = unsafe {
-                        let p = policy.as_ptr();
-                        let p = p.as_mut().unwrap();
-                        p.add(item.key, item.cost, guard)
-                    };
+        for i in 0..150 {
+            cache.set(i, i, 10, &guard);
+        }
+        assert_eq!(cache.key_cost(&149u64, &guard).unwrap_or(1), 1);
 
-                    let store = self.store.load(Ordering::SeqCst, guard);
-                    if added {
-                        let store = unsafe { store.as_ptr() };
-                        let store = unsafe { store.as_mut().unwrap() };
-                        store.set(node, guard);
-                        break;
-                    }
+        let survivors = (0..150u64).filter(|i| cache.get(i, &guard).is_some()).count();
+        assert!((90..=100).contains(&survivors), "{} survivors", survivors);
+    }
 
-                    for i in 0..victims.len() {
-                        let store = unsafe { store.as_ptr() };
-                        let store = unsafe { store.as_mut().unwrap() };
-                        let delVal = store.del(&victims[i].key, &0, guard);
-                        match delVal {
-                            Some((_c, _v)) => {
-                                // victims[i].value = Some(v.clone());
-                                // victims[i].conflict = c;
+    #[test]
+    fn test_cache_decay_frequencies() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
 
-                                if self.on_evict.is_some() {
-                                    let v = victims[i].value.load(Ordering::SeqCst, guard);
+        cache.set(1, 1, 1, &guard);
+        for _i in 0..128 {
+            cache.get(&1, &guard);
+        }
+        let before = cache.estimate_frequency(&1, &guard);
+        cache.decay_frequencies(&guard);
+        let after = cache.estimate_frequency(&1, &guard);
 
-                                    (self.on_evict.unwrap())(victims[i].key, victims[i].conflict, unsafe { v.deref().deref().deref() }, victims[i].cost)
-                                }
-                                // if !self.metrics.is_null() {
-                                //     unsafe {
-                                //         self.metrics.as_mut().unwrap().add(KEY_EVICT, victims[i].key, 1);
-                                //         self.metrics.as_mut().unwrap().add(COST_EVICT, victims[i].key, victims[i].cost as u64);
-                                //     };
-                                // }
-                            }
-                            None => { continue; }
-                        }
-                    }
-                }
-            }
-            ItemDelete => {
-                let policy = self.policy.load(Ordering::SeqCst, guard);
-                if policy.is_null() {
-                    return;
-                }
+        assert!(after >= before / 2 - 1 && after <= before / 2, "{} -> {}", before, after);
+        assert_eq!(cache.get(&1, &guard), Some(&1));
+        assert_eq!(cache.key_cost(&1u64, &guard), Some(1));
+    }
 
-                let store = self.store.load(Ordering::SeqCst, guard);
-                unsafe {
-                    let p = policy.as_ptr();
-                    let p = p.as_mut().unwrap();
-                    p.del(&item.key, guard)
-                }
+    #[test]
+    fn test_metrics_add_spread() {
+        let collector = Collector::new();
+        let guard = collector.enter();
+        let metrics = Metrics::new(DO_NOT_USE, &collector);
 
-                let store = unsafe { store.as_ptr() };
-                let store = unsafe { store.as_mut().unwrap() };
-                store.del(&item.key, &item.conflict, guard);
-            }
-            ItemFlag::ItemUpdate => {
-                let policy = self.policy.load(Ordering::SeqCst, guard);
-                if policy.is_null() {
-                    return;
-                }
-                unsafe {
-                    let p = policy.as_ptr();
-                    let p = p.as_mut().unwrap();
-                    p.update(item.key, item.cost, guard);
-                }
-            }
+        for i in 0..1000u64 {
+            metrics.add(KEY_ADD, i.wrapping_mul(0x9E37_79B9_7F4A_7C15), 2, &guard);
         }
+        assert_eq!(metrics.get(KEY_ADD, &guard), 2000);
+        assert_eq!(metrics.get(HIT, &guard), 0);
+
+        let row = metrics.all[KEY_ADD].load(Ordering::SeqCst, &guard);
+        let used = unsafe { row.deref() }.iter().filter(|c| c.load(Ordering::Relaxed) > 0).count();
+        assert!(used > 100, "only {} slots used", used);
     }
 
+    #[test]
+    fn test_cache_get_hit_miss_metrics() {
+        let cache = Cache::<u64, u64>::with_config(Config { metrics: true, ..Default::default() });
+        let guard = cache.guard();
+        cache.set(1, 1, 1, &guard);
+        thread::sleep(Duration::from_millis(10));
+
+        // found keys count as hits and missing ones as misses, not the other way round
+        assert_eq!(cache.get(&1, &guard), Some(&1));
+        assert_eq!(cache.get(&1, &guard), Some(&1));
+        assert_eq!(cache.get(&2, &guard), None);
 
-    pub fn clean_up<'g>(&'g self, guard: &'g Guard<'_>) {
-        self.check_guard(guard);
-        let store = self.store.load(Ordering::SeqCst, guard);
-        let policy = self.policy.load(Ordering::SeqCst, guard);
-        if store.is_null() || policy.is_null() {}
-        unsafe { store.as_ptr().as_mut().unwrap() }.clean_up(unsafe { policy.as_ptr().as_mut().unwrap() }, guard)
+        let metrics = cache.metrics.get().unwrap();
+        assert_eq!((metrics.get(HIT, &guard), metrics.get(MISS, &guard)), (2, 1));
     }
-}
 
-type MetricType = usize;
+    #[test]
+    fn test_metrics_add_concurrent() {
+        let collector = Collector::new();
+        let metrics = Arc::new(Metrics::new(DO_NOT_USE, &collector));
+
+        thread::scope(|s| {
+            for t in 0..8u64 {
+                let metrics = Arc::clone(&metrics);
+                let collector = &collector;
+                s.spawn(move || {
+                    let guard = collector.enter();
+                    // few distinct hashes, so the threads keep landing on the same counters
+                    for i in 0..125_000u64 {
+                        metrics.add(HIT, (i + t) % 4, 1, &guard);
+                    }
+                });
+            }
+        });
 
-pub const HIT: MetricType = 0;
-pub const MISS: MetricType = 1;
-// The following 3 keep track of number of keys added, updated and evicted.
-const KEY_ADD: MetricType = 2;
-pub const KEY_UPDATE: MetricType = 3;
-pub const KEY_EVICT: MetricType = 4;
-// The following 2 keep track of cost of keys added and evicted.
-pub const COST_ADD: MetricType = 5;
-pub const COST_EVICT: MetricType = 6;
-// The following keep track of how many sets were dropped or rejected later.
+        let guard = collector.enter();
+        assert_eq!(metrics.get(HIT, &guard), 1_000_000);
+    }
 
-pub const DROP_SETS: MetricType = 7;
-pub const REJECT_SETS: MetricType = 8;
-// The following 2 keep track of how many gets were kept and dropped on the
-// floor.
-pub const DROP_GETS: MetricType = 9;
-pub const KEEP_GETS: MetricType = 10;
-// This should be the final enum. Other enums should be set before this.
-pub const DO_NOT_USE: MetricType = 11;
+    #[test]
+    fn test_metrics_compact() {
+        let metrics = |metrics_mode| {
+            let cache = Cache::<u64, u64>::with_config(Config { metrics: true, metrics_mode, ..Default::default() });
+            let guard = cache.guard();
+            for i in 0..100 {
+                cache.set(i, i, 1, &guard);
+                cache.get(&(i * 2), &guard);
+            }
+            let m = cache.metrics.get().unwrap();
+            let totals: Vec<u64> = (0..DO_NOT_USE).map(|t| m.get(t, &guard)).collect();
+            (totals, m.memory())
+        };
+        let (sharded, sharded_memory) = metrics(MetricsMode::Sharded);
+        let (compact, compact_memory) = metrics(MetricsMode::Compact);
+        assert_eq!(compact, sharded);
+        assert!(compact[KEY_ADD] > 0);
+        assert_eq!(compact_memory, DO_NOT_USE * 8);
+        assert!(compact_memory * 256 <= sharded_memory);
+    }
 
-pub struct Metrics {
-    pub(crate) all: Box<[Atomic<[u64; 256]>]>,
+    #[test]
+    fn test_cache_get_or_insert_single_flight() {
+        // every thread gets its own clone, which shares the flights
+        let cache = Cache::<u64, u64>::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(16));
+
+        let handles: Vec<_> = (0..16).map(|_| {
+            let cache = cache.clone();
+            let calls = calls.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let guard = cache.guard();
+                barrier.wait();
+                let v = cache.get_or_insert_single_flight(1, || {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(50));
+                    (7, 1)
+                }, &guard);
+                assert_eq!(v, Some(&7));
+            })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
 
-}
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(cache.in_flight.lock().is_empty());
+        let guard = cache.guard();
+        assert_eq!(cache.get_or_insert_single_flight(1, || unreachable!(), &guard), Some(&7));
+    }
 
-impl Clone for Metrics {
-    fn clone(&self) -> Self {
-        Self {
-            all: self.all.clone()
+    #[test]
+    fn test_cache_get_or_insert_single_flight_rejected() {
+        let cache = Cache::<u64, u64>::with_config(Config { max_cost: 10, ..Default::default() });
+        let calls = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+
+        let handles: Vec<_> = (0..8).map(|_| {
+            let cache = cache.clone();
+            let calls = calls.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let guard = cache.guard();
+                barrier.wait();
+                // too costly to be admitted, but every caller still gets it
+                let v = cache.get_or_insert_single_flight(1, || {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(50));
+                    (7, 100)
+                }, &guard);
+                assert_eq!(v, Some(&7));
+            })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
         }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(cache.in_flight.lock().is_empty());
+        let guard = cache.guard();
+        assert_eq!(cache.get(&1, &guard), None);
     }
-}
 
-impl Metrics {
-    pub(crate) fn new(n: usize, collector: &Collector) -> Self {
-        let data = vec![Atomic::from(Shared::boxed([0u64; 256], collector)); n];
-        Metrics {
-            all: data.into_boxed_slice(),
+    static PRESSURE: AtomicUsize = AtomicUsize::new(0);
+
+    #[test]
+    fn test_cache_on_pressure() {
+        let cache = Cache::<u64, u64>::with_config(Config {
+            max_cost: 10,
+            on_pressure: Some(|used, max| {
+                assert_eq!((used, max), (9, 10));
+                PRESSURE.fetch_add(1, Ordering::SeqCst);
+            }),
+            high_water: 0.9,
+            ..Default::default()
+        });
+        let guard = cache.guard();
+        for i in 0..8 {
+            cache.set(i, i, 1, &guard);
         }
+        assert_eq!(PRESSURE.load(Ordering::SeqCst), 0);
+
+        cache.set(8, 8, 1, &guard);
+        assert_eq!(PRESSURE.load(Ordering::SeqCst), 1);
+
+        // still above the mark, so it doesn't fire again
+        cache.set(9, 9, 1, &guard);
+        assert_eq!(PRESSURE.load(Ordering::SeqCst), 1);
     }
-    pub(crate) fn get<'g>(&'g self, t: MetricType, guard: &'g Guard) -> u64 {
-        let all = self.all[t].load(Ordering::SeqCst, guard);
-        if all.is_null() {
-            return 0;
+
+    #[test]
+    fn test_cache_update_bigger_cost() {
+        let cache = Cache::<u64, u64>::with_config(Config {
+            max_cost: 10,
+            ..Default::default()
+        });
+        let guard = cache.guard();
+        for i in 0..5 {
+            cache.set(i, i, 1, &guard);
         }
 
-        let data = unsafe { all.as_ptr() };
-        let data = unsafe { data.as_mut().unwrap() };
-        let mut total = 0;
-        for i in 0..data.len() {
-            total += data[i];
-        }
-        total
+        // only 5 left, an add of 9 would need evictions, an update must not be rejected
+        cache.set(1, 100, 9, &guard);
+        assert_eq!(cache.get(&1, &guard), Some(&100));
+        assert_eq!(cache.key_cost(&1, &guard), Some(9));
+        assert_eq!(cache.policy_cap(&guard), -3);
     }
-    pub(crate) fn SetsDropped<'g>(&'g self, guard: &'g Guard) -> u64 {
-        self.get(DROP_SETS, guard)
+
+    #[test]
+    fn test_cache_remove_if() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        assert!(!cache.remove_if(&1, |_| true, &guard));
+
+        cache.set(1, 10, 1, &guard);
+        assert!(!cache.remove_if(&1, |v| *v == 20, &guard));
+        assert_eq!(cache.get(&1, &guard), Some(&10));
+        assert_eq!(cache.key_cost(&1, &guard), Some(1));
+
+        assert!(cache.remove_if(&1, |v| *v == 10, &guard));
+        assert_eq!(cache.get(&1, &guard), None);
+        assert_eq!(cache.key_cost(&1, &guard), None);
     }
-    pub(crate) fn add<'g>(&self, t: MetricType, hash: u64, delta: u64, guard: &'g Guard) {
-        let idx = (hash % 5) * 10;
-        let all = self.all[t].load(Ordering::SeqCst, guard);
-        if all.is_null() {
-            panic!("metric all is null");
-        }
-        let data = unsafe { all.as_ptr() };
-        let data = unsafe { data.as_mut().unwrap() };
 
-        let _ = data[idx as usize].checked_add(delta);
-        // unsafe {all.deref().deref().deref()[idx as usize] = delta};
+    #[test]
+    fn test_cache_get_cloned() {
+        let cache = Cache::<u64, String>::new();
+        let mut results = Vec::new();
+        {
+            let guard = cache.guard();
+            cache.set(1, "one".to_string(), 1, &guard);
+            results.push(cache.get_cloned(&1, &guard));
+            results.push(cache.get_cloned(&2, &guard));
+            cache.del(&1, &guard);
+        }
+        cache.clean_up(&cache.guard());
+        assert_eq!(results, vec![Some("one".to_string()), None]);
     }
 
-    pub fn clear<'g>(&self, guard: &'g Guard) {
-        let _data = vec![Atomic::from(Shared::boxed([0u64; 256], guard.collector().unwrap())); DO_NOT_USE];
-        // self.all.as_mut() = &mut *data.into_boxed_slice();
+    #[test]
+    fn test_cache_keys_values() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        assert!(cache.is_empty(&guard));
+        assert_eq!(cache.keys(&guard).count(), 0);
+
+        for i in 0..100 {
+            cache.set(i, i * 10, 1, &guard);
+        }
+        assert_eq!(cache.len(&guard), 100);
+        assert_eq!(cache.keys(&guard).count(), cache.len(&guard));
+
+        let mut keys: Vec<_> = cache.keys(&guard).collect();
+        keys.sort();
+        let mut want: Vec<_> = (0..100u64).map(|i| cache.hash(&i).0).collect();
+        want.sort();
+        assert_eq!(keys, want);
+        let mut values: Vec<_> = cache.values(&guard).copied().collect();
+        values.sort();
+        assert_eq!(values, (0..100).map(|i| i * 10).collect::<Vec<_>>());
+
+        cache.set_with_ttl(100, 1000, 1, Duration::from_millis(1), &guard);
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(cache.len(&guard), 101);
+        assert_eq!(cache.keys(&guard).count(), 100);
+        assert!(cache.values(&guard).all(|v| *v != 1000));
     }
-}
 
-#[derive(Eq, PartialEq, Debug)]
-pub enum PutResult<'a, T> {
-    Inserted {
-        new: &'a T,
-    },
-    Replaced {
-        old: &'a T,
-        new: &'a T,
-    },
-    Exists {
-        current: &'a T,
-        not_inserted: Box<Linked<T>>,
-    },
-}
+    #[test]
+    fn test_cache_get_sliding() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        assert_eq!(cache.get_sliding(&1, Duration::from_millis(200), &guard), None);
 
+        cache.set_with_ttl(1, 10, 1, Duration::from_millis(200), &guard);
+        for _ in 0..10 {
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(cache.get_sliding(&1, Duration::from_millis(200), &guard), Some(&10));
+        }
 
-#[cfg(test)]
-mod tests {
-    use std::sync::Arc;
-    use std::sync::atomic::Ordering;
-    use std::thread;
-    use std::time::Duration;
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.get(&1, &guard), Some(&10));
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(cache.get(&1, &guard), None);
+        assert_eq!(cache.get_sliding(&1, Duration::from_millis(200), &guard), None);
 
-    use hashbrown::HashSet;
-    use rayon;
-    use rayon::prelude::*;
+        // the new deadline is in the expiration map, so cleanup still finds the entry
+        cache.set(2, 20, 1, &guard);
+        assert_eq!(cache.get_sliding(&2, Duration::from_millis(10), &guard), Some(&20));
+        assert_eq!(cache.expiring_within(Duration::from_secs(1), &guard), vec![cache.hash(&2u64).0]);
+    }
 
-    use crate::bloom::haskey::key_to_hash;
-    use crate::cache::{Cache, Item};
-    use crate::cache::ItemFlag::ItemUpdate;
-    use crate::reclaim::{Atomic, Shared};
-    use crate::store::Node;
+    #[test]
+    fn test_cache_expiring_within() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        assert!(cache.expiring_within(Duration::from_secs(1), &guard).is_empty());
 
-    const ITER: u64 = 32 * 1024;
+        cache.set_with_ttl(1, 1, 1, Duration::from_secs(3), &guard);
+        cache.set_with_ttl(2, 2, 1, Duration::from_millis(500), &guard);
+        cache.set_with_ttl(3, 3, 1, Duration::from_secs(60), &guard);
+        cache.set_with_ttl(4, 4, 1, Duration::from_secs(1), &guard);
+        cache.set(5, 5, 1, &guard);
+        let h = |key: u64| cache.hash(&key).0;
+
+        assert_eq!(cache.expiring_within(Duration::from_secs(2), &guard), vec![h(2), h(4)]);
+        assert_eq!(cache.expiring_within(Duration::from_secs(5), &guard), vec![h(2), h(4), h(1)]);
+        assert_eq!(cache.expiring_within(Duration::from_millis(100), &guard), Vec::<u64>::new());
+
+        cache.del(&4, &guard);
+        assert_eq!(cache.expiring_within(Duration::from_secs(2), &guard), vec![h(2)]);
+    }
 
     #[test]
-    fn check() {
-        let _v = 1e7 as i64;
-        let _s = 1 << 30;
-        let _v2 = 1e6 as i64;
-        let _s2 = 1 << 20;
-        println!("")
+    #[cfg(feature = "rayon")]
+    fn test_cache_par_get_batch() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        for i in 0..1000 {
+            if i % 3 != 0 {
+                cache.set(i, i * 2, 1, &guard);
+            }
+        }
+        let keys: Vec<u64> = (0..1500).rev().collect();
+        let serial: Vec<Option<u64>> = keys.iter().map(|k| cache.get_cloned(k, &guard)).collect();
+        assert_eq!(cache.par_get_batch(&keys), serial);
+        assert_eq!(serial[1499 - 4], Some(8));
+        assert_eq!(serial[1499 - 3], None);
     }
 
     #[test]
-    fn test_cache_key_to_hash() {
-        let _key_to_hash_count = 0;
-        let cache = Cache::new();
+    #[cfg(feature = "rayon")]
+    fn test_cache_len_parallel() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        assert_eq!(cache.len_parallel(&guard), 0);
+        assert_eq!(cache.cost_used_parallel(&guard), 0);
 
+        for i in 0..10_000u64 {
+            cache.set(i, i, 1 + (i % 7) as i64, &guard);
+        }
+        for i in 0..1000u64 {
+            cache.del(&(i * 3), &guard);
+        }
+        assert_eq!(cache.len_parallel(&guard), cache.len(&guard));
+        assert_eq!(cache.len_parallel(&guard), 9000);
+        assert_eq!(cache.cost_used_parallel(&guard), cache.cost_used(&guard));
+    }
+
+    #[test]
+    fn test_cache_set_with_tti() {
+        let cache = Cache::<u64, u64>::new();
         let guard = cache.guard();
-        cache.set(1, 2, 1, &guard);
-        cache.set(2, 2, 1, &guard);
-        println!("{:?}", cache.get(&1, &guard));
-        println!("{:?}", cache.get(&2, &guard));
+        assert!(cache.set_with_tti(1, 1, 1, Duration::from_millis(100), &guard));
+        cache.set_with_ttl(2, 2, 1, Duration::from_millis(100), &guard);
+
+        // reads keep 1 alive well past its idle window, 2 expires on schedule
+        for _ in 0..6 {
+            thread::sleep(Duration::from_millis(40));
+            assert_eq!(cache.get(&1, &guard), Some(&1));
+            cache.get(&2, &guard);
+        }
+        cache.clean_up(&guard);
+        assert_eq!(cache.get_cloned(&1, &guard), Some(1));
+        assert_eq!(cache.len(&guard), 1);
+
+        thread::sleep(Duration::from_millis(150));
+        cache.clean_up(&guard);
+        assert_eq!(cache.len(&guard), 0);
+        assert_eq!(cache.get(&1, &guard), None);
     }
 
     #[test]
-    fn test_cache_insert_thread() {
-        let map = Cache::<u64, u64>::new();
-        // let map1 = Arc::clone(&map);
-        let mut hashet = HashSet::new();
+    fn test_cache_soft_values() {
+        let cache = Cache::<u64, SoftValue<String>>::with_config(Config {
+            is_reclaimed: Some(SoftValue::is_dead),
+            ..Default::default()
+        });
+        let guard = cache.guard();
+        let values: Vec<_> = (0..10u64).map(|i| Arc::new(i.to_string())).collect();
+        for (i, v) in values.iter().enumerate() {
+            assert!(cache.set_with_ttl(i as u64, SoftValue::new(v), 1, Duration::from_secs(60), &guard));
+        }
 
-        let guard = map.guard();
-        for i in 0..300_u64 {
-            if i == 256 {
-                println!("")
-            }
-            let (key_hash, _conflict) = map.hash(&i);
+        // the strong references of the odd keys go away
+        let kept: Vec<_> = values.into_iter().enumerate().filter(|(i, _)| i % 2 == 0).map(|(_, v)| v).collect();
+        assert!(cache.get(&1, &guard).unwrap().get().is_none());
 
-            hashet.insert(key_hash);
+        cache.clean_up(&guard);
+        for i in 0..10u64 {
+            match cache.get(&i, &guard) {
+                Some(v) => assert_eq!(*v.get().unwrap(), i.to_string()),
+                None => assert_eq!(i % 2, 1),
+            }
         }
+        assert_eq!(cache.len(&guard), 5);
+        let report = cache.consistency_report(&guard);
+        assert_eq!(report.policy_entries, 5);
+        assert_eq!(report.cost_used, 5);
+        drop(kept);
+    }
 
-        let _size = hashet.len();
+    #[test]
+    fn test_cache_ttl_cost_bias() {
+        let cache = Cache::<u64, u64>::with_config(Config {
+            ttl_cost_bias: Some(|ttl, cost| if cost == 1 { ttl * 2 } else { ttl }),
+            ..Default::default()
+        });
+        let guard = cache.guard();
+        cache.set_with_ttl(1, 1, 1, Duration::from_secs(10), &guard);
+        cache.set_with_ttl(2, 2, 5, Duration::from_secs(10), &guard);
+        cache.set(3, 3, 1, &guard);
 
-        // let map2 = Arc::clone(&map);
-        for i in 0..300 {
-            if let Some(v) = map.get(&i, &guard) {
-                if &i != v {
-                    panic!("i not equel {i}", )
-                }
-                println!("key:{i}, value: {:?}", map.get(&i, &guard));
-            } else {
-                println!("key:{i}, value: None");
-            }
+        let store = cache.store.load(Ordering::SeqCst, &guard);
+        let store = unsafe { store.deref() };
+        let lifetime = |key: u64| {
+            let key = cache.hash(&key).0;
+            let node = &store.data[store.bini(key)][&key];
+            node.expiration.map(|e| e - node.inserted)
         };
+        let cheap = lifetime(1).unwrap();
+        assert!(cheap >= Duration::from_secs(19) && cheap <= Duration::from_secs(20), "{:?}", cheap);
+        let dear = lifetime(2).unwrap();
+        assert!(dear >= Duration::from_secs(9) && dear <= Duration::from_secs(10), "{:?}", dear);
+        assert_eq!(lifetime(3), None);
+        assert_eq!(cache.expiring_within(Duration::from_secs(15), &guard), vec![cache.hash(&2u64).0]);
     }
 
+    #[test]
+    fn test_cache_update_adds_ttl() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        cache.set(1, 1, 1, &guard);
+        cache.set_with_ttl(1, 2, 1, Duration::from_millis(50), &guard);
+        assert_eq!(cache.expiring_within(Duration::from_secs(1), &guard), vec![cache.hash(&1u64).0]);
+
+        thread::sleep(Duration::from_millis(100));
+        cache.clean_up(&guard);
+        assert_eq!(cache.len(&guard), 0);
+        assert_eq!(cache.get_cloned(&1, &guard), None);
+    }
 
     #[test]
-    fn test_cache_key_to_hash_thread() {
-        let _key_to_hash_count = 0;
-        let cache = Cache::new();
+    fn test_cache_update_drops_ttl() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        cache.set_with_ttl(1, 1, 1, Duration::from_millis(50), &guard);
+        cache.set(1, 2, 1, &guard);
+        assert!(cache.expiring_within(Duration::from_secs(1), &guard).is_empty());
 
-        let arcc = Arc::new(cache);
-        let c1 = Arc::clone(&arcc);
-        let c2 = Arc::clone(&arcc);
-        let c3 = Arc::clone(&arcc);
+        thread::sleep(Duration::from_millis(100));
+        cache.clean_up(&guard);
+        assert_eq!(cache.len(&guard), 1);
+        assert_eq!(cache.get_cloned(&1, &guard), Some(2));
+    }
 
-        let t1 = thread::spawn(move || {
-            let guard = c1.guard();
-            for i in 0..100000 {
-                c1.set(i, i + 7, 1, &guard);
-            }
-        });
+    #[test]
+    fn test_cache_on_conflict() {
+        let cache = Cache::<u64, u64>::new();
+        let guard = cache.guard();
+        cache.set(1, 10, 1, &guard);
+        cache.set(1, 20, 1, &guard);
+        assert_eq!(cache.get(&1, &guard), Some(&20));
 
-        let t2 = thread::spawn(move || {
-            let guard = c2.guard();
-            for i in 0..100000 {
-                c2.set(i, i + 7, 1, &guard);
-            }
+        let cache = Cache::<u64, u64>::with_config(Config {
+            on_conflict: ConflictPolicy::KeepExisting,
+            ..Default::default()
         });
+        let guard = cache.guard();
+        cache.set(1, 10, 1, &guard);
+        cache.set(1, 20, 1, &guard);
+        assert_eq!(cache.get(&1, &guard), Some(&10));
+        assert_eq!(cache.set_and_get(1, 30, 1, &guard), None);
+        assert_eq!(cache.get(&1, &guard), Some(&10));
+
+        // a deleted key can be set again
+        cache.del(&1, &guard);
+        cache.set(1, 40, 1, &guard);
+        assert_eq!(cache.get(&1, &guard), Some(&40));
+    }
 
-        let t3 = thread::spawn(move || {
-            let guard = c3.guard();
-            for i in 0..100000 {
-                c3.set(i, i + 7, 1, &guard);
-            }
-        });
-        let c41 = Arc::clone(&arcc);
-        let t4 = thread::spawn(move || {
-            thread::sleep(Duration::from_millis(1000));
-            let guard = c41.guard();
-            for i in 0..300 {
-                println!("{:?}", c41.get(&i, &guard))
-            }
+    #[test]
+    fn test_cache_set_and_get() {
+        let cache = Cache::<u64, String>::with_config(Config {
+            max_cost: 10,
+            ..Default::default()
         });
+        let guard = cache.guard();
 
-        t1.join();
-        t2.join();
-        t3.join();
-        t4.join();
-        let c4 = Arc::clone(&arcc);
-        let guard = c4.guard();
-        c4.set(1, 2, 1, &guard);
-        c4.set(2, 2, 1, &guard);
-        println!("{:?}", c4.get(&1, &guard));
-        println!("{:?}", c4.get(&2, &guard));
+        let v = cache.set_and_get(1, "one".to_string(), 1, &guard);
+        assert_eq!(v.map(|v| v.as_str()), Some("one"));
+        assert_eq!(v.unwrap().len(), 3);
+        assert_eq!(cache.get(&1, &guard), Some(&"one".to_string()));
+
+        // overwriting returns the new value, the old reference stays usable under the guard
+        let old = v.unwrap();
+        assert_eq!(cache.set_and_get(1, "uno".to_string(), 1, &guard), Some(&"uno".to_string()));
+        assert_eq!(old, "one");
+
+        // bigger than the whole cache, so the policy rejects it
+        assert_eq!(cache.set_and_get(2, "two".to_string(), 11, &guard), None);
+        assert_eq!(cache.get(&2, &guard), None);
     }
 
+    static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+    // Tracked counts the live values, and holds n next to !n so a torn or freed
+    // value shows up as a mismatch.
+    struct Tracked(u64, u64);
+
+    impl Tracked {
+        fn new(n: u64) -> Self {
+            LIVE.fetch_add(1, Ordering::SeqCst);
+            Tracked(n, !n)
+        }
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            LIVE.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
 
     #[test]
-    fn test_cache_with_ttl2() {
-        let _key_to_hash_count = 0;
-        let cache = Cache::new();
+    fn test_cache_overwrite_reclaim() {
+        {
+            let cache = Arc::new(Cache::<u64, Tracked>::new());
+            let (key, conflict) = cache.hash(&1u64);
+            cache.set(1, Tracked::new(0), 1, &cache.guard());
+
+            let handles: Vec<_> = (0..8).map(|t| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for i in 0..10_000 {
+                        let guard = cache.guard();
+                        if t % 2 == 0 {
+                            cache.set(1, Tracked::new(i), 1, &guard);
+                            if i % 64 == 0 {
+                                cache.del(&1u64, &guard);
+                            }
+                        } else {
+                            let store = cache.store.load(Ordering::SeqCst, &guard);
+                            if let Some(v) = unsafe { store.deref() }.get(key, Some(conflict), &guard) {
+                                assert_eq!(v.1, !v.0);
+                            }
+                        }
+                    }
+                })
+            }).collect();
+            for h in handles {
+                h.join().unwrap();
+            }
+        }
+        assert_eq!(LIVE.load(Ordering::SeqCst), 0);
+    }
 
-        (0..ITER).into_par_iter().for_each(|i| {
-            let guard = cache.guard();
-            cache.set(i, i + 7, 1, &guard);
-        });
+    #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+    struct Point {
+        x: u32,
+        y: u32,
+    }
+
+    impl CacheKey for Point {
+        fn to_hash(&self) -> (u64, u64) {
+            (((self.x as u64) << 32) | self.y as u64, 0)
+        }
     }
 
     #[test]
-    fn test_cache_with_ttl() {
-        let _key_to_hash_count = 0;
-        let cache = Cache::new();
+    fn test_cache_custom_key() {
+        let cache = Cache::<Point, &str>::new();
+        let guard = cache.guard();
+
+        cache.set(Point { x: 1, y: 2 }, "a", 1, &guard);
+        cache.set(Point { x: 2, y: 1 }, "b", 1, &guard);
+        assert_eq!(cache.hash(&Point { x: 1, y: 2 }), ((1 << 32) | 2, 0));
+        assert_eq!(cache.get(&Point { x: 1, y: 2 }, &guard), Some(&"a"));
+        assert_eq!(cache.get(&Point { x: 2, y: 1 }, &guard), Some(&"b"));
+        assert_eq!(cache.get(&Point { x: 3, y: 3 }, &guard), None);
+    }
 
+    #[test]
+    fn test_cache_builtin_keys() {
+        let cache = Cache::<String, u64>::new();
         let guard = cache.guard();
+        cache.set("key".to_string(), 1, 1, &guard);
+        assert_eq!(cache.get("key", &guard), Some(&1));
+        assert_eq!(cache.get(&"key".to_string(), &guard), Some(&1));
 
-        let key = 1;
-        let value = 1;
-        let _cost = 1;
-        let _ttl = Duration::from_millis(0);
+        let cache = Cache::<i8, u64>::new();
+        let guard = cache.guard();
+        cache.set(-3, 1, 1, &guard);
+        assert_eq!(cache.get(&-3i8, &guard), Some(&1));
 
-        loop {
-            if !cache.set_with_ttl(1, 1, 1, Duration::from_millis(0), &guard) {
-                thread::sleep(Duration::from_millis(10));
-                continue;
-            }
-            thread::sleep(Duration::from_millis(50));
-            match cache.get(&key, &guard) {
-                None => {
-                    assert!(false)
-                }
-                Some(v) => {
-                    assert_eq!(v, &value)
-                }
-            }
-            break;
-        }
+        let cache = Cache::<usize, u64>::new();
+        let guard = cache.guard();
+        cache.set(3, 1, 1, &guard);
+        assert_eq!(cache.get(&3usize, &guard), Some(&1));
 
+        let cache = Cache::<Vec<u8>, u64>::new();
+        let guard = cache.guard();
+        cache.set(vec![1, 2, 3], 1, 1, &guard);
+        assert_eq!(cache.get(&[1u8, 2, 3][..], &guard), Some(&1));
+    }
 
-        cache.set(1, 2, 2, &guard);
-        let (key_hash, confilictha) = cache.hash(&1);
-        let store = cache.store.load(Ordering::SeqCst, &guard);
-        assert_eq!(store.is_null(), false);
-        let some = unsafe { store.deref() }.get(key_hash, confilictha, &guard);
-        assert_eq!(some.is_some(), true);
-        assert_eq!(some.unwrap(), &2);
+    #[test]
+    fn test_cache_u128_keys() {
+        // a and b only differ in their high 64 bits
+        let a = (1u128 << 64) | 42;
+        let b = (2u128 << 64) | 42;
+        let cache = Cache::<u128, u64>::new();
+        let guard = cache.guard();
+        assert_ne!(cache.hash(&a), cache.hash(&b));
 
+        cache.set(a, 1, 1, &guard);
+        assert_eq!(cache.get_cloned(&a, &guard), Some(1));
+        assert_eq!(cache.get_cloned(&b, &guard), None);
+        assert!(!cache.contains_key(&b, &guard));
 
-        thread::sleep(Duration::from_millis(10));
+        let cache = Cache::<i128, u64>::new();
+        let guard = cache.guard();
+        cache.set(-1, 1, 1, &guard);
+        assert_eq!(cache.get_cloned(&-1i128, &guard), Some(1));
+        assert_eq!(cache.get_cloned(&i128::from(u64::MAX), &guard), None);
+    }
 
-        for _i in 0..1000 {
-            let (key_hash, conflict) = cache.hash(&1);
-            cache.process_items(Node {
-                key: 0,
-                conflict,
-                value: Atomic::null(),
-                expiration: None,
-            }, Item {
-                flag: ItemUpdate,
-                key: key_hash,
-                conflict: conflict,
-                value: Atomic::from(Shared::boxed(1, &cache.collector)),
-                cost: 1,
-                expiration: Some(Duration::from_millis(200u64)),
-            }, 1, &guard);
+    #[test]
+    fn test_cache_drop_clone() {
+        let cache = Cache::<u64, u64>::new();
+        let clone = cache.clone();
+        {
+            let guard = clone.guard();
+            clone.set(1, 10, 1, &guard);
         }
-        thread::sleep(Duration::from_millis(50));
-        let v = cache.set(2, 2, 1, &guard);
-        assert_eq!(v, true);
+        drop(clone);
 
-        /*        assert_eq!(cache.metrics.is_none(), false);
-                assert_eq!(cache.metrics.unwrap().SetsDropped(&guard), 0)*/
+        let guard = cache.guard();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(cache.get(&1u64, &guard), Some(&10));
+        cache.set(2, 20, 1, &guard);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(cache.get(&2u64, &guard), Some(&20));
+        drop(guard);
+
+        // the clone outliving the original works the same
+        let clone = cache.clone();
+        drop(cache);
+        let guard = clone.guard();
+        assert_eq!(clone.get(&2u64, &guard), Some(&20));
     }
 
     #[test]
@@ -1179,11 +5208,11 @@ mod tests {
                     panic!("store is null");
                 }
                 for i in 0..ITER {
-                    let (key, confilict) = key_to_hash(&i);
+                    let (key, confilict) = i.to_hash();
                     let value = Shared::boxed(i + 2, &map1.collector);
                     let node = Node::new(key, confilict, value, None);
                     unsafe { s.as_ptr().as_mut().unwrap().set(node, &guard) };
-                    let v = unsafe { s.deref() }.get(key, confilict, &guard);
+                    let v = unsafe { s.deref() }.get(key, Some(confilict), &guard);
                     assert_eq!(v, Some(&(i + 2)))
                 }
             })
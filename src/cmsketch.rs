@@ -2,20 +2,92 @@ use std::time::SystemTime;
 
 use rand::{Rng, SeedableRng};
 use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
 
 const cmDepth: usize = 4;
 
-struct CmRows(Vec<u8>);
+/// CounterWidth is the number of bits of each counter. Wider counters tell apart
+/// very hot keys for longer, at twice the memory.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum CounterWidth {
+    #[default]
+    Four,
+    Eight,
+}
+
+impl CounterWidth {
+    /// max returns the value counters of this width saturate at.
+    pub fn max(&self) -> u8 {
+        match self {
+            CounterWidth::Four => 0x0f,
+            CounterWidth::Eight => 0xff,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct CmRows(Vec<u8>, CounterWidth);
+
+/// CmSketchSnapshot is a copy of the counters of a CmSketch, along with the seeds
+/// they were hashed with. It can be serialized, and turned back into a sketch
+/// that gives the same estimates with CmSketch::from_snapshot.
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct CmSketchSnapshot {
+    rows: Vec<Vec<u8>>,
+    width: CounterWidth,
+    seed: [u64; cmDepth],
+    mask: u64,
+    #[serde(default)]
+    conservative: bool,
+}
+
+/// SnapshotError tells why CmSketch::from_snapshot refused a snapshot.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum SnapshotError {
+    /// Depth means the snapshot doesn't have one row per seed.
+    Depth(usize),
+    /// Mask means the number of counters per row, mask + 1, isn't a power of two
+    /// of at least 2.
+    Mask(u64),
+    /// RowLen means a row doesn't hold mask + 1 counters at the snapshot's width.
+    RowLen { row: usize, len: usize, want: u64 },
+}
 
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Depth(n) => write!(f, "sketch snapshot must have {} rows, got {}", cmDepth, n),
+            SnapshotError::Mask(n) => write!(f, "sketch snapshot mask + 1 must be a power of two of at least 2, got mask {}", n),
+            SnapshotError::RowLen { row, len, want } => write!(f, "sketch snapshot row {} must be {} bytes, got {}", row, want, len),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+#[derive(Clone)]
 pub struct CmSketch {
     rows: Vec<CmRows>,
+    width: CounterWidth,
     seed: [u64; cmDepth],
     mask: u64,
+    // conservative makes increment only bump the counters at the key's minimum.
+    conservative: bool,
 }
 
 impl CmSketch {
-   pub fn new(num_counter: i64) -> Self {
+    /// SATURATION is the highest value a counter of the default 4-bit width holds.
+    /// Counters stop incrementing once they get there, see `saturation`.
+    pub const SATURATION: i64 = 15;
+
+    /// MIN_COUNTERS is the fewest counters a row gets, whatever is asked for:
+    /// smaller rows collide so much that estimates are meaningless. It matches
+    /// the smallest Bloom filter.
+    pub const MIN_COUNTERS: i64 = 512;
+
+   pub fn new(num_counter: i64, width: CounterWidth) -> Self {
         assert!(num_counter > 0, "cmSketch: bad numCounters");
+        let num_counter = num_counter.max(Self::MIN_COUNTERS);
 
         let d = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
@@ -24,26 +96,49 @@ impl CmSketch {
 
         let mut skatch = CmSketch {
             rows: Vec::with_capacity(cmDepth),
+            width,
             seed: [0; cmDepth],
             mask: (num_counter - 1) as u64,
+            conservative: false,
         };
 
         let mut raange = StdRng::seed_from_u64(d.as_secs());
         let source = raange.gen::<u64>();
         for i in 0..cmDepth {
             skatch.seed[i] = source;
-            skatch.rows.push(new_cm_row(num_counter));
+            skatch.rows.push(new_cm_row(num_counter, width));
         }
 
         skatch
     }
 
+    /// with_conservative_update switches increment to the conservative update:
+    /// only the counters of the key that hold its current estimate are bumped,
+    /// so keys colliding with hot ones are overestimated less.
+    pub fn with_conservative_update(mut self, conservative: bool) -> Self {
+        self.conservative = conservative;
+        self
+    }
+
   pub  fn increment(&mut self, hashed: u64) {
+        if self.conservative {
+            let min = self.estimate(hashed) as u8;
+            for i in 0..self.rows.len() {
+                let n = (hashed ^ self.seed[i]) & self.mask;
+                if self.rows[i].get(n) == min {
+                    self.rows[i].increment(n)
+                }
+            }
+            return;
+        }
         for i in 0..self.rows.len() {
             self.rows[i].increment((hashed ^ self.seed[i]) & self.mask)
         }
     }
 
+    /// estimate returns the value of the lowest counter of the key. It is capped
+    /// at the saturation value, which means the key was seen at least that many
+    /// times.
     pub fn estimate(&self, hashed: u64) -> i64 {
         let mut min = 255u8;
         for i in 0..self.rows.len() {
@@ -55,6 +150,17 @@ impl CmSketch {
 
         min as i64
     }
+
+    /// saturation returns the highest value a counter holds at this width.
+    pub fn saturation(&self) -> i64 {
+        self.width.max() as i64
+    }
+
+    /// is_saturated reports whether every counter of the key is saturated, so
+    /// its estimate no longer grows.
+    pub fn is_saturated(&self, hashed: u64) -> bool {
+        self.estimate(hashed) >= self.saturation()
+    }
     pub  fn reset(&mut self) {
         for i in 0..self.rows.len() {
             self.rows[i].reset();
@@ -66,32 +172,88 @@ impl CmSketch {
             self.rows[i].clear()
         }
     }
+
+    /// snapshot copies the counters out of the sketch.
+    pub fn snapshot(&self) -> CmSketchSnapshot {
+        CmSketchSnapshot {
+            rows: self.rows.iter().map(|r| r.0.clone()).collect(),
+            width: self.width,
+            seed: self.seed,
+            mask: self.mask,
+            conservative: self.conservative,
+        }
+    }
+
+    /// from_snapshot rebuilds the sketch a snapshot was taken of. Snapshots may
+    /// come from anywhere once deserialized, so it checks that the rows are
+    /// shaped like the ones of a sketch before trusting them.
+    pub fn from_snapshot(snapshot: CmSketchSnapshot) -> Result<Self, SnapshotError> {
+        if snapshot.rows.len() != cmDepth {
+            return Err(SnapshotError::Depth(snapshot.rows.len()));
+        }
+        let counters = match snapshot.mask.checked_add(1) {
+            Some(n) if n >= 2 && n.is_power_of_two() => n,
+            _ => return Err(SnapshotError::Mask(snapshot.mask)),
+        };
+        let want = match snapshot.width {
+            CounterWidth::Four => counters / 2,
+            CounterWidth::Eight => counters,
+        };
+        if let Some((row, r)) = snapshot.rows.iter().enumerate().find(|(_, r)| r.len() as u64 != want) {
+            return Err(SnapshotError::RowLen { row, len: r.len(), want });
+        }
+        Ok(CmSketch {
+            rows: snapshot.rows.into_iter().map(|r| CmRows(r, snapshot.width)).collect(),
+            width: snapshot.width,
+            seed: snapshot.seed,
+            mask: snapshot.mask,
+            conservative: snapshot.conservative,
+        })
+    }
 }
 
 impl CmRows {
     fn increment(&mut self, n: u64) {
-        let i = n / 2;
-        let s = (n & 1) * 4;
-        let v = (self.0[i as usize] >> s) & 0x0f;
-        if v < 15 {
-            self.0[i as usize] += 1 << s
+        match self.1 {
+            CounterWidth::Four => {
+                let i = n / 2;
+                let s = (n & 1) * 4;
+                let v = (self.0[i as usize] >> s) & 0x0f;
+                if v < CounterWidth::Four.max() {
+                    self.0[i as usize] += 1 << s
+                }
+            }
+            CounterWidth::Eight => {
+                self.0[n as usize] = self.0[n as usize].saturating_add(1);
+            }
         }
     }
 
     fn get(&self, n: u64) -> u8 {
-        self.0[(n / 2) as usize] >> ((n & 1) * 4) & 0x0f
+        match self.1 {
+            CounterWidth::Four => self.0[(n / 2) as usize] >> ((n & 1) * 4) & 0x0f,
+            CounterWidth::Eight => self.0[n as usize],
+        }
     }
 
     fn reset(&mut self) {
+        let mask = match self.1 {
+            CounterWidth::Four => 0x77,
+            CounterWidth::Eight => 0x7f,
+        };
         for i in 0..self.0.len() {
-            self.0[i] = (self.0[i] >> 1) & 0x77
+            self.0[i] = (self.0[i] >> 1) & mask
         }
     }
 
     fn string(&self) -> String {
         let mut s = "".to_owned();
-        for i in 0..self.0.len() * 2 {
-            s.push_str(format!("{:#02} ", (self.0[(i / 2)] >> ((i & 1) * 4)) & 0x0f).as_str());
+        let per_byte = match self.1 {
+            CounterWidth::Four => 2,
+            CounterWidth::Eight => 1,
+        };
+        for i in 0..self.0.len() * per_byte {
+            s.push_str(format!("{:#02} ", self.get(i as u64)).as_str());
         }
         let s = s;
         s
@@ -106,8 +268,11 @@ impl CmRows {
     }
 }
 
-fn new_cm_row(x: i64) -> CmRows {
-    CmRows(vec![0u8; (x / 2) as usize])
+fn new_cm_row(x: i64, width: CounterWidth) -> CmRows {
+    match width {
+        CounterWidth::Four => CmRows(vec![0u8; (x / 2) as usize], width),
+        CounterWidth::Eight => CmRows(vec![0u8; x as usize], width),
+    }
 }
 
 
@@ -130,7 +295,7 @@ mod tests {
 
     #[test]
     fn TestSketchClear() {
-        let mut s = CmSketch::new(16);
+        let mut s = CmSketch::new(16, CounterWidth::Four);
         for i in 0..16 {
             s.increment(i);
         }
@@ -139,7 +304,7 @@ mod tests {
 
     #[test]
     fn test_sketch_reset() {
-        let mut s = CmSketch::new(16);
+        let mut s = CmSketch::new(16, CounterWidth::Four);
         s.increment(1);
         s.increment(1);
         s.increment(1);
@@ -151,7 +316,7 @@ mod tests {
 
     #[test]
     fn test_sketch_estimate() {
-        let mut s = CmSketch::new(16);
+        let mut s = CmSketch::new(16, CounterWidth::Four);
         s.increment(1);
         s.increment(1);
         s.increment(9);
@@ -159,9 +324,137 @@ mod tests {
         assert_eq!(s.estimate(0), 0);
     }
 
+    #[test]
+    fn test_sketch_saturation() {
+        let mut s = CmSketch::new(16, CounterWidth::Four);
+        for _ in 0..20 {
+            s.increment(1);
+        }
+        assert_eq!(s.estimate(1), CmSketch::SATURATION);
+        assert!(s.is_saturated(1));
+
+        s.increment(2);
+        assert!(!s.is_saturated(2));
+    }
+
+    #[test]
+    fn test_sketch_eight_bit() {
+        let mut s = CmSketch::new(16, CounterWidth::Eight);
+        for _ in 0..20 {
+            s.increment(1);
+        }
+        s.increment(9);
+        assert_eq!(s.estimate(1), 20);
+        assert!(!s.is_saturated(1));
+
+        for _ in 0..300 {
+            s.increment(1);
+        }
+        assert_eq!(s.estimate(1), 255);
+        assert_eq!(s.saturation(), 255);
+        assert!(s.is_saturated(1));
+
+        s.reset();
+        assert_eq!(s.estimate(1), 127);
+    }
+
+    #[test]
+    fn test_sketch_tiny() {
+        let mut s = CmSketch::new(1, CounterWidth::Four);
+        assert_eq!(s.mask, CmSketch::MIN_COUNTERS as u64 - 1);
+        s.increment(1);
+        s.increment(1);
+        s.increment(2);
+        assert_eq!(s.estimate(1), 2);
+        assert_eq!(s.estimate(2), 1);
+    }
+
+    #[test]
+    fn test_sketch_snapshot() {
+        let mut s = CmSketch::new(16, CounterWidth::Four);
+        for _ in 0..5 {
+            s.increment(1);
+        }
+        s.increment(2);
+
+        let data = serde_json::to_vec(&s.snapshot()).unwrap();
+        let r = CmSketch::from_snapshot(serde_json::from_slice(&data).unwrap()).unwrap();
+        assert_eq!(r.estimate(1), 5);
+        assert_eq!(r.estimate(2), 1);
+        assert_eq!(r.estimate(3), 0);
+        assert_eq!(r.snapshot(), s.snapshot());
+    }
+
+    #[test]
+    fn test_sketch_snapshot_malformed() {
+        let s = CmSketch::new(16, CounterWidth::Four).snapshot();
+
+        let mut bad = s.clone();
+        bad.rows.push(bad.rows[0].clone());
+        assert_eq!(CmSketch::from_snapshot(bad).err(), Some(SnapshotError::Depth(cmDepth + 1)));
+
+        let mut bad = s.clone();
+        bad.rows[2].clear();
+        assert_eq!(CmSketch::from_snapshot(bad).err(), Some(SnapshotError::RowLen { row: 2, len: 0, want: 256 }));
+
+        // eight bit counters need twice the bytes
+        let mut bad = s.clone();
+        bad.width = CounterWidth::Eight;
+        assert_eq!(CmSketch::from_snapshot(bad).err(), Some(SnapshotError::RowLen { row: 0, len: 256, want: 512 }));
+
+        for mask in [0, 500, u64::MAX] {
+            let mut bad = s.clone();
+            bad.mask = mask;
+            assert_eq!(CmSketch::from_snapshot(bad).err(), Some(SnapshotError::Mask(mask)));
+        }
+
+        let json = r#"{"rows":[[0],[0],[0],[0]],"width":"Four","seed":[1,2,3,4],"mask":511}"#;
+        let err = CmSketch::from_snapshot(serde_json::from_str(json).unwrap()).err().unwrap();
+        assert_eq!(err.to_string(), "sketch snapshot row 0 must be 256 bytes, got 1");
+    }
+
+    #[test]
+    fn test_sketch_conservative_update() {
+        // a skewed stream: key k is seen about 2000 / (k + 1) times, over more
+        // keys than the sketch has counters
+        let mut stream = Vec::new();
+        for k in 0..4000u64 {
+            let hashed = k.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            for _ in 0..(2000 / (k + 1)).max(1) {
+                stream.push(hashed);
+            }
+        }
+        let mut rng = StdRng::seed_from_u64(7);
+        for i in (1..stream.len()).rev() {
+            stream.swap(i, rng.gen_range(0..=i));
+        }
+
+        let error = |conservative: bool| {
+            let mut s = CmSketch::new(16, CounterWidth::Eight).with_conservative_update(conservative);
+            let mut counts = std::collections::HashMap::new();
+            for hashed in stream.iter() {
+                s.increment(*hashed);
+                *counts.entry(*hashed).or_insert(0i64) += 1;
+            }
+            counts.iter().map(|(hashed, n)| {
+                let estimate = s.estimate(*hashed);
+                assert!(estimate >= (*n).min(s.saturation()), "underestimated");
+                (estimate - n).abs()
+            }).sum::<i64>()
+        };
+        assert!(error(true) <= error(false));
+
+        let mut s = CmSketch::new(16, CounterWidth::Four).with_conservative_update(true);
+        s.increment(1);
+        s.increment(1);
+        assert_eq!(s.estimate(1), 2);
+        let r = CmSketch::from_snapshot(s.snapshot()).unwrap();
+        assert!(r.conservative);
+    }
+
     #[test]
     fn test_sketch_increment() {
-        let mut s = CmSketch::new(16);
+        let mut s = CmSketch::new(16, CounterWidth::Four);
         s.increment(1);
         s.increment(5);
         s.increment(9);
@@ -178,8 +471,10 @@ mod tests {
 
     #[test]
     fn test_sketch() {
-        let s = CmSketch::new(5);
-        assert_eq!(s.mask, 7)
+        let s = CmSketch::new(5, CounterWidth::Four);
+        assert_eq!(s.mask, 511);
+        let s = CmSketch::new(600, CounterWidth::Four);
+        assert_eq!(s.mask, 1023)
     }
 
     #[test]
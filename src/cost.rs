@@ -0,0 +1,31 @@
+/// Cost lets a value report its own cost, for caches built with
+/// `Cache::with_costed_config`: a set with a cost of 0 uses it. Strings and byte
+/// vectors cost their length in bytes.
+pub trait Cost {
+    /// cost returns the cost of the value, in the units of Config::max_cost.
+    fn cost(&self) -> i64;
+}
+
+impl Cost for String {
+    fn cost(&self) -> i64 {
+        self.len() as i64
+    }
+}
+
+impl Cost for Vec<u8> {
+    fn cost(&self) -> i64 {
+        self.len() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cost::Cost;
+
+    #[test]
+    fn test_cost() {
+        assert_eq!("héllo".to_string().cost(), 6);
+        assert_eq!(String::new().cost(), 0);
+        assert_eq!(vec![0u8; 10].cost(), 10);
+    }
+}
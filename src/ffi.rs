@@ -0,0 +1,150 @@
+//! C ABI for ByteCache, enabled by the `ffi` feature. A cache is handed out as
+//! an opaque pointer by `cache_new` and must be released with `cache_free`.
+//! Keys and values are passed as pointer and length pairs; a null pointer is
+//! only accepted with a length of 0.
+
+use std::{ptr, slice};
+
+use crate::bytes::ByteCache;
+use crate::cache::Config;
+
+/// bytes turns a pointer and length pair into a slice, None if the pointer is
+/// null but the length isn't 0.
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    if data.is_null() {
+        return if len == 0 { Some(&[]) } else { None };
+    }
+    Some(slice::from_raw_parts(data, len))
+}
+
+/// cache_new returns a new cache holding up to max_cost, or null if max_cost
+/// isn't positive.
+#[no_mangle]
+pub extern "C" fn cache_new(max_cost: i64) -> *mut ByteCache {
+    let config = Config {
+        max_cost,
+        ..Default::default()
+    };
+    if config.validate().is_err() {
+        return ptr::null_mut();
+    }
+    Box::into_raw(Box::new(ByteCache::with_config(config)))
+}
+
+/// cache_set copies the key and value into the cache. It returns false if the
+/// set was dropped or an argument is invalid.
+///
+/// # Safety
+///
+/// cache must be null or come from cache_new and not be freed yet, and key and
+/// value must point to key_len and value_len readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cache_set(cache: *const ByteCache, key: *const u8, key_len: usize, value: *const u8, value_len: usize, cost: i64) -> bool {
+    let (cache, key, value) = match (cache.as_ref(), bytes(key, key_len), bytes(value, value_len)) {
+        (Some(cache), Some(key), Some(value)) => (cache, key, value),
+        _ => return false,
+    };
+    cache.set(key, value, cost)
+}
+
+/// cache_get copies as much of the value of the key as fits into buf and returns
+/// the full length of the value, so a caller can pass a null buf to learn how
+/// big it has to be. It returns -1 if the key isn't there or an argument is
+/// invalid.
+///
+/// # Safety
+///
+/// cache must be null or come from cache_new and not be freed yet, key must point
+/// to key_len readable bytes and buf to buf_len writable ones.
+#[no_mangle]
+pub unsafe extern "C" fn cache_get(cache: *const ByteCache, key: *const u8, key_len: usize, buf: *mut u8, buf_len: usize) -> isize {
+    let (cache, key) = match (cache.as_ref(), bytes(key, key_len)) {
+        (Some(cache), Some(key)) => (cache, key),
+        _ => return -1,
+    };
+    let value = match cache.get(key) {
+        Some(value) => value,
+        None => return -1,
+    };
+    if !buf.is_null() {
+        ptr::copy_nonoverlapping(value.as_ptr(), buf, value.len().min(buf_len));
+    }
+    value.len() as isize
+}
+
+/// cache_del removes the key from the cache.
+///
+/// # Safety
+///
+/// cache must be null or come from cache_new and not be freed yet, and key must
+/// point to key_len readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cache_del(cache: *const ByteCache, key: *const u8, key_len: usize) {
+    if let (Some(cache), Some(key)) = (cache.as_ref(), bytes(key, key_len)) {
+        cache.del(key)
+    }
+}
+
+/// cache_free releases the cache and everything in it. Null is ignored.
+///
+/// # Safety
+///
+/// cache must be null or come from cache_new, and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn cache_free(cache: *mut ByteCache) {
+    if !cache.is_null() {
+        drop(Box::from_raw(cache));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use crate::ffi::{cache_del, cache_free, cache_get, cache_new, cache_set};
+
+    #[test]
+    fn test_ffi_round_trip() {
+        unsafe {
+            let cache = cache_new(100);
+            assert!(!cache.is_null());
+            let key = b"key";
+            let value = b"value";
+            assert!(cache_set(cache, key.as_ptr(), key.len(), value.as_ptr(), value.len(), 1));
+
+            // ask for the length first, then read into a buffer that fits
+            assert_eq!(cache_get(cache, key.as_ptr(), key.len(), ptr::null_mut(), 0), 5);
+            let mut buf = [0u8; 5];
+            assert_eq!(cache_get(cache, key.as_ptr(), key.len(), buf.as_mut_ptr(), buf.len()), 5);
+            assert_eq!(&buf, value);
+
+            // a short buffer gets what fits
+            let mut short = [0u8; 2];
+            assert_eq!(cache_get(cache, key.as_ptr(), key.len(), short.as_mut_ptr(), short.len()), 5);
+            assert_eq!(&short, b"va");
+
+            cache_del(cache, key.as_ptr(), key.len());
+            assert_eq!(cache_get(cache, key.as_ptr(), key.len(), buf.as_mut_ptr(), buf.len()), -1);
+            cache_free(cache);
+        }
+    }
+
+    #[test]
+    fn test_ffi_null() {
+        unsafe {
+            assert!(cache_new(0).is_null());
+            let key = b"key";
+            assert!(!cache_set(ptr::null(), key.as_ptr(), key.len(), key.as_ptr(), key.len(), 1));
+            assert_eq!(cache_get(ptr::null(), key.as_ptr(), key.len(), ptr::null_mut(), 0), -1);
+            cache_del(ptr::null(), key.as_ptr(), key.len());
+            cache_free(ptr::null_mut());
+
+            let cache = cache_new(100);
+            assert!(!cache_set(cache, ptr::null(), 3, key.as_ptr(), key.len(), 1));
+            // null with a length of 0 is the empty key
+            assert!(cache_set(cache, ptr::null(), 0, ptr::null(), 0, 1));
+            assert_eq!(cache_get(cache, ptr::null(), 0, ptr::null_mut(), 0), 0);
+            cache_free(cache);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+use crate::bloom::haskey::key_to_hash;
+use crate::key::CacheKey;
+
+/// FrozenView is a read-only copy of a cache, taken by `Cache::freeze`. It owns
+/// clones of the values that were live at the time, so reads need neither locks
+/// nor a guard and never see later changes to the cache. Entries don't expire
+/// in the view.
+pub struct FrozenView<V, S = crate::DefaultHashBuilder> {
+    entries: HashMap<u64, (u64, V)>,
+    build_hasher: S,
+    conflict_hash: Option<fn(&[u8]) -> u64>,
+}
+
+impl<V, S> FrozenView<V, S> {
+    pub(crate) fn new(entries: HashMap<u64, (u64, V)>, build_hasher: S, conflict_hash: Option<fn(&[u8]) -> u64>) -> Self {
+        FrozenView {
+            entries,
+            build_hasher,
+            conflict_hash,
+        }
+    }
+
+    /// Len returns the number of entries in the view.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<V, S: BuildHasher> FrozenView<V, S> {
+    /// Get returns the value the key had when the view was taken.
+    pub fn get<Q: ?Sized + CacheKey>(&self, key: &Q) -> Option<&V> {
+        let (key_hash, conflict) = key_to_hash(key, &self.build_hasher, self.conflict_hash);
+        match self.entries.get(&key_hash) {
+            Some((c, v)) if *c == conflict => Some(v),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::cache::Cache;
+
+    #[test]
+    fn test_frozen_view() {
+        let cache = Cache::<u64, String>::new();
+        let guard = cache.guard();
+        assert!(cache.freeze(&guard).is_empty());
+
+        cache.set(1, "one".to_string(), 1, &guard);
+        cache.set(2, "two".to_string(), 1, &guard);
+        cache.set_with_ttl(3, "three".to_string(), 1, Duration::from_millis(1), &guard);
+        std::thread::sleep(Duration::from_millis(5));
+
+        let view = cache.freeze(&guard);
+        cache.set(1, "uno".to_string(), 1, &guard);
+        cache.del(&2, &guard);
+        cache.set(4, "four".to_string(), 1, &guard);
+
+        assert_eq!(view.len(), 2);
+        assert_eq!(view.get(&1u64), Some(&"one".to_string()));
+        assert_eq!(view.get(&2u64), Some(&"two".to_string()));
+        assert_eq!(view.get(&3u64), None);
+        assert_eq!(view.get(&4), None);
+        assert_eq!(cache.get(&1, &guard), Some(&"uno".to_string()));
+    }
+
+    #[test]
+    fn test_frozen_view_str_keys() {
+        let cache = Cache::<&str, u64>::new();
+        let guard = cache.guard();
+        cache.set("a", 1, 1, &guard);
+
+        let view = cache.freeze(&guard);
+        drop(guard);
+        drop(cache);
+        assert_eq!(view.get("a"), Some(&1));
+        assert_eq!(view.get("b"), None);
+    }
+}
@@ -0,0 +1,198 @@
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+
+use xxhash_rust::const_xxh3::xxh3_64 as const_xxh3;
+
+use crate::bloom::rutil::mem_hash;
+
+/// CacheKey turns a key into the key hash and the conflict hash the cache stores
+/// it under. Integers and strings and byte slices hash their bytes, integers in
+/// little-endian order, with the cache's hasher, and their conflict hash comes
+/// from the Config's conflict hash function, or xxh3. Implement it for your own
+/// key types to use them with a Cache.
+///
+/// Owned and borrowed forms of a key hash alike, the way `K: Borrow<Q>` works for
+/// a HashMap: a `Cache<String, V>` is looked up with a `&str`, and one keyed by
+/// `Vec<u8>` with a `&[u8]`, without allocating an owned key.
+pub trait CacheKey {
+    /// to_hash returns the key hash and the conflict hash of the key.
+    fn to_hash(&self) -> (u64, u64);
+
+    /// to_hash_with is what the cache calls, passing its hasher and the conflict
+    /// hash function from the Config. It defaults to to_hash; keys that hash their
+    /// bytes use the given hashers instead.
+    fn to_hash_with<S: BuildHasher>(&self, _build_hasher: &S, _conflict_hash: Option<fn(&[u8]) -> u64>) -> (u64, u64) {
+        self.to_hash()
+    }
+}
+
+// Without a hasher integers are their own key hash, with no conflict hash.
+macro_rules! int_cache_key {
+    ($($t: ty),*) => {
+        $(
+            impl CacheKey for $t {
+                fn to_hash(&self) -> (u64, u64) {
+                    (*self as u64, 0)
+                }
+
+                fn to_hash_with<S: BuildHasher>(&self, build_hasher: &S, conflict_hash: Option<fn(&[u8]) -> u64>) -> (u64, u64) {
+                    bytes_to_hash(&self.to_le_bytes(), build_hasher, conflict_hash)
+                }
+            }
+        )*
+    };
+}
+
+int_cache_key!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+// 128-bit keys don't fit in the key hash, the high half goes to the conflict
+// hash so keys that only differ there are still told apart.
+impl CacheKey for u128 {
+    fn to_hash(&self) -> (u64, u64) {
+        (*self as u64, (*self >> 64) as u64)
+    }
+
+    fn to_hash_with<S: BuildHasher>(&self, build_hasher: &S, conflict_hash: Option<fn(&[u8]) -> u64>) -> (u64, u64) {
+        bytes_to_hash(&self.to_le_bytes(), build_hasher, conflict_hash)
+    }
+}
+
+impl CacheKey for i128 {
+    fn to_hash(&self) -> (u64, u64) {
+        (*self as u128).to_hash()
+    }
+
+    fn to_hash_with<S: BuildHasher>(&self, build_hasher: &S, conflict_hash: Option<fn(&[u8]) -> u64>) -> (u64, u64) {
+        (*self as u128).to_hash_with(build_hasher, conflict_hash)
+    }
+}
+
+/// bytes_to_hash hashes the bytes of a key with the given hashers, falling back
+/// to xxh3 for the conflict hash. The conflict hash never depends on
+/// build_hasher, so keys that collide on the key hash are still told apart.
+fn bytes_to_hash<S: BuildHasher>(raw: &[u8], build_hasher: &S, conflict_hash: Option<fn(&[u8]) -> u64>) -> (u64, u64) {
+    let mut h = build_hasher.build_hasher();
+    h.write(raw);
+    let conflict = match conflict_hash {
+        Some(f) => f(raw),
+        None => const_xxh3(raw),
+    };
+    (h.finish(), conflict)
+}
+
+macro_rules! bytes_cache_key {
+    ($($t: ty),*) => {
+        $(
+            impl CacheKey for $t {
+                fn to_hash(&self) -> (u64, u64) {
+                    let raw: &[u8] = self.as_ref();
+                    (mem_hash(raw), const_xxh3(raw))
+                }
+
+                fn to_hash_with<S: BuildHasher>(&self, build_hasher: &S, conflict_hash: Option<fn(&[u8]) -> u64>) -> (u64, u64) {
+                    bytes_to_hash(self.as_ref(), build_hasher, conflict_hash)
+                }
+            }
+        )*
+    };
+}
+
+bytes_cache_key!(str, String, [u8], Vec<u8>);
+
+// References and owning pointers hash as what they point to, so a `Box<str>` or
+// `Arc<str>` key is found with a `&str`.
+macro_rules! deref_cache_key {
+    ($($t: ty),*) => {
+        $(
+            impl<T: ?Sized + CacheKey> CacheKey for $t {
+                fn to_hash(&self) -> (u64, u64) {
+                    (**self).to_hash()
+                }
+
+                fn to_hash_with<S: BuildHasher>(&self, build_hasher: &S, conflict_hash: Option<fn(&[u8]) -> u64>) -> (u64, u64) {
+                    (**self).to_hash_with(build_hasher, conflict_hash)
+                }
+            }
+        )*
+    };
+}
+
+deref_cache_key!(&T, Box<T>, Arc<T>);
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{BuildHasherDefault, Hasher};
+
+    use xxhash_rust::const_xxh3::xxh3_64 as const_xxh3;
+
+    use crate::key::CacheKey;
+
+    #[test]
+    fn test_int_keys() {
+        assert_eq!(7i8.to_hash(), (7, 0));
+        assert_eq!(7i16.to_hash(), (7, 0));
+        assert_eq!(7i32.to_hash(), (7, 0));
+        assert_eq!(7i64.to_hash(), (7, 0));
+        assert_eq!(7isize.to_hash(), (7, 0));
+        assert_eq!(7u8.to_hash(), (7, 0));
+        assert_eq!(7u16.to_hash(), (7, 0));
+        assert_eq!(7u32.to_hash(), (7, 0));
+        assert_eq!(7u64.to_hash(), (7, 0));
+        assert_eq!(7usize.to_hash(), (7, 0));
+        assert_eq!((-1i64).to_hash(), (u64::MAX, 0));
+    }
+
+    #[test]
+    fn test_128_bit_keys() {
+        assert_eq!(7u128.to_hash(), (7, 0));
+        assert_eq!(((3u128 << 64) | 7).to_hash(), (7, 3));
+        assert_eq!(7i128.to_hash(), (7, 0));
+        assert_eq!((-1i128).to_hash(), (u64::MAX, u64::MAX));
+    }
+
+    #[test]
+    fn test_bytes_keys() {
+        let h = "key".to_hash();
+        assert_ne!(h.1, 0);
+        assert_eq!(String::from("key").to_hash(), h);
+        assert_eq!(b"key"[..].to_hash(), h);
+        assert_eq!(b"key".to_vec().to_hash(), h);
+        assert_eq!((&"key").to_hash(), h);
+        assert_eq!((&&"key").to_hash(), h);
+        assert_ne!("other".to_hash(), h);
+        assert_eq!(Box::<str>::from("key").to_hash(), h);
+        assert_eq!(std::sync::Arc::<str>::from("key").to_hash(), h);
+        assert_eq!(Box::<[u8]>::from(&b"key"[..]).to_hash(), h);
+
+        let s = crate::DefaultHashBuilder::default();
+        assert_eq!("key".to_hash_with(&s, None), String::from("key").to_hash_with(&s, None));
+    }
+
+    #[test]
+    fn test_bytes_keys_use_hasher() {
+        let s = BuildHasherDefault::<DefaultHasher>::default();
+        let (key_hash, conflict) = "key".to_hash_with(&s, None);
+        let mut h = DefaultHasher::new();
+        h.write(b"key");
+        assert_eq!(key_hash, h.finish());
+        assert_eq!(conflict, const_xxh3(b"key"));
+
+        assert_eq!("key".to_hash_with(&s, Some(|_| 7)), (key_hash, 7));
+    }
+
+    #[test]
+    fn test_int_keys_use_hasher() {
+        let s = BuildHasherDefault::<DefaultHasher>::default();
+        let mut h = DefaultHasher::new();
+        h.write(&7u64.to_le_bytes());
+        assert_eq!(7u64.to_hash_with(&s, None), (h.finish(), const_xxh3(&7u64.to_le_bytes())));
+        assert_eq!(7u64.to_hash_with(&s, Some(|b| b[0] as u64)).1, 7);
+        assert_ne!(7u32.to_hash_with(&s, None), 7u64.to_hash_with(&s, None));
+
+        let (low, high) = (7u128.to_hash_with(&s, None), ((3u128 << 64) | 7).to_hash_with(&s, None));
+        assert_ne!(low.0, high.0);
+        assert_ne!(low.1, high.1);
+        assert_eq!((-1i128).to_hash_with(&s, None), u128::MAX.to_hash_with(&s, None));
+    }
+}
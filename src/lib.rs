@@ -3,13 +3,26 @@ mod store;
 mod reclaim;
 mod ttl;
 pub mod cache;
+pub mod key;
+pub mod cost;
+pub mod frozen;
 mod policy;
-mod cmsketch;
+pub mod cmsketch;
+pub mod bytes;
+pub mod soft;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 mod ring;
 
 /// Default hasher for [`HashMap`].
 pub type DefaultHashBuilder = ahash::RandomState;
 
+/// StdHashBuilder is the hasher of the standard library's HashMap, for caches
+/// built with `Cache::with_hasher` that shouldn't depend on ahash. Like ahash it
+/// is seeded randomly; use `BuildHasherDefault<DefaultHasher>` for hashes that
+/// are the same on every run.
+pub type StdHashBuilder = std::collections::hash_map::RandomState;
+
 
 #[cfg(test)]
 mod tests {
@@ -1,13 +1,13 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, LinkedList};
 use std::marker::PhantomData;
 
 use parking_lot::Mutex;
 use seize::Guard;
 
 use crate::bloom::bbloom::Bloom;
-use crate::cache::{COST_ADD, Item, KEEP_GETS, KEY_UPDATE, Metrics, REJECT_SETS};
+use crate::cache::{AdaptiveSample, COST_ADD, HIT, Item, KEEP_GETS, KEY_UPDATE, Metrics, MISS, REJECT_SETS, TinyLfuConfig};
 use crate::cache::ItemFlag::ItemNew;
-use crate::cmsketch::CmSketch;
+use crate::cmsketch::{CmSketch, CmSketchSnapshot};
 use crate::reclaim::Atomic;
 use crate::store::Node;
 
@@ -46,48 +46,151 @@ pub struct DefaultPolicy<T> {
     number_counters: i64,
     lock: Mutex<()>,
     max_cost: i64,
+    // on_pressure is called with used and max_cost when used crosses the
+    // high_water fraction of max_cost.
+    pub(crate) on_pressure: Option<fn(i64, i64)>,
+    pub(crate) high_water: f64,
+    // recency breaks ties between eviction candidates of equal frequency in
+    // favour of evicting the least recently used one. None keeps the first.
+    pub(crate) recency: Option<RecencyIndex>,
+    // max_item_cost is the highest cost a single new item may have.
+    pub(crate) max_item_cost: Option<i64>,
+    // max_entries caps the number of keys, whatever their costs add up to.
+    pub(crate) max_entries: Option<usize>,
+    // admit_all skips the frequency check: new items always get in, evicting
+    // whatever it takes to make room.
+    pub(crate) admit_all: bool,
+    // adaptive moves the eviction sample size with the hit ratio, see
+    // AdaptiveSample.
+    adaptive: Option<Adaptive>,
     _merker: PhantomData<T>,
 }
 
+/// Adaptive is the state of an adaptive sample size: its bounds, and the hit and
+/// miss counts the last batch of gets was measured from.
+#[derive(Clone, Copy)]
+struct Adaptive {
+    c: AdaptiveSample,
+    hits: u64,
+    misses: u64,
+}
+
 
 impl<T> DefaultPolicy<T> {
+    #[cfg(test)]
     pub(crate) fn new(number_counters: i64, max_cost: i64, metrics: *const Metrics) -> Self {
-     DefaultPolicy {
-            admit: TinyLFU::new(number_counters),
+        Self::with_tiny_lfu(number_counters, max_cost, metrics, &TinyLfuConfig::default())
+    }
 
-            evict: SampledLFU::new(max_cost, metrics),
+    /// with_tiny_lfu builds the policy with the admission and sampling knobs of c.
+    pub(crate) fn with_tiny_lfu(number_counters: i64, max_cost: i64, metrics: *const Metrics, c: &TinyLfuConfig) -> Self {
+        let mut evict = SampledLFU::new(max_cost, metrics);
+        evict.sample_size = c.sample_size;
+        if let Some(a) = c.adaptive_sample {
+            evict.sample_size = c.sample_size.clamp(a.min, a.max);
+        }
+        DefaultPolicy {
+            admit: TinyLFU::new(number_counters, c),
+
+            evict,
             metrics: metrics,
             // flag: AtomicIsize::new(0),
             number_counters,
             lock: Default::default(),
             max_cost,
+            on_pressure: None,
+            high_water: 0.9,
+            recency: None,
+            max_item_cost: None,
+            max_entries: None,
+            admit_all: false,
+            adaptive: c.adaptive_sample.map(|c| Adaptive { c, hits: 0, misses: 0 }),
             _merker: PhantomData,
         }
     }
 
+    /// fresh returns a policy with the same settings and metrics, that hasn't seen
+    /// any key yet.
+    pub(crate) fn fresh(&self, tiny_lfu: &TinyLfuConfig) -> Self {
+        let mut p = Self::with_tiny_lfu(self.number_counters, self.evict.max_cost, self.metrics, tiny_lfu);
+        p.max_cost = self.max_cost;
+        p.on_pressure = self.on_pressure;
+        p.high_water = self.high_water;
+        p.recency = self.recency.as_ref().map(|r| RecencyIndex::new(r.cap));
+        p.max_item_cost = self.max_item_cost;
+        p.max_entries = self.max_entries;
+        p.admit_all = self.admit_all;
+        p
+    }
+
+    /// deep_clone returns a copy of the policy, frequencies and costs included,
+    /// that counts into the given metrics.
+    pub(crate) fn deep_clone(&self, metrics: *const Metrics) -> Self {
+        let l = self.lock.lock();
+        let p = DefaultPolicy {
+            admit: self.admit.clone(),
+            evict: SampledLFU {
+                key_costs: self.evict.key_costs.clone(),
+                max_cost: self.evict.max_cost,
+                used: self.evict.used,
+                metrics,
+                sample_size: self.evict.sample_size,
+            },
+            metrics,
+            number_counters: self.number_counters,
+            lock: Mutex::new(()),
+            max_cost: self.max_cost,
+            on_pressure: self.on_pressure,
+            high_water: self.high_water,
+            recency: self.recency.clone(),
+            max_item_cost: self.max_item_cost,
+            max_entries: self.max_entries,
+            admit_all: self.admit_all,
+            adaptive: self.adaptive,
+            _merker: PhantomData,
+        };
+        drop(l);
+        p
+    }
+
+    /// replace takes over the frequencies and tracked keys of other, a policy
+    /// built with fresh, in one go. f runs under the lock first, so the caller
+    /// can swap the store in the same critical section.
+    pub(crate) fn replace<F: FnOnce()>(&mut self, other: DefaultPolicy<T>, f: F) {
+        let l = self.lock.lock();
+        f();
+        self.admit = other.admit;
+        self.evict.key_costs = other.evict.key_costs;
+        self.evict.used = other.evict.used;
+        self.recency = other.recency;
+        drop(l);
+    }
+
+    /// set_metrics points the policy and its eviction sample at the given metrics
+    /// block, used when metrics are enabled after the cache was built.
+    pub(crate) fn set_metrics(&mut self, metrics: *const Metrics) {
+        let _lock = self.lock.lock();
+        self.metrics = metrics;
+        self.evict.metrics = metrics;
+    }
+
     pub fn push<'g>(&mut self, keys: Vec<u64>, guard: &'g Guard) -> bool {
         if keys.len() == 0 {
             return true;
         }
 
 
-        // if self.flag.load(Ordering::SeqCst) == 0 {
-        //     self.flag.store(1, Ordering::SeqCst);
-            self.process_items(keys.clone(), guard);
-            let metrics = self.metrics;
-            if !metrics.is_null() {
-                unsafe {
-                    metrics.as_ref().unwrap().add(KEEP_GETS, keys[0], keys.len() as u64, guard)
-                };
-            }
-       /* } else {
-            let metrics = self.metrics;
-            if metrics.is_null() {
-                unsafe {
-                    metrics.as_ref().unwrap().add(DROP_GETS, keys[0], keys.len() as u64, guard)
-                };
-            }
-        }*/
+        // every batch is applied: the ring drains synchronously, so there is no
+        // batch in flight to drop this one for.
+        let (first, len) = (keys[0], keys.len() as u64);
+        self.process_items(keys, guard);
+        let metrics = self.metrics;
+        if !metrics.is_null() {
+            unsafe {
+                metrics.as_ref().unwrap().add(KEEP_GETS, first, len, guard)
+            };
+            self.adapt_sample_size(guard);
+        }
 
         /*select! {
             send(self.item_ch.0,keys.clone())->res =>{
@@ -128,90 +231,201 @@ impl<T> DefaultPolicy<T> {
     //      self.evict.store(new_table, Ordering::SeqCst)*/
     // }
     pub fn add<'g>(&'g mut self, key: u64, cost: i64, guard: &'g Guard<'_>) -> (Vec<Item<T>>, bool) {
+        self.add_with_veto(key, cost, &|_| true, guard)
+    }
+
+    /// add_with_veto works like add, but only evicts the keys can_evict returns
+    /// true for. If every key left is pinned that way, the new item is rejected.
+    pub fn add_with_veto<'g>(&'g mut self, key: u64, cost: i64, can_evict: &dyn Fn(u64) -> bool, guard: &'g Guard<'_>) -> (Vec<Item<T>>, bool) {
         let l = self.lock.lock();
 
 
         if key > 200000 {
             println!("")
         }
+        let cost = cost.max(0);
         // can't add an item bigger than entire cache
         if cost > self.evict.max_cost {
             drop(l);
             return (vec![], false);
         }
+        // nor one bigger than the item limit, however much room is left
+        if self.max_item_cost.is_some_and(|max| cost > max) {
+            drop(l);
+            let metrics = self.metrics;
+            if !metrics.is_null() {
+                unsafe {
+                    metrics.as_ref().unwrap().add(REJECT_SETS, key, 1, guard)
+                };
+            }
+            return (vec![], false);
+        }
         // we don't need to go any further if the item is already in the cache
         if self.evict.update_if_has(key, cost, guard) {
             drop(l);
             // An update does not count as an addition, so return false.
             return (vec![], false);
         }
-        let mut room = self.evict.room_left(cost);
+        let room = self.evict.room_left(cost);
+        let used = self.evict.used;
         // if we got this far, this key doesn't exist in the cache
         //
         // calculate the remaining room in the cache (usually bytes)
-        if room >= 0 {
+        if room >= 0 && !self.entries_over(1, 0) {
             // there's enough room in the cache to store the new item without
             // overflowing, so we can do that now and stop here
             self.evict.add(key, cost);
+            self.evict.check_used();
+            let now_used = self.evict.used;
             drop(l);
+            self.check_pressure(used, now_used);
             return (vec![], true);
         }
 
 
         let inc_hits = self.admit.estimate(key);
+        let (selected, admitted) = self.select_victims(inc_hits, cost, 1, can_evict);
+        let victims = Self::remove_victims(&mut self.evict, &mut self.recency, selected);
+        if !admitted {
+            let metrics = self.metrics;
+            if !metrics.is_null() {
+                unsafe {
+                    metrics.as_ref().unwrap().add(REJECT_SETS, key, 1, guard)
+                };
+            }
+            return (victims, false);
+        }
+        self.evict.add(key, cost);
+        self.evict.check_used();
+        let now_used = self.evict.used;
+        drop(l);
+        self.check_pressure(used, now_used);
+        return (victims, true);
+    }
+
+    /// remove_victims drops the selected keys from the policy and returns them as
+    /// the items add hands back.
+    fn remove_victims(evict: &mut SampledLFU, recency: &mut Option<RecencyIndex>, selected: Vec<PolicyPair>) -> Vec<Item<T>> {
+        let mut victims = Vec::with_capacity(selected.len());
+        for victim in selected {
+            evict.del(&victim.key);
+            if let Some(r) = recency {
+                r.remove(victim.key);
+            }
+            victims.push(Item {
+                flag: ItemNew,
+                key: victim.key,
+                conflict: 0,
+                value: Atomic::null(),
+                cost: victim.cost,
+                expiration: None,
+            });
+        }
+        victims
+    }
+
+    /// recost sets the cost of every key of costs the policy holds, then evicts
+    /// until used is back under max_cost and returns the victims. Keys can_evict
+    /// refuses are kept, even if that leaves used over max_cost.
+    pub(crate) fn recost<'g>(&'g mut self, costs: &[(u64, i64)], can_evict: &dyn Fn(u64) -> bool, guard: &'g Guard<'_>) -> Vec<Item<T>> {
+        let l = self.lock.lock();
+        for (key, cost) in costs {
+            self.evict.update_if_has(*key, *cost, guard);
+        }
+        let (selected, _) = self.select_victims(i64::MAX, 0, 0, can_evict);
+        let victims = Self::remove_victims(&mut self.evict, &mut self.recency, selected);
+        drop(l);
+        victims
+    }
+
+    /// select_victims picks the keys add evicts to make room for adding items of
+    /// the given cost and number of keys, the new one estimated at inc_hits,
+    /// without evicting them. The flag is false if the item is rejected, after
+    /// evicting the keys returned.
+    fn select_victims(&self, inc_hits: i64, cost: i64, adding: usize, can_evict: &dyn Fn(u64) -> bool) -> (Vec<PolicyPair>, bool) {
         // sample is the eviction candidate pool to be filled via random sampling
         //
         // TODO: perhaps we should use a min heap here. Right now our time
         // complexity is N for finding the min. Min heap should bring it down to
         // O(lg N).
-
         let mut sample = Vec::new();
-        let mut victims = Vec::new();
-        room = self.evict.room_left(cost);
-        while room < 0 {
-            room = self.evict.room_left(cost);
-            // fill up empty slots in sample
-            self.evict.fill_sample(&mut sample);
+        let mut victims: Vec<PolicyPair> = Vec::new();
+        // skip holds the keys not to sample again: the pinned ones and the victims
+        let mut skip = HashSet::new();
+        let mut used = self.evict.used;
+        while self.evict.max_cost.saturating_sub(used).saturating_sub(cost) < 0 || self.entries_over(adding, victims.len()) {
+            // fill up empty slots in sample, replacing the candidates that can't
+            // be evicted until there are no more keys to try
+            loop {
+                self.evict.fill_sample(&mut sample, &skip);
+                let sampled = sample.len();
+                sample.retain(|pair: &PolicyPair| can_evict(pair.key) || !skip.insert(pair.key));
+                if sample.len() == sampled {
+                    break;
+                }
+            }
+            if sample.is_empty() {
+                return (victims, false);
+            }
             let mut min_key: u64 = 0;
             let mut min_hits: i64 = i64::MAX;
-            let mut min_id: i64 = 0;
-            let mut min_cost: i64 = 0;
-
+            let mut min_id: usize = 0;
 
             for i in 0..sample.len() {
                 let hits = self.admit.estimate(sample[i].key);
-                if hits < min_hits {
+                let older = hits == min_hits && self.recency.as_ref()
+                    .is_some_and(|r| r.is_older(sample[i].key, min_key));
+                if hits < min_hits || older {
                     min_key = sample[i].key;
                     min_hits = hits;
-                    min_id = i as i64;
-                    min_cost = sample[i].cost;
+                    min_id = i;
                 }
             }
-            if inc_hits < min_hits {
-                unsafe {
-                    let metrics = self.metrics;
-                    if metrics.is_null() {
-                        unsafe {
-                            metrics.as_ref().unwrap().add(REJECT_SETS, key, 1, guard)
-                        };
-                    }
-                }
+            if inc_hits < min_hits && !self.admit_all {
                 return (victims, false);
             }
-            self.evict.del(&min_key);
-            sample[min_id as usize] = sample[sample.len() - 1];
-            victims.push(Item {
-                flag: ItemNew,
-                key: min_key,
-                conflict: 0,
-                value: Atomic::null(),
-                cost: min_cost,
-                expiration: None,
-            })
-        };
-        self.evict.add(key, cost);
+            let victim = sample.swap_remove(min_id);
+            skip.insert(victim.key);
+            used = used.saturating_sub(victim.cost).max(0);
+            victims.push(victim);
+        }
+        (victims, true)
+    }
+
+    /// preview_victims returns the keys adding a new item of the given cost, whose
+    /// key is estimated at inc_hits, would evict, without evicting them. It is
+    /// empty if the item fits, or would be rejected.
+    pub(crate) fn preview_victims(&self, inc_hits: i64, cost: i64, can_evict: &dyn Fn(u64) -> bool) -> Vec<u64> {
+        let l = self.lock.lock();
+        let cost = cost.max(0);
+        if cost > self.evict.max_cost
+            || self.max_item_cost.is_some_and(|max| cost > max)
+            || (self.evict.room_left(cost) >= 0 && !self.entries_over(1, 0)) {
+            return vec![];
+        }
+        let (victims, admitted) = self.select_victims(inc_hits, cost, 1, can_evict);
         drop(l);
-        return (victims, true);
+        if !admitted {
+            return vec![];
+        }
+        victims.iter().map(|v| v.key).collect()
+    }
+
+    /// entries_over reports whether adding the given number of keys after
+    /// evicting others would go over max_entries.
+    fn entries_over(&self, adding: usize, evicted: usize) -> bool {
+        self.max_entries.is_some_and(|max| (self.evict.key_costs.len() + adding).saturating_sub(evicted) > max)
+    }
+
+    /// check_pressure calls on_pressure if an add took used from below the high
+    /// water mark to at or above it.
+    fn check_pressure(&self, before: i64, after: i64) {
+        if let Some(f) = self.on_pressure {
+            let mark = (self.evict.max_cost as f64 * self.high_water) as i64;
+            if before < mark && after >= mark {
+                f(after, self.evict.max_cost);
+            }
+        }
     }
 
     //TODO lock
@@ -219,13 +433,28 @@ impl<T> DefaultPolicy<T> {
         self.evict.key_costs.contains_key(&key)
     }
 
+    /// del stops tracking the key. Deleting a key twice, say by a del racing an
+    /// expiry clean up, takes its cost off used only once.
     pub fn del<'g>(&'g mut self, key: &u64, _guard: &'g Guard) {
+        let l = self.lock.lock();
         self.evict.del(key);
+        if let Some(r) = &mut self.recency {
+            r.remove(*key);
+        }
+        drop(l);
     }
 
 
+    /// update sets the cost of a key that is already in the cache. It never goes
+    /// through admission, so an update can't be rejected; a key the policy lost
+    /// track of is added back as is. The next add evicts if the new cost pushed
+    /// used over max_cost.
     pub fn update<'g>(&'g mut self, key: u64, cost: i64, guard: &'g Guard) {
-        self.evict.update_if_has(key, cost, guard);
+        let l = self.lock.lock();
+        if !self.evict.update_if_has(key, cost, guard) {
+            self.evict.add(key, cost);
+        }
+        drop(l);
     }
 
     pub fn clear<'g>(&'g mut self, _guard: &'g Guard) {
@@ -233,6 +462,69 @@ impl<T> DefaultPolicy<T> {
         self.evict.clear();
     }
 
+    /// decay halves the admission counters and clears the doorkeeper, the same
+    /// aging TinyLFU does on its own every reset_at increments.
+    pub fn decay<'g>(&'g mut self, _guard: &'g Guard) {
+        let l = self.lock.lock();
+        self.admit.reset();
+        drop(l);
+    }
+
+    /// cost_histogram counts the tracked keys by cost: bucket i holds the keys
+    /// costing at most bounds[i] and more than the bounds before it, the last
+    /// bucket those costing more than every bound.
+    pub(crate) fn cost_histogram(&self, bounds: &[i64]) -> Vec<u64> {
+        let mut counts = vec![0u64; bounds.len() + 1];
+        let l = self.lock.lock();
+        for cost in self.evict.key_costs.values() {
+            let i = bounds.iter().position(|b| cost <= b).unwrap_or(bounds.len());
+            counts[i] += 1;
+        }
+        drop(l);
+        counts
+    }
+
+    /// frequency_histogram returns the keys the policy holds with their admission
+    /// estimates, highest first.
+    pub(crate) fn frequency_histogram(&self) -> Vec<(u64, i64)> {
+        let l = self.lock.lock();
+        let mut hits: Vec<_> = self.evict.key_costs.keys().map(|key| (*key, self.admit.estimate(*key))).collect();
+        drop(l);
+        hits.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hits
+    }
+
+    /// frequencies returns a snapshot of the admission counters.
+    pub(crate) fn frequencies(&self) -> CmSketchSnapshot {
+        let l = self.lock.lock();
+        let snapshot = self.admit.freq.snapshot();
+        drop(l);
+        snapshot
+    }
+
+    /// restore_frequencies replaces the admission counters with ones restored
+    /// from a snapshot.
+    pub(crate) fn restore_frequencies(&mut self, freq: CmSketch) {
+        let l = self.lock.lock();
+        self.admit.freq = freq;
+        drop(l);
+    }
+
+    /// compact releases the spare capacity of the key cost map.
+    pub fn compact<'g>(&'g mut self, _guard: &'g Guard) {
+        let l = self.lock.lock();
+        self.evict.key_costs.shrink_to_fit();
+        drop(l);
+    }
+
+    /// reserve sizes the cost map for n more keys up front, so warming up doesn't
+    /// stall on rehashes.
+    pub fn reserve<'g>(&'g mut self, n: usize, _guard: &'g Guard) {
+        let l = self.lock.lock();
+        self.evict.key_costs.reserve(n);
+        drop(l);
+    }
+
     pub fn close(&mut self) {
         //self.stop.0.send(true).expect("Chanla close");
     }
@@ -243,13 +535,80 @@ impl<T> DefaultPolicy<T> {
         }
     }
 
+    /// adapt_sample_size grows or shrinks the eviction sample by one key, going by
+    /// the hit ratio of the gets since it was last called.
+    fn adapt_sample_size(&mut self, guard: &Guard) {
+        let a = match &mut self.adaptive {
+            Some(a) => a,
+            None => return,
+        };
+        let l = self.lock.lock();
+        let metrics = unsafe { self.metrics.as_ref().unwrap() };
+        let (hits, misses) = (metrics.get(HIT, guard), metrics.get(MISS, guard));
+        let (new_hits, new_misses) = (hits.saturating_sub(a.hits), misses.saturating_sub(a.misses));
+        a.hits = hits;
+        a.misses = misses;
+        if new_hits + new_misses == 0 {
+            return;
+        }
+        let ratio = new_hits as f64 / (new_hits + new_misses) as f64;
+        if ratio < a.c.low_hit_ratio {
+            self.evict.sample_size = (self.evict.sample_size + 1).min(a.c.max);
+        } else if ratio > a.c.high_hit_ratio {
+            self.evict.sample_size = self.evict.sample_size.saturating_sub(1).max(a.c.min);
+        }
+        drop(l);
+    }
+
+    /// accounting returns, under the lock, the number of keys tracked, the cost
+    /// they are charged in total, and the sum of the costs of the given keys.
+    pub(crate) fn accounting(&self, keys: &[u64]) -> (usize, i64, i64) {
+        let l = self.lock.lock();
+        let sum = keys.iter().filter_map(|k| self.evict.key_costs.get(k)).sum();
+        let r = (self.evict.key_costs.len(), self.evict.used, sum);
+        drop(l);
+        r
+    }
+
+    /// used returns the total cost of the keys the policy holds.
+    pub(crate) fn used(&self) -> i64 {
+        let l = self.lock.lock();
+        let used = self.evict.used;
+        drop(l);
+        used
+    }
+
+    /// used_parallel adds up the costs of the keys the policy holds on the rayon
+    /// pool, rather than reading the running total like used.
+    #[cfg(feature = "rayon")]
+    pub(crate) fn used_parallel(&self) -> i64 {
+        use rayon::prelude::*;
+        let l = self.lock.lock();
+        let used = self.evict.key_costs.par_iter().map(|(_, cost)| *cost).sum();
+        drop(l);
+        used
+    }
+
     pub fn cap(&self) -> i64 {
-        self.evict.max_cost - self.evict.used
+        self.evict.max_cost.saturating_sub(self.evict.used)
+    }
+
+    /// estimate returns the TinyLFU access frequency recorded for the key.
+    pub fn estimate<'g>(&'g mut self, key: u64, _guard: &'g Guard) -> i64 {
+        self.admit.estimate(key)
     }
 
     fn process_items<'g>(&'g mut self, item: Vec<u64>, _guard: &'g Guard) {
+        // drains can come from several threads at once, the lock keeps their
+        // increments from racing.
+        let l = self.lock.lock();
+        if let Some(r) = &mut self.recency {
+            for key in item.iter() {
+                r.touch(*key);
+            }
+        }
         self.admit.push(item);
-        // self.flag.store(0, Ordering::SeqCst)
+        drop(l);
         /*        loop {
                     select! {
                        recv(self.item_ch.1) -> item => {
@@ -286,6 +645,7 @@ impl<T> DefaultPolicy<T> {
     }
 }
 
+#[derive(Clone)]
 pub struct TinyLFU {
     pub freq: CmSketch,
     pub door: Bloom,
@@ -294,22 +654,31 @@ pub struct TinyLFU {
 }
 
 impl TinyLFU {
-    pub fn new(num_counter: i64) -> Self {
+    /// new sizes the sketch and doorkeeper for num_counter keys, tuned by c.
+    pub fn new(num_counter: i64, c: &TinyLfuConfig) -> Self {
+        // resetting every few increments would keep every estimate at 0
+        let num_counter = num_counter.max(CmSketch::MIN_COUNTERS);
         TinyLFU {
-            freq: CmSketch::new(num_counter),
-            door: Bloom::new(num_counter as f64, 0.01),
+            freq: CmSketch::new(num_counter, c.counter_width).with_conservative_update(c.conservative_update),
+            door: Bloom::new(num_counter as f64, c.doorkeeper_fp),
             incrs: 0,
-            reset_at: num_counter,
+            reset_at: c.reset_at.unwrap_or(num_counter),
         }
     }
 
     pub fn push(&mut self, keys: Vec<u64>) {
-        for (_i, key) in keys.iter().enumerate() {
-            self.increment(*key)
+        for key in keys {
+            self.increment(key)
         }
     }
 
-    pub fn estimate(&mut self, key: u64) -> i64 {
+    /// doorkeeper_fp_rate estimates how often the doorkeeper mistakes a key seen
+    /// for the first time for one it has seen before.
+    pub fn doorkeeper_fp_rate(&self) -> f64 {
+        self.door.estimated_fp_rate()
+    }
+
+    pub fn estimate(&self, key: u64) -> i64 {
         let mut hits = self.freq.estimate(key);
         if self.door.has(key) {
             hits += 1;
@@ -317,9 +686,12 @@ impl TinyLFU {
         hits
     }
 
+    /// increment records an access to the key. The first one only sets the
+    /// doorkeeper bit, so keys seen once never reach the count-min sketch; later
+    /// ones bump its counters. Either way estimate goes up by one.
     pub fn increment(&mut self, key: u64) {
         // flip doorkeeper bit if not already
-        if self.door.add_if_not_has(key) {
+        if !self.door.add_if_not_has(key) {
             // increment count-min counter if doorkeeper bit is already set.
             self.freq.increment(key);
         }
@@ -337,13 +709,13 @@ impl TinyLFU {
         // halves count-min counters
         self.freq.clear();
     }
-    fn reset(&mut self) {
+    pub(crate) fn reset(&mut self) {
         // Zero out incrs.
         self.incrs = 0;
         // clears doorkeeper bits
         self.door.clear();
         // halves count-min counters
-        self.freq.clear();
+        self.freq.reset();
     }
 }
 
@@ -352,6 +724,8 @@ pub struct SampledLFU {
     pub max_cost: i64,
     pub used: i64,
     pub(crate) metrics: *const Metrics,
+    // sample_size is the number of candidates compared to pick a victim.
+    pub(crate) sample_size: usize,
 }
 
 
@@ -362,21 +736,27 @@ impl SampledLFU {
             key_costs: HashMap::new(),
             max_cost,
             used: 0,
-            metrics: shared
+            metrics: shared,
+            sample_size: LFU_SAMPLE,
         }
     }
 
+    /// room_left returns how much room would be left after adding cost, saturating
+    /// rather than overflowing for huge costs; anything negative means evict.
     fn room_left(&self, cost: i64) -> i64 {
-        self.max_cost - (self.used + cost)
+        self.max_cost.saturating_sub(self.used).saturating_sub(cost)
     }
 
-    fn fill_sample(&self, input: &mut Vec<PolicyPair>) {
-        if input.len() >= LFU_SAMPLE {
+    fn fill_sample(&self, input: &mut Vec<PolicyPair>, skip: &HashSet<u64>) {
+        if input.len() >= self.sample_size {
             return;
         }
         for (key, cost) in self.key_costs.iter() {
+            if skip.contains(key) || input.iter().any(|pair| pair.key == *key) {
+                continue;
+            }
             input.push(PolicyPair { key: *key, cost: *cost });
-            if input.len() >= LFU_SAMPLE {
+            if input.len() >= self.sample_size {
                 return;
             }
         }
@@ -387,18 +767,22 @@ impl SampledLFU {
         match self.key_costs.get(key) {
             None => {}
             Some(v) => {
-                self.used -= v;
+                self.used = self.used.saturating_sub(*v).max(0);
                 self.key_costs.remove(key);
             }
         }
+        debug_assert!(self.used >= 0, "SampledLFU: negative used {}", self.used);
     }
 
     fn add(&mut self, key: u64, cost: i64) {
-        //eprintln!("{}", cost);
+        let cost = cost.max(0);
         self.key_costs.insert(key, cost);
-        self.used += cost;
+        self.used = self.used.saturating_add(cost);
     }
+    /// update_if_has updates the cost of the key if it's tracked. An update may
+    /// push used over max_cost; the next add evicts to make up for it.
     fn update_if_has(&mut self, key: u64, cost: i64, guard: &Guard) -> bool {
+        let cost = cost.max(0);
         match self.key_costs.get(&key) {
             None => false,
             Some(v) => {
@@ -420,19 +804,95 @@ impl SampledLFU {
                         unsafe { metrics.as_ref().unwrap().add(COST_ADD, key, diff as u64, guard) }
                     }
                 }
-                self.used += cost - v;
+                self.used = self.used.saturating_sub(*v).saturating_add(cost).max(0);
                 self.key_costs.insert(key, cost);
+                debug_assert!(self.used >= 0, "SampledLFU: negative used {}", self.used);
                 true
             }
         }
     }
 
+    /// check_used asserts in debug builds that admission kept used within [0, max_cost].
+    fn check_used(&self) {
+        debug_assert!(self.used >= 0 && self.used <= self.max_cost,
+                      "SampledLFU: used {} out of [0, {}]", self.used, self.max_cost);
+    }
+
     fn clear(&mut self) {
         self.used = 0;
         self.key_costs = HashMap::default();
     }
 }
 
+/// RecencyIndex keeps the most recently accessed keys, newest first. It holds at
+/// most cap keys; keys that fell off the end count as older than any in it.
+///
+/// Every access stamps the key from a clock, so comparing two keys is a map
+/// lookup. The list keeps the accesses in order to find the oldest key once
+/// the index is full; entries of keys accessed again or removed since are left
+/// in it and skipped when they reach the end.
+#[derive(Clone)]
+pub(crate) struct RecencyIndex {
+    keys: LinkedList<(u64, u64)>,
+    stamps: HashMap<u64, u64>,
+    clock: u64,
+    cap: usize,
+}
+
+impl RecencyIndex {
+    pub(crate) fn new(cap: usize) -> Self {
+        RecencyIndex {
+            keys: LinkedList::new(),
+            stamps: HashMap::new(),
+            clock: 0,
+            cap,
+        }
+    }
+
+    /// touch moves the key to the front, dropping the oldest key when full.
+    fn touch(&mut self, key: u64) {
+        self.clock += 1;
+        self.stamps.insert(key, self.clock);
+        self.keys.push_front((key, self.clock));
+        while self.stamps.len() > self.cap {
+            match self.keys.pop_back() {
+                Some((key, stamp)) => self.forget(key, stamp),
+                None => break,
+            }
+        }
+        // a few hot keys touched over and over would grow the list for ever,
+        // so it is rebuilt from the live entries once half of it is stale.
+        if self.keys.len() > 2 * self.cap {
+            let stamps = &self.stamps;
+            self.keys = std::mem::take(&mut self.keys).into_iter()
+                .filter(|(key, stamp)| stamps.get(key) == Some(stamp))
+                .collect();
+        }
+    }
+
+    /// forget drops the key if stamp is its latest access.
+    fn forget(&mut self, key: u64, stamp: u64) {
+        if self.stamps.get(&key) == Some(&stamp) {
+            self.stamps.remove(&key);
+        }
+    }
+
+    fn remove(&mut self, key: u64) {
+        self.stamps.remove(&key);
+    }
+
+    /// rank returns how many accesses were made since the key's latest, 0 for
+    /// the newest key, or usize::MAX if it isn't in the index.
+    fn rank(&self, key: u64) -> usize {
+        self.stamps.get(&key).map_or(usize::MAX, |stamp| (self.clock - stamp) as usize)
+    }
+
+    /// is_older reports whether a was used less recently than b.
+    fn is_older(&self, a: u64, b: u64) -> bool {
+        self.rank(a) > self.rank(b)
+    }
+}
+
 #[derive(Clone, Copy)]
 struct PolicyPair {
     key: u64,
@@ -444,8 +904,10 @@ struct PolicyPair {
 mod tests {
     use seize::Collector;
 
-    use crate::cache::{DO_NOT_USE, Metrics};
-    use crate::policy::{DefaultPolicy, SampledLFU};
+    use crate::bloom::bbloom::Bloom;
+    use crate::cache::{DO_NOT_USE, Metrics, TinyLfuConfig};
+    use crate::cmsketch::{CmSketch, CounterWidth};
+    use crate::policy::{DefaultPolicy, RecencyIndex, SampledLFU};
 
     #[test]
     fn test_policy_policy_push() {
@@ -467,6 +929,75 @@ mod tests {
         drop(Box::into_raw(shard_metric))
     }
 
+    #[test]
+    fn test_policy_recency_tie() {
+        let collector = Collector::new();
+        let guard = collector.enter();
+
+        let mut p = DefaultPolicy::<i32>::new(100, 3, std::ptr::null());
+        p.recency = Some(RecencyIndex::new(10));
+        p.add(1, 1, &guard);
+        p.add(2, 1, &guard);
+        p.add(3, 1, &guard);
+
+        // nothing was accessed, so they tie on frequency and 2 is the least recent
+        for key in [2, 1, 3] {
+            p.recency.as_mut().unwrap().touch(key);
+        }
+        assert_eq!(p.estimate(1, &guard), p.estimate(2, &guard));
+        assert_eq!(p.estimate(3, &guard), p.estimate(2, &guard));
+
+        let (victims, added) = p.add(4, 1, &guard);
+        assert!(added);
+        assert_eq!(victims.len(), 1);
+        assert_eq!(victims[0].key, 2);
+        assert_eq!(p.recency.as_ref().unwrap().rank(2), usize::MAX);
+
+        // accesses pushed from the get buffers move keys to the front
+        p.push(vec![1], &guard);
+        assert_eq!(p.recency.as_ref().unwrap().rank(1), 0);
+    }
+
+    #[test]
+    fn test_recency_index() {
+        let mut r = RecencyIndex::new(2);
+        r.touch(1);
+        r.touch(2);
+        assert!(r.is_older(1, 2));
+        r.touch(1);
+        assert!(r.is_older(2, 1));
+        r.touch(3);
+        assert_eq!(r.rank(2), usize::MAX);
+        assert_eq!(r.rank(3), 0);
+        r.remove(3);
+        assert_eq!(r.rank(3), usize::MAX);
+        assert!(r.is_older(3, 1));
+    }
+
+    #[test]
+    fn test_recency_index_stale() {
+        let mut r = RecencyIndex::new(3);
+        r.touch(1);
+        r.touch(2);
+        r.touch(3);
+        // the old entries of 1 are skipped, so 2 is the oldest key to go
+        for _ in 0..100 {
+            r.touch(1);
+        }
+        assert!(r.keys.len() <= 2 * r.cap);
+        r.touch(4);
+        assert_eq!(r.rank(2), usize::MAX);
+        assert!(r.is_older(3, 4));
+        assert!(r.is_older(1, 4));
+        assert_eq!(r.stamps.len(), 3);
+
+        // a removed key leaves room without being evicted first
+        r.remove(3);
+        r.touch(5);
+        assert_eq!(r.stamps.len(), 3);
+        assert!(r.is_older(4, 5));
+    }
+
     #[test]
     fn test_policy_policy_add() {
         let collector = Collector::new();
@@ -500,6 +1031,73 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_policy_tiny_lfu_config() {
+        let p = DefaultPolicy::<u64>::new(1000, 10, std::ptr::null());
+        assert_eq!(p.admit.freq.saturation(), 15);
+        assert_eq!(p.admit.reset_at, 1000);
+        assert_eq!(p.admit.door.set_locs(), Bloom::new(1000.0, 0.01).set_locs());
+        assert_eq!(p.evict.sample_size, 5);
+
+        let c = TinyLfuConfig {
+            counter_width: CounterWidth::Eight,
+            reset_at: Some(100),
+            doorkeeper_fp: 0.0001,
+            sample_size: 8,
+            ..Default::default()
+        };
+        let p = DefaultPolicy::<u64>::with_tiny_lfu(1000, 10, std::ptr::null(), &c);
+        assert_eq!(p.admit.freq.saturation(), 255);
+        assert_eq!(p.admit.reset_at, 100);
+        assert_eq!(p.admit.door.set_locs(), Bloom::new(1000.0, 0.0001).set_locs());
+        assert!(p.admit.door.set_locs() > Bloom::new(1000.0, 0.01).set_locs());
+        assert_eq!(p.evict.sample_size, 8);
+    }
+
+    #[test]
+    fn test_policy_doorkeeper_fp_rate() {
+        let mut p = DefaultPolicy::<u64>::new(1000, 10, std::ptr::null());
+        assert_eq!(p.admit.doorkeeper_fp_rate(), 0.0);
+        p.admit.push((0..500).map(|i: u64| i.wrapping_mul(0x9e3779b97f4a7c15)).collect());
+        let rate = p.admit.doorkeeper_fp_rate();
+        assert!(rate > 0.0 && rate <= 0.01, "{}", rate);
+    }
+
+    #[test]
+    fn test_policy_push_overlapping() {
+        let collector = Collector::new();
+        let guard = collector.enter();
+
+        let mut p = DefaultPolicy::<u64>::new(1 << 16, 10, std::ptr::null());
+        let key = |i: u64| i.wrapping_mul(0x9e3779b97f4a7c15);
+        for batch in [[1, 2, 3], [2, 3, 4], [3, 4, 5], [1, 2, 3]] {
+            assert!(p.push(batch.iter().map(|&i| key(i)).collect(), &guard));
+        }
+        for (i, seen) in [(1, 2), (2, 3), (3, 4), (4, 2), (5, 1), (6, 0)] {
+            assert_eq!(p.estimate(key(i), &guard), seen, "key {}", i);
+        }
+    }
+
+    #[test]
+    fn test_policy_tiny_counters() {
+        let collector = Collector::new();
+        let guard = collector.enter();
+
+        let mut p = DefaultPolicy::<i32>::new(1, 3, std::ptr::null());
+        assert_eq!(p.admit.reset_at, CmSketch::MIN_COUNTERS);
+        for key in 1..=3 {
+            let (victims, added) = p.add(key, 1, &guard);
+            assert!(victims.is_empty() && added);
+        }
+        p.push(vec![4, 4, 4], &guard);
+        let (victims, added) = p.add(4, 1, &guard);
+        assert!(added);
+        assert_eq!(victims.len(), 1);
+        assert!(!p.has(victims[0].key, &guard));
+        assert!(p.has(4, &guard));
+        assert_eq!(p.cap(), 0);
+    }
+
     #[test]
     fn test_policy_del() {
         let collector = Collector::new();
@@ -552,6 +1150,24 @@ mod tests {
         drop(Box::into_raw(shard_metric))
     }
 
+    #[test]
+    fn test_policy_update_skips_admission() {
+        let collector = Collector::new();
+        let guard = collector.enter();
+
+        let mut p = DefaultPolicy::<i32>::new(100, 10, std::ptr::null());
+        p.add(1, 1, &guard);
+        p.add(2, 1, &guard);
+
+        // bigger than the room left, but updates aren't admitted again
+        p.update(1, 9, &guard);
+        assert_eq!(p.cost(&1, &guard), 9);
+        assert_eq!(p.evict.used, 10);
+
+        p.update(3, 2, &guard);
+        assert_eq!(p.cost(&3, &guard), 2);
+    }
+
     #[test]
     fn test_policy_cost() {
         let collector = Collector::new();
@@ -650,6 +1266,75 @@ mod tests {
         drop(Box::into_raw(shard_metric))
     }
 
+    #[test]
+    fn test_lfu_room_left_overflow() {
+        let mut lfu = SampledLFU::new(i64::MAX, std::ptr::null());
+        assert_eq!(lfu.room_left(i64::MAX), 0);
+        lfu.add(1, i64::MAX);
+        assert_eq!(lfu.room_left(1), -1);
+        assert_eq!(lfu.room_left(i64::MAX), -i64::MAX);
+        lfu.add(2, i64::MAX);
+        assert_eq!(lfu.used, i64::MAX);
+        assert!(lfu.room_left(i64::MAX) < 0);
+
+        let lfu = SampledLFU::new(10, std::ptr::null());
+        assert_eq!(lfu.room_left(i64::MAX), 10 - i64::MAX);
+        assert!(lfu.room_left(i64::MIN) > 0);
+    }
+
+    #[test]
+    fn test_policy_huge_cost() {
+        let collector = Collector::new();
+        let guard = collector.enter();
+
+        let mut p = DefaultPolicy::<i32>::new(100, 10, std::ptr::null());
+        p.add(1, 5, &guard);
+        let (victims, added) = p.add(2, i64::MAX, &guard);
+        assert!(victims.is_empty());
+        assert!(!added);
+        assert_eq!(p.add(3, i64::MAX - 1, &guard).1, false);
+        assert_eq!(p.cap(), 5);
+
+        // an update can still push used way up without overflowing cap
+        p.update(1, i64::MAX, &guard);
+        assert_eq!(p.cap(), 10 - i64::MAX);
+        p.update(1, 5, &guard);
+        assert_eq!(p.cap(), 5);
+
+        let mut p = DefaultPolicy::<i32>::new(100, i64::MAX, std::ptr::null());
+        assert!(p.add(1, i64::MAX, &guard).1);
+        assert_eq!(p.cap(), 0);
+        let (victims, added) = p.add(2, 1, &guard);
+        assert!(added);
+        assert_eq!(victims.len(), 1);
+    }
+
+    #[test]
+    fn test_lfu_used_saturates(){
+
+        let collector = Collector::new();
+
+        let guard = collector.enter();
+        let shard_metric =Box::new(Metrics::new(DO_NOT_USE, &collector));
+
+
+        let mut lfu = SampledLFU::new(4,&*shard_metric);
+        lfu.del(&1);
+        assert_eq!(lfu.used,0);
+
+        lfu.add(1, 2);
+        assert_eq!(lfu.update_if_has(1,-3,&guard),true);
+        assert_eq!(lfu.used,0);
+        lfu.del(&1);
+        assert_eq!(lfu.used,0);
+
+        lfu.add(2, 3);
+        lfu.used = 1;
+        lfu.del(&2);
+        assert_eq!(lfu.used,0);
+        drop(Box::into_raw(shard_metric))
+    }
+
     #[test]
     fn test_lfu_clear(){
 
@@ -7,20 +7,31 @@ use syncpool::prelude::*;
 use crate::policy::DefaultPolicy;
 use crate::reclaim::{Atomic, Shared};
 
-pub type RingConsumer = Box<dyn Fn(Vec<u64>) -> bool>;
+/// RingConsumer is fed the keys of full stripes. The cache hands them to its
+/// policy, but any consumer of access hints can be plugged in.
+pub trait RingConsumer {
+    /// push receives a drained batch of keys and reports whether it kept it.
+    fn push(&mut self, keys: Vec<u64>, guard: &Guard) -> bool;
+}
+
+impl<T> RingConsumer for DefaultPolicy<T> {
+    fn push(&mut self, keys: Vec<u64>, guard: &Guard) -> bool {
+        DefaultPolicy::push(self, keys, guard)
+    }
+}
 
 /// ringStripe is a singular ring buffer that is not concurrent safe.
 #[derive(Clone)]
-pub struct RingStripe<T> {
+pub struct RingStripe<C> {
     pub(crate) data: Atomic<Vec<u64>>,
     pub capa: usize,
-    pub(crate)  cons: Atomic<DefaultPolicy<T>>,
+    pub(crate)  cons: Atomic<C>,
 
 }
 
 
-impl<T> RingStripe<T> {
-    fn new(capa: usize, p: Shared<DefaultPolicy<T>>) -> Self {
+impl<C: RingConsumer> RingStripe<C> {
+    fn new(capa: usize, p: Shared<C>) -> Self {
         RingStripe {
             data: Atomic::null(),
             capa,
@@ -33,7 +44,7 @@ impl<T> RingStripe<T> {
     fn push<'g>(&'g self, item: u64, guard: &'g Guard) {
         let mut data = self.data.load(Ordering::SeqCst, guard);
         if data.is_null() {
-            data = Shared::boxed(vec![0; self.capa], guard.collector().unwrap());
+            data = Shared::boxed(Vec::with_capacity(self.capa), guard.collector().unwrap());
             self.data.store(data, Ordering::SeqCst);
         }
         let data = unsafe { data.as_ptr() };
@@ -41,24 +52,30 @@ impl<T> RingStripe<T> {
 
         data.push(item);
         if data.len() >= self.capa {
-            unsafe {
-                let p = self.cons.load(Ordering::SeqCst, guard);
-                let p = unsafe {p.as_ptr()};
-                let p = unsafe {p.as_mut().unwrap()};
-                let mut data = self.data.load(Ordering::SeqCst, guard);
-                if data.is_null() || !unsafe { data.deref() }.is_empty() {
-                    data = Shared::boxed(Vec::with_capacity(self.capa), guard.collector().unwrap());
-                    self.data.store(data, Ordering::SeqCst);
-                }
-                let data = data.as_ptr();
-                if p.push(data.as_mut().unwrap().clone(), guard) {
-                    let empty = Shared::boxed(vec![0; self.capa], guard.collector().unwrap());
-                    self.data.store(empty, Ordering::SeqCst);
-                } else {
-                    let empty = Shared::boxed(vec![0; self.capa], guard.collector().unwrap());
-                    self.data.store(empty, Ordering::SeqCst);
-                }
-            }
+            let p = self.cons.load(Ordering::SeqCst, guard);
+            let p = unsafe { p.as_ptr().as_mut().unwrap() };
+            // hand the full stripe to the consumer and start over with an empty one
+            let keys = std::mem::replace(&mut **data, Vec::with_capacity(self.capa));
+            p.push(keys, guard);
+        }
+    }
+
+    /// pending returns the number of items waiting in the stripe.
+    fn pending<'g>(&'g self, guard: &'g Guard) -> usize {
+        let data = self.data.load(Ordering::SeqCst, guard);
+        unsafe { data.as_ref() }.map_or(0, |data| data.len())
+    }
+
+    /// flush hands whatever is in the stripe to the consumer, full or not.
+    fn flush<'g>(&'g self, guard: &'g Guard) {
+        let data = self.data.load(Ordering::SeqCst, guard);
+        let data = match unsafe { data.as_ptr().as_mut() } {
+            Some(data) if !data.is_empty() => data,
+            _ => return,
+        };
+        let p = self.cons.load(Ordering::SeqCst, guard);
+        if let Some(p) = unsafe { p.as_ptr().as_mut() } {
+            p.push(std::mem::take(&mut **data), guard);
         }
     }
 }
@@ -69,8 +86,8 @@ impl<T> RingStripe<T> {
 /// This implements the "batching" process described in the BP-Wrapper paper
 /// (section III part A).
 #[derive(Clone)]
-pub struct RingBuffer<T> {
-    pool: RingStripe<T>,
+pub struct RingBuffer<C> {
+    pool: RingStripe<C>,
 }
 //
 // impl<'g,T> Clone for RingBuffer<'g,T> {
@@ -82,10 +99,10 @@ pub struct RingBuffer<T> {
 //     }
 // }
 
-impl<T> RingBuffer<T> {
+impl<C: RingConsumer> RingBuffer<C> {
     /// newRingBuffer returns a striped ring buffer. The Consumer in ringConfig will
     /// be called when individual stripes are full and need to drain their elements.
-    pub(crate)  fn new(f: Shared<DefaultPolicy<T>>, capa: usize) -> Self
+    pub(crate)  fn new(f: Shared<C>, capa: usize) -> Self
     {
         // LOSSY buffers use a very simple sync.Pool for concurrently reusing
         // stripes. We do lose some stripes due to GC (unheld items in sync.Pool
@@ -104,16 +121,57 @@ impl<T> RingBuffer<T> {
         self.pool.push(item, guard);
         // self.pool.put(g);
     }
+
+    /// Flush drains the stripes into the consumer even if they aren't full.
+    pub fn flush<'g>(&'g self, guard: &'g Guard) {
+        self.pool.flush(guard);
+    }
+
+    /// Pending returns the number of items not yet handed to the consumer.
+    pub fn pending<'g>(&'g self, guard: &'g Guard) -> usize {
+        self.pool.pending(guard)
+    }
 }
 
 
 #[cfg(test)]
 mod tests {
-    
+    use seize::{Collector, Guard};
+
+    use crate::reclaim::Shared;
+    use crate::ring::{RingBuffer, RingConsumer};
+
+    struct Recorder(Vec<Vec<u64>>);
+
+    impl RingConsumer for Recorder {
+        fn push(&mut self, keys: Vec<u64>, _guard: &Guard) -> bool {
+            self.0.push(keys);
+            true
+        }
+    }
 
     #[test]
     fn test_ring_drain() {
-        // let r := RingBuffer::new()
+        let collector = Collector::new();
+        let guard = collector.enter();
+        let recorder = Shared::boxed(Recorder(vec![]), &collector);
+        let r = RingBuffer::new(recorder, 4);
+
+        for key in 0..3 {
+            r.push(key, &guard);
+        }
+        assert!(unsafe { recorder.deref() }.0.is_empty());
+
+        for key in 3..9 {
+            r.push(key, &guard);
+        }
+        assert_eq!(unsafe { recorder.deref() }.0, vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7]]);
+
+        r.flush(&guard);
+        r.flush(&guard);
+        assert_eq!(unsafe { recorder.deref() }.0.last(), Some(&vec![8]));
+        assert_eq!(unsafe { recorder.deref() }.0.len(), 3);
+        let _ = unsafe { recorder.into_box() };
     }
 }
 
@@ -0,0 +1,62 @@
+use std::sync::{Arc, Weak};
+
+/// SoftValue holds a value the cache doesn't keep alive: it points to an Arc
+/// owned elsewhere, and once every strong reference is gone the entry is dead.
+/// Build the cache with `Config::is_reclaimed` set to `SoftValue::is_dead` and
+/// `Cache::clean_up` removes dead entries along with the expired ones.
+pub struct SoftValue<T> {
+    value: Weak<T>,
+}
+
+impl<T> SoftValue<T> {
+    pub fn new(value: &Arc<T>) -> Self {
+        SoftValue {
+            value: Arc::downgrade(value),
+        }
+    }
+
+    /// Get returns the value, or None if it has been dropped.
+    pub fn get(&self) -> Option<Arc<T>> {
+        self.value.upgrade()
+    }
+
+    /// IsDead reports whether the value has been dropped.
+    pub fn is_dead(&self) -> bool {
+        self.value.strong_count() == 0
+    }
+}
+
+impl<T> Clone for SoftValue<T> {
+    fn clone(&self) -> Self {
+        SoftValue {
+            value: self.value.clone(),
+        }
+    }
+}
+
+impl<T> From<&Arc<T>> for SoftValue<T> {
+    fn from(value: &Arc<T>) -> Self {
+        SoftValue::new(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::soft::SoftValue;
+
+    #[test]
+    fn test_soft_value() {
+        let value = Arc::new(7);
+        let soft = SoftValue::new(&value);
+        assert!(!soft.is_dead());
+        assert_eq!(soft.get().as_deref(), Some(&7));
+
+        let copy = soft.clone();
+        drop(value);
+        assert!(soft.is_dead());
+        assert!(copy.is_dead());
+        assert_eq!(soft.get(), None);
+    }
+}
@@ -1,22 +1,29 @@
 use std::collections::HashMap;
-use std::ops::{Deref, DerefMut};
-use std::sync::atomic::Ordering;
-use std::time::{Duration, Instant};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
 
 use parking_lot::Mutex;
 use seize::Guard;
 
-use crate::cache::{Item, NUM_SHARDS};
+use crate::cache::{ConflictPolicy, GetResult, Item, NUM_SHARDS};
 use crate::policy::DefaultPolicy;
-use crate::reclaim::Atomic;
-use crate::ttl::ExpirationMap;
+use crate::reclaim::{Atomic, RetireShared, Shared};
+use crate::ttl::{ExpirationMap, now};
 
 pub struct Node<V> {
     pub key: u64,
     pub conflict: u64,
     pub(crate) value: Atomic<V>,
     pub expiration: Option<Duration>,
-
+    // inserted is when the value was set, see Cache::entry_age.
+    pub inserted: Duration,
+    // idle is the time-to-idle of entries set with Cache::set_with_tti: every
+    // get pushes the expiration that far ahead.
+    pub idle: Option<Duration>,
+    // generation is bumped on every write of the entry, see
+    // Cache::get_with_generation.
+    pub generation: u64,
 }
 
 impl<V> Node<V> {
@@ -28,6 +35,9 @@ impl<V> Node<V> {
             conflict,
             value: value.into(),
             expiration,
+            inserted: now(),
+            idle: None,
+            generation: 0,
         }
     }
 }
@@ -39,6 +49,9 @@ impl<V> Clone for Node<V> {
             conflict: self.conflict,
             value: self.value.clone(),
             expiration: self.expiration,
+            inserted: self.inserted,
+            idle: self.idle,
+            generation: self.generation,
         }
     }
 }
@@ -47,36 +60,41 @@ pub(crate) struct Store<V> {
     pub data: Vec<HashMap<u64, Node<V>>>,
     em: ExpirationMap,
     lock: Mutex<()>,
+    pub(crate) on_conflict: ConflictPolicy,
+    // shard_capacity is the capacity every shard map starts with.
+    shard_capacity: usize,
+    // stats counts the gets and sets that land in each shard, when enabled.
+    pub(crate) stats: Option<Vec<AtomicU64>>,
+    // has_idle is set once an entry has a time-to-idle, so gets on stores
+    // without any skip refresh_idle.
+    has_idle: AtomicBool,
+    // generation hands out entry generations. It is shared by every key so an
+    // entry that is deleted and set again doesn't repeat an old generation.
+    generation: AtomicU64,
 }
 
-// impl<V> Clone for Store<V> {
-//     fn clone(&self) -> Self {
-//         let mut store = Store::new();
-//         for map in &self.data {
-//             store.data.push(map.clone())
-//         }
-//         store
-//     }
-// }
-
-impl<V> Deref for Store<V> {
-    type Target = ();
-
-    fn deref(&self) -> &Self::Target {
-        todo!()
-    }
-}
 
-impl<V> DerefMut for Store<V> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        self
-    }
+/// next_generation returns a generation no entry of the store has had yet.
+pub(crate) fn next_generation(counter: &AtomicU64) -> u64 {
+    counter.fetch_add(1, Ordering::SeqCst) + 1
 }
 
 impl<V> Store<V> {
     pub fn new() -> Self {
         Self::from(Vec::with_capacity(NUM_SHARDS))
     }
+
+    /// with_capacity returns a store whose shards can hold capacity entries in
+    /// total before they have to grow.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut store = Self::from(Vec::with_capacity(NUM_SHARDS));
+        store.shard_capacity = capacity.div_ceil(NUM_SHARDS);
+        for map in store.data.iter_mut() {
+            map.reserve(store.shard_capacity);
+        }
+        store
+    }
+
     pub fn from(mut data: Vec<HashMap<u64, Node<V>>>) -> Self {
         for _i in 0..NUM_SHARDS {
             data.push(HashMap::new());
@@ -86,23 +104,131 @@ impl<V> Store<V> {
             data: data,
             em: ExpirationMap::new(),
             lock: Default::default(),
+            on_conflict: ConflictPolicy::Overwrite,
+            shard_capacity: 0,
+            stats: None,
+            has_idle: AtomicBool::new(false),
+            generation: AtomicU64::new(0),
         }
     }
-    pub(crate) fn clear<'g>(&'g mut self, _guard: &'g Guard) {
-        self.data = Vec::with_capacity(NUM_SHARDS);
-        for _i in 0..NUM_SHARDS {
-            self.data.push(HashMap::new());
+    /// clear empties every shard and the expiration map. With the rayon feature
+    /// the shards are emptied in parallel, each on a guard of its own.
+    pub(crate) fn clear<'g>(&'g mut self, guard: &'g Guard) {
+        let capacity = self.shard_capacity;
+        #[cfg(feature = "rayon")]
+        if let Some(collector) = guard.collector() {
+            use rayon::prelude::*;
+            self.data.par_iter_mut().for_each(|map| {
+                Self::clear_shard(map, capacity, &collector.enter());
+            });
+            self.em.clear(guard);
+            return;
+        }
+        for map in self.data.iter_mut() {
+            Self::clear_shard(map, capacity, guard);
+        }
+        self.em.clear(guard);
+    }
+
+    fn clear_shard(map: &mut HashMap<u64, Node<V>>, capacity: usize, guard: &Guard) {
+        for node in map.values() {
+            let value = node.value.load(Ordering::SeqCst, guard);
+            if !value.is_null() {
+                unsafe { guard.retire_shared(value) };
+            }
         }
+        *map = HashMap::with_capacity(capacity);
     }
+    /// deep_clone returns a copy of the store with the same number of shards. The
+    /// values are cloned into the guard's collector, so the copy owns them, and
+    /// the expiration map is rebuilt from the nodes so TTLs keep working.
+    pub(crate) fn deep_clone<'g>(&'g self, guard: &'g Guard<'_>) -> Self
+        where V: Clone
+    {
+        let lock = self.lock.lock();
+        let mut store = Store {
+            data: Vec::with_capacity(self.data.len()),
+            em: ExpirationMap::new(),
+            lock: Default::default(),
+            on_conflict: self.on_conflict,
+            shard_capacity: self.shard_capacity,
+            stats: self.stats.as_ref().map(|stats| {
+                stats.iter().map(|n| AtomicU64::new(n.load(Ordering::Relaxed))).collect()
+            }),
+            has_idle: AtomicBool::new(self.has_idle.load(Ordering::Relaxed)),
+            generation: AtomicU64::new(self.generation.load(Ordering::SeqCst)),
+        };
+        for map in self.data.iter() {
+            let mut copy = HashMap::with_capacity(map.len());
+            for (key, node) in map.iter() {
+                let value = match unsafe { node.value.load(Ordering::SeqCst, guard).as_ref() } {
+                    Some(v) => Shared::boxed((**v).clone(), guard.collector().unwrap()),
+                    None => continue,
+                };
+                if let Some(e) = node.expiration {
+                    store.em.add(*key, node.conflict, e, guard);
+                }
+                let mut copy_node = Node::new(*key, node.conflict, value, node.expiration);
+                copy_node.inserted = node.inserted;
+                copy_node.idle = node.idle;
+                copy_node.generation = node.generation;
+                copy.insert(*key, copy_node);
+            }
+            store.data.push(copy);
+        }
+        drop(lock);
+        store
+    }
+
+    /// compact releases the spare capacity of every shard, e.g. after a burst of deletions.
+    pub(crate) fn compact<'g>(&'g mut self, _guard: &'g Guard) {
+        let lock = self.lock.lock();
+        for map in self.data.iter_mut() {
+            map.shrink_to_fit();
+        }
+        drop(lock);
+    }
+
+    /// reserve spreads room for n more entries over the shards.
+    pub(crate) fn reserve<'g>(&'g mut self, n: usize, _guard: &'g Guard) {
+        let lock = self.lock.lock();
+        let per_shard = n.div_ceil(self.data.len().max(1));
+        for map in self.data.iter_mut() {
+            map.reserve(per_shard);
+        }
+        drop(lock);
+    }
+
     pub(crate) fn is_empty(&self) -> bool {
         self.data.is_empty()
     }
 
 
-    pub(crate) fn bini(&self, hash: u64) -> usize {
+    /// bini returns the index of the shard the key hash lands in.
+    pub fn bini(&self, hash: u64) -> usize {
         (hash % NUM_SHARDS as u64) as usize
     }
 
+    /// enable_stats starts counting the gets and sets of every shard.
+    pub(crate) fn enable_stats(&mut self) {
+        self.stats = Some((0..NUM_SHARDS).map(|_| AtomicU64::new(0)).collect());
+    }
+
+    fn record(&self, index: usize) {
+        if let Some(stats) = &self.stats {
+            stats[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// shard_stats returns the number of gets and sets seen by each shard, or an
+    /// empty vec if stats aren't enabled.
+    pub(crate) fn shard_stats(&self) -> Vec<u64> {
+        match &self.stats {
+            Some(stats) => stats.iter().map(|n| n.load(Ordering::Relaxed)).collect(),
+            None => Vec::new(),
+        }
+    }
+
 
     /*   pub(crate) fn bin<'g>(&'g self, i: usize, guard: &'g Guard<'_>) -> Shared<'g, HashMap<u64, Node<V>>> {
            self.data[i].load(Ordering::Acquire, guard)
@@ -118,54 +244,195 @@ impl<V> Store<V> {
        ) -> Result<Shared<'g, HashMap<u64, Node<V>>>, reclaim::CompareExchangeError<'g, HashMap<u64, Node<V>>>> {
            self.data[i].compare_exchange(current, new, Ordering::AcqRel, Ordering::Acquire, guard)
        }*/
-    pub(crate) fn expiration<'g>(&'g mut self, key: &u64, _guard: &'g Guard<'_>) -> Option<Duration> {
-        let index = self.bini(*key);
+    /// del_expired deletes the key only if it has expired, checking and removing
+    /// under one lock, so a key re-set with a later deadline in the meantime
+    /// stays. It returns the conflict and value of the deleted entry.
+    pub(crate) fn del_expired<'g>(&'g mut self, key_hash: &u64, conflict: u64, guard: &'g Guard<'_>) -> Option<(u64, &'g V)> {
+        let lock = self.lock.lock();
+        let index = self.bini(*key_hash);
+        let expired = match self.data[index].get(key_hash) {
+            Some(v) => v.conflict == conflict && matches!(v.expiration, Some(e) if e <= now()),
+            None => false,
+        };
+        if !expired {
+            drop(lock);
+            return None;
+        }
+        let item = self.data[index].remove(key_hash)?;
+        drop(lock);
+        let v = item.value.load(Ordering::SeqCst, guard);
+        assert!(!v.is_null());
+        unsafe { guard.retire_shared(v) };
+        Some((item.conflict, unsafe { v.as_ref().unwrap().deref() }))
+    }
 
-        return match self.data[index].get(key) {
-            None => None,
-            Some(v) => {
-                v.expiration
+    /// del_where deletes every entry whose value f returns true for, checking and
+    /// removing under one lock, and returns their keys.
+    pub(crate) fn del_where<'g>(&'g mut self, f: fn(&V) -> bool, guard: &'g Guard<'_>) -> Vec<u64> {
+        let lock = self.lock.lock();
+        let mut keys = Vec::new();
+        for map in self.data.iter_mut() {
+            map.retain(|key, node| {
+                let value = node.value.load(Ordering::SeqCst, guard);
+                if !unsafe { value.as_ref() }.is_some_and(|v| f(v)) {
+                    return true;
+                }
+                if let Some(e) = node.expiration {
+                    self.em.del(key, e, guard);
+                }
+                unsafe { guard.retire_shared(value) };
+                keys.push(*key);
+                false
+            });
+        }
+        drop(lock);
+        keys
+    }
+
+    /// get returns the value stored for the key. A `None` conflict skips the
+    /// conflict check, so any entry under the key hash matches.
+    pub fn get<'g>(&'g self, key_hash: u64, confilict_hash: Option<u64>, guard: &'g Guard<'_>) -> Option<&'g V> {
+        match self.lookup(key_hash, confilict_hash, guard) {
+            GetResult::Fresh(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// get_cloned works like get but clones the value while holding the lock, so
+    /// the result doesn't borrow from the guard.
+    pub(crate) fn get_cloned<'g>(&'g self, key_hash: u64, confilict_hash: u64, guard: &'g Guard<'_>) -> Option<V>
+        where V: Clone
+    {
+        let lock = self.lock.lock();
+        self.record(self.bini(key_hash));
+        let value = match self.data[self.bini(key_hash)].get(&key_hash) {
+            Some(v) if v.conflict == confilict_hash && !matches!(v.expiration, Some(e) if e <= now()) => {
+                unsafe { v.value.load(Ordering::SeqCst, guard).as_ref() }.map(|v| (**v).clone())
+            }
+            _ => None,
+        };
+        drop(lock);
+        value
+    }
+
+    /// get_with_generation works like get but also returns the generation of the
+    /// entry, read under the same lock as the value.
+    pub(crate) fn get_with_generation<'g>(&'g self, key_hash: u64, confilict_hash: u64, guard: &'g Guard<'_>) -> Option<(&'g V, u64)> {
+        let lock = self.lock.lock();
+        self.record(self.bini(key_hash));
+        let found = match self.data[self.bini(key_hash)].get(&key_hash) {
+            Some(v) if v.conflict == confilict_hash && !matches!(v.expiration, Some(e) if e <= now()) => {
+                unsafe { v.value.load(Ordering::SeqCst, guard).as_ref() }.map(|value| (&**value, v.generation))
             }
+            _ => None,
         };
+        drop(lock);
+        found
     }
 
-    pub fn get<'g>(&'g self, key_hash: u64, confilict_hash: u64, guard: &'g Guard<'_>) -> Option<&'g V> {
+    /// lookup works like get but tells apart an entry that expired and hasn't
+    /// been cleaned up yet from one that isn't in the store at all.
+    pub(crate) fn lookup<'g>(&'g self, key_hash: u64, confilict_hash: Option<u64>, guard: &'g Guard<'_>) -> GetResult<&'g V> {
         let lock = self.lock.lock();
         let index = self.bini(key_hash);
+        self.record(index);
 
         return match self.data[index].get(&key_hash) {
             None => {
                 drop(lock);
-                None
+                GetResult::Missing
             }
             Some(v) => {
-                if confilict_hash != 0 && confilict_hash != v.conflict {
+                if matches!(confilict_hash, Some(c) if c != v.conflict) {
                     drop(lock);
-                    return None;
+                    return GetResult::Missing;
                 }
-                let now = Instant::now();
-                if v.expiration.is_some() && v.expiration.unwrap().as_millis() > now.elapsed().as_millis() {
-                    drop(lock);
-                    None
-                } else {
-                    let item = v.value.load(Ordering::SeqCst, guard);
-                    if let Some(v) = unsafe { item.as_ref() } {
-                        let v = &**v;
-                        drop(lock);
-                        return Some(v);
-                    }
-                    drop(lock);
-                    return None;
+                let item = v.value.load(Ordering::SeqCst, guard);
+                let expired = matches!(v.expiration, Some(e) if e <= now());
+                drop(lock);
+                match unsafe { item.as_ref() } {
+                    None => GetResult::Missing,
+                    Some(item) if expired => GetResult::Stale(&**item),
+                    Some(item) => GetResult::Fresh(&**item),
                 }
             }
         };
     }
 
-    pub(crate) fn set<'g>(&'g mut self, item: Node<V>, guard: &'g Guard<'_>) {
+    /// touch moves the deadline of a live entry to expiration, moving it to the
+    /// matching expiration bucket, and returns its value. Entries that are
+    /// missing or already expired are left alone.
+    pub(crate) fn touch<'g>(&'g mut self, key_hash: u64, conflict: u64, expiration: Duration, guard: &'g Guard<'_>) -> Option<&'g V> {
+        let lock = self.lock.lock();
+        let index = self.bini(key_hash);
+
+        let node = match self.data[index].get_mut(&key_hash) {
+            Some(v) if v.conflict == conflict => v,
+            _ => {
+                drop(lock);
+                return None;
+            }
+        };
+        match node.expiration {
+            Some(e) if e <= now() => {
+                drop(lock);
+                return None;
+            }
+            Some(e) => self.em.update(key_hash, conflict, e, expiration, guard),
+            None => self.em.add(key_hash, conflict, expiration, guard),
+        }
+        node.expiration = Some(expiration);
+        let value = node.value.load(Ordering::SeqCst, guard);
+        drop(lock);
+        unsafe { value.as_ref() }.map(|v| &**v)
+    }
+
+    /// set_idle gives a live entry a time-to-idle: from now on refresh_idle moves
+    /// its expiration to idle after the last access.
+    pub(crate) fn set_idle(&mut self, key_hash: u64, conflict: u64, idle: Duration) {
+        let lock = self.lock.lock();
+        let index = self.bini(key_hash);
+        if let Some(node) = self.data[index].get_mut(&key_hash) {
+            if node.conflict == conflict {
+                node.idle = Some(idle);
+                self.has_idle.store(true, Ordering::Relaxed);
+            }
+        }
+        drop(lock);
+    }
+
+    /// refresh_idle pushes back the expiration of a live entry that has a
+    /// time-to-idle, after an access. Other entries are left alone.
+    pub(crate) fn refresh_idle<'g>(&'g mut self, key_hash: u64, conflict: u64, guard: &'g Guard<'_>) {
+        if !self.has_idle.load(Ordering::Relaxed) {
+            return;
+        }
+        let lock = self.lock.lock();
+        let index = self.bini(key_hash);
+        if let Some(node) = self.data[index].get_mut(&key_hash) {
+            if let (true, Some(idle), Some(e)) = (node.conflict == conflict, node.idle, node.expiration) {
+                if e > now() {
+                    let expiration = now() + idle;
+                    node.expiration = Some(expiration);
+                    self.em.update(key_hash, conflict, e, expiration, guard);
+                }
+            }
+        }
+        drop(lock);
+    }
+
+    /// set inserts the node, taking ownership of its value. A value that replaces
+    /// another, or that is dropped because of a conflict, is retired through the
+    /// collector, so readers still holding it under their guard stay valid. With
+    /// `ConflictPolicy::KeepExisting` an existing entry is left as is.
+    pub(crate) fn set<'g>(&'g mut self, mut item: Node<V>, guard: &'g Guard<'_>) {
         let lock = self.lock.lock();
+        item.inserted = now();
+        item.generation = next_generation(&self.generation);
 
 
         let index = self.bini(item.key);
+        self.record(index);
 
         match self.data[index].get(&item.key) {
             None => {
@@ -177,60 +444,108 @@ impl<V> Store<V> {
                 drop(lock);
                 return;
             }
-            Some(v) if v.conflict != item.conflict && item.conflict != 0 => {
+            Some(v) if v.conflict != item.conflict || self.on_conflict == ConflictPolicy::KeepExisting => {
                 drop(lock);
+                let value = item.value.load(Ordering::SeqCst, guard);
+                if !value.is_null() {
+                    unsafe { guard.retire_shared(value) };
+                }
                 return;
             }
             Some(v) => {
-                if v.expiration.is_some() {
-                    self.em.update(item.key, item.conflict, v.expiration.unwrap(), item.expiration.unwrap(), guard);
-                }
+                self.move_expiration(item.key, item.conflict, v.expiration, item.expiration, guard);
 
+                let old = v.value.load(Ordering::SeqCst, guard);
+                let new = item.value.load(Ordering::SeqCst, guard);
                 self.data[index].insert(item.key, item);
                 drop(lock);
+                if !old.is_null() && old != new {
+                    unsafe { guard.retire_shared(old) };
+                }
                 return;
             }
         }
     }
 
+    /// move_expiration keeps the expiration map in line with an entry whose
+    /// deadline goes from old to new, either of which may be unset.
+    fn move_expiration(&self, key: u64, conflict: u64, old: Option<Duration>, new: Option<Duration>, guard: &Guard) {
+        match (old, new) {
+            (Some(old), Some(new)) => self.em.update(key, conflict, old, new, guard),
+            (None, Some(new)) => self.em.add(key, conflict, new, guard),
+            (Some(old), None) => self.em.del(&key, old, guard),
+            (None, None) => {}
+        }
+    }
+
+    /// update replaces the value of an existing entry, retiring the previous
+    /// value. It returns false, leaving the item's value to the caller, if the
+    /// key isn't there. With `ConflictPolicy::KeepExisting` the entry is left as
+    /// is, but it still returns true.
     pub(crate) fn update<'g>(&'g mut self, item: &Item<V>, guard: &'g Guard<'_>) -> bool {
+        self.update_if(item, None, guard)
+    }
+
+    /// update_if works like update, but with an expected generation it only
+    /// replaces the entry if that is still its generation, returning false
+    /// otherwise.
+    pub(crate) fn update_if<'g>(&'g mut self, item: &Item<V>, expected: Option<u64>, guard: &'g Guard<'_>) -> bool {
+        let lock = self.lock.lock();
         let index = self.bini(item.key);
+        self.record(index);
 
 
-        return match self.data[index].get_mut(&item.key) {
+        return match self.data[index].get(&item.key) {
             None => {
+                drop(lock);
                 false
             }
-            Some(v) if v.conflict != item.conflict && item.conflict != 0 => {
+            Some(v) if v.conflict != item.conflict || matches!(expected, Some(g) if g != v.generation) => {
+                drop(lock);
                 false
             }
+            Some(_) if self.on_conflict == ConflictPolicy::KeepExisting => {
+                drop(lock);
+                true
+            }
             Some(v) => {
-                if v.expiration.is_some() {
-                    //todo
-                    self.em.update(item.key, item.conflict, v.expiration.unwrap(), item.expiration.unwrap(), guard);
-                }
+                self.move_expiration(item.key, item.conflict, v.expiration, item.expiration, guard);
+                let old = v.value.load(Ordering::SeqCst, guard);
+                let new = item.value.load(Ordering::SeqCst, guard);
                 self.data[index].insert(item.key, Node {
                     key: item.key,
                     conflict: item.conflict,
                     value: item.value.clone(),
                     expiration: item.expiration,
-
+                    inserted: now(),
+                    idle: None,
+                    generation: next_generation(&self.generation),
                 });
+                drop(lock);
+                if !old.is_null() && old != new {
+                    unsafe { guard.retire_shared(old) };
+                }
 
                 true
             }
         };
     }
 
-    pub(crate) fn del<'g>(&'g mut self, key_hash: &u64, conflict: &u64, guard: &'g Guard<'_>) -> Option<(u64, &'g V)> {
+    /// del removes the entry for the key. As with get, a `None` conflict skips
+    /// the conflict check. The removed value is retired, the returned reference
+    /// stays valid for as long as the guard is held.
+    pub(crate) fn del<'g>(&'g mut self, key_hash: &u64, conflict: Option<u64>, guard: &'g Guard<'_>) -> Option<(u64, &'g V)> {
+        let lock = self.lock.lock();
         let index = self.bini(*key_hash);
 
 
         return match self.data[index].get_mut(key_hash) {
             None => {
+                drop(lock);
                 None
             }
-            Some(v) if v.conflict != *conflict && *conflict != 0 => {
+            Some(v) if matches!(conflict, Some(c) if c != v.conflict) => {
+                drop(lock);
                 None
             }
             Some(v) => {
@@ -238,31 +553,230 @@ impl<V> Store<V> {
                     self.em.del(&v.key, v.expiration.unwrap(), guard);
                 }
                 if let Some(item) = self.data[index].remove(key_hash) {
+                    drop(lock);
                     let v = item.value.load(Ordering::SeqCst, guard);
                     assert!(!v.is_null());
+                    unsafe { guard.retire_shared(v) };
                     return Some((item.conflict, unsafe { v.as_ref().unwrap().deref() }));
                 }
+                drop(lock);
                 None
             }
         };
     }
 
+    /// len returns the number of entries, including expired ones that haven't
+    /// been cleaned up yet.
+    pub(crate) fn len(&self) -> usize {
+        let lock = self.lock.lock();
+        let len = self.data.iter().map(|m| m.len()).sum();
+        drop(lock);
+        len
+    }
+
+    /// len_parallel works like len but counts the shards on the rayon pool.
+    #[cfg(feature = "rayon")]
+    pub(crate) fn len_parallel(&self) -> usize
+        where V: Send + Sync
+    {
+        use rayon::prelude::*;
+        let lock = self.lock.lock();
+        let len = self.data.par_iter().map(|m| m.len()).sum();
+        drop(lock);
+        len
+    }
+
+    /// keys returns the key hashes of the live entries.
+    pub(crate) fn keys(&self) -> Vec<u64> {
+        let now = now();
+        let lock = self.lock.lock();
+        let keys = self.data.iter()
+            .flat_map(|m| m.iter())
+            .filter(|(_, node)| !matches!(node.expiration, Some(e) if e <= now))
+            .map(|(key, _)| *key)
+            .collect();
+        drop(lock);
+        keys
+    }
+
+    /// values returns the values of the live entries.
+    pub(crate) fn values<'g>(&'g self, guard: &'g Guard<'_>) -> Vec<&'g V> {
+        let now = now();
+        let lock = self.lock.lock();
+        let values = self.data.iter()
+            .flat_map(|m| m.values())
+            .filter(|node| !matches!(node.expiration, Some(e) if e <= now))
+            .filter_map(|node| unsafe { node.value.load(Ordering::SeqCst, guard).as_ref() }.map(|v| &**v))
+            .collect();
+        drop(lock);
+        values
+    }
+
+    /// freeze copies the live entries into a map of key hash to conflict and value.
+    pub(crate) fn freeze<'g>(&'g self, guard: &'g Guard<'_>) -> HashMap<u64, (u64, V)>
+        where V: Clone
+    {
+        let now = now();
+        let lock = self.lock.lock();
+        let mut entries = HashMap::new();
+        for map in self.data.iter() {
+            for (key, node) in map.iter() {
+                if matches!(node.expiration, Some(e) if e <= now) {
+                    continue;
+                }
+                if let Some(value) = unsafe { node.value.load(Ordering::SeqCst, guard).as_ref() } {
+                    entries.insert(*key, (node.conflict, (**value).clone()));
+                }
+            }
+        }
+        drop(lock);
+        entries
+    }
+
+    /// expiring_within returns the keys whose deadline falls in the next window,
+    /// soonest first. Entries that have already expired are left out.
+    pub(crate) fn expiring_within<'g>(&'g self, window: Duration, guard: &'g Guard<'_>) -> Vec<u64> {
+        let now = now();
+        let candidates = self.em.expiring_before(now + window, guard);
+
+        let lock = self.lock.lock();
+        let mut keys: Vec<(Duration, u64)> = candidates.into_iter()
+            .filter_map(|(key, conflict)| {
+                let node = self.data[self.bini(key)].get(&key)?;
+                match node.expiration {
+                    Some(e) if node.conflict == conflict && e > now && e <= now + window => Some((e, key)),
+                    _ => None,
+                }
+            })
+            .collect();
+        drop(lock);
+        keys.sort();
+        keys.into_iter().map(|(_, key)| key).collect()
+    }
+
+    /// remove_if removes the entry for the key if f returns true for its value,
+    /// checking and removing under the same lock. The removed value is retired.
+    pub(crate) fn remove_if<'g, F>(&'g mut self, key_hash: u64, conflict: u64, f: F, guard: &'g Guard<'_>) -> bool
+        where F: FnOnce(&V) -> bool
+    {
+        let lock = self.lock.lock();
+        let index = self.bini(key_hash);
+
+        let node = match self.data[index].get(&key_hash) {
+            Some(v) if v.conflict == conflict => v,
+            _ => {
+                drop(lock);
+                return false;
+            }
+        };
+        let value = node.value.load(Ordering::SeqCst, guard);
+        match unsafe { value.as_ref() } {
+            Some(v) if f(v) => {}
+            _ => {
+                drop(lock);
+                return false;
+            }
+        }
+        if let Some(e) = node.expiration {
+            self.em.del(&key_hash, e, guard);
+        }
+        self.data[index].remove(&key_hash);
+        drop(lock);
+        unsafe { guard.retire_shared(value) };
+        true
+    }
+
+    /// refresh replaces the value of a live entry with the one f computes from it,
+    /// keeping its deadline, and returns the cost f gave. The read and the write
+    /// happen under the store lock, so concurrent refreshes don't lose updates.
+    pub(crate) fn refresh<'g, F>(&'g mut self, key_hash: u64, conflict: u64, f: F, guard: &'g Guard<'_>) -> Option<i64>
+        where F: FnOnce(&V) -> (V, i64)
+    {
+        let lock = self.lock.lock();
+        let index = self.bini(key_hash);
+
+        let node = match self.data[index].get_mut(&key_hash) {
+            Some(v) if v.conflict == conflict && !matches!(v.expiration, Some(e) if e <= now()) => v,
+            _ => {
+                drop(lock);
+                return None;
+            }
+        };
+        let old = node.value.load(Ordering::SeqCst, guard);
+        let (value, cost) = match unsafe { old.as_ref() } {
+            Some(v) => f(v),
+            None => {
+                drop(lock);
+                return None;
+            }
+        };
+        node.value.store(Shared::boxed(value, guard.collector().unwrap()), Ordering::SeqCst);
+        node.inserted = now();
+        node.generation = next_generation(&self.generation);
+        drop(lock);
+        unsafe { guard.retire_shared(old) };
+        Some(cost)
+    }
+
+    /// entry_age returns how long ago the live entry for the key was set.
+    pub(crate) fn entry_age(&self, key_hash: u64, conflict: u64) -> Option<Duration> {
+        let lock = self.lock.lock();
+        let age = match self.data[self.bini(key_hash)].get(&key_hash) {
+            Some(v) if v.conflict == conflict && !matches!(v.expiration, Some(e) if e <= now()) => {
+                Some(now().saturating_sub(v.inserted))
+            }
+            _ => None,
+        };
+        drop(lock);
+        age
+    }
+
+    /// swap_data replaces the entries with those of other in one go: readers see
+    /// either the old entries or the new ones. The deadlines of the old entries
+    /// are dropped, those of other are kept, and the old values are retired.
+    pub(crate) fn swap_data<'g>(&'g mut self, mut other: Store<V>, guard: &'g Guard<'_>) {
+        let lock = self.lock.lock();
+        std::mem::swap(&mut self.data, &mut other.data);
+        self.em.clear(guard);
+        for map in self.data.iter_mut() {
+            for node in map.values_mut() {
+                // other counted generations on its own, restamp them from ours
+                node.generation = next_generation(&self.generation);
+                if let Some(e) = node.expiration {
+                    self.em.add(node.key, node.conflict, e, guard);
+                }
+            }
+        }
+        drop(lock);
+        for map in other.data.iter_mut() {
+            Self::clear_shard(map, 0, guard);
+        }
+    }
+
+    /// with_lock runs f on the shards while holding the store lock, so whatever f
+    /// reads and writes changes at once as far as other callers can tell.
+    pub(crate) fn with_lock<R, F>(&mut self, f: F) -> R
+        where F: FnOnce(&mut Vec<HashMap<u64, Node<V>>>, &AtomicU64) -> R
+    {
+        let lock = self.lock.lock();
+        let r = f(&mut self.data, &self.generation);
+        drop(lock);
+        r
+    }
+
+    /// compact_ttl drops the empty buckets of the expiration map and returns how
+    /// many are left.
+    pub(crate) fn compact_ttl<'g>(&'g self, guard: &'g Guard<'_>) -> usize {
+        self.em.compact(guard)
+    }
+
     pub(crate) fn clean_up<'g>(&'g mut self, policy: &mut DefaultPolicy<V>, guard: &'g Guard<'_>) {
         let maps = self.em.cleanup(policy, None, guard);
         for (key, conflict) in maps {
-            match self.expiration(&key,
-                                  guard) {
-                None => { continue; }
-                Some(_v) => {
-                    let _cost = policy.cost(&key, guard);
-                    policy.del(&key, guard);
-                    let _value = self.del(&key, &conflict, guard);
-                    //ToDO for evict
-                    // if f.is_some(){
-                    // //ToDO for evict
-                    // // f.unwrap()(*key,*confilct,value.unwrap().1,cost)
-                    // }
-                }
+            // the key may have been re-set with a later deadline, or without one,
+            // or deleted already: then whoever removed it settled its cost
+            if self.del_expired(&key, conflict, guard).is_some() {
+                policy.del(&key, guard);
             }
         }
     }
@@ -272,16 +786,39 @@ impl<V> Store<V> {
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use seize::Collector;
 
-    use crate::bloom::haskey::key_to_hash;
-    use crate::cache::Item;
+    use crate::cache::{ConflictPolicy, Item, NUM_SHARDS};
+    use crate::key::CacheKey;
+    use crate::policy::DefaultPolicy;
+    use crate::ttl::now;
     use crate::cache::ItemFlag::ItemNew;
     use crate::reclaim::Shared;
     use crate::store::{Node, Store};
 
     const ITER: u64 = 32 * 1024;
 
+    // Probe::<T>::IS_DEREF resolves to the inherent const only when T: Deref,
+    // and falls back to the trait default otherwise.
+    trait NotDeref {
+        const IS_DEREF: bool = false;
+    }
+
+    struct Probe<T>(std::marker::PhantomData<T>);
+
+    impl<T> NotDeref for Probe<T> {}
+
+    impl<T: std::ops::Deref> Probe<T> {
+        #[allow(dead_code)]
+        const IS_DEREF: bool = true;
+    }
+
+    // Store used to deref to () through a todo!(); make sure it can't again.
+    const _: () = assert!(!Probe::<Store<u64>>::IS_DEREF);
+    const _: () = assert!(Probe::<Box<u64>>::IS_DEREF);
+
     #[test]
     fn test_set_get() {
         let collector = Collector::new();
@@ -289,12 +826,12 @@ mod tests {
         let mut s = Store::new();
 
         for i in 0..20 {
-            let (key, confilict) = key_to_hash(&i);
+            let (key, confilict) = i.to_hash();
             let value = Shared::boxed(i + 2, &collector);
             let node = Node::new(key, confilict, value, None);
 
             s.set(node, &guard);
-            let v = s.get(key, confilict, &guard);
+            let v = s.get(key, Some(confilict), &guard);
             assert_eq!(v, Some(&(i + 2)))
         }
     }
@@ -306,20 +843,122 @@ mod tests {
         let mut s = Store::new();
 
         for i in 0..20 {
-            let (key, confilict) = key_to_hash(&i);
+            let (key, confilict) = i.to_hash();
             let value = Shared::boxed(i + 2, &collector);
             let node = Node::new(key, confilict, value, None);
 
             s.set(node, &guard);
-            let d = s.del(&key, &confilict, &guard);
+            let d = s.del(&key, Some(confilict), &guard);
             assert_eq!(d.unwrap().1, &(i + 2));
 
-            let v = s.get(key, confilict, &guard);
+            let v = s.get(key, Some(confilict), &guard);
             assert_eq!(v, None);
         }
     }
 
 
+    #[test]
+    fn test_with_capacity() {
+        let collector = Collector::new();
+        let guard = collector.enter();
+        let mut s = Store::with_capacity(NUM_SHARDS * 100);
+        let capacities: Vec<_> = s.data.iter().map(|m| m.capacity()).collect();
+        assert!(capacities.iter().all(|c| *c >= 100));
+
+        // key % NUM_SHARDS picks the shard, so every shard gets 100 keys
+        for key in 0..(NUM_SHARDS * 100) as u64 {
+            s.set(Node::new(key, 0, Shared::boxed(key, &collector), None), &guard);
+        }
+        assert_eq!(s.len(), NUM_SHARDS * 100);
+        assert_eq!(s.data.iter().map(|m| m.capacity()).collect::<Vec<_>>(), capacities);
+    }
+
+    #[test]
+    fn test_clean_up_past_buckets() {
+        let collector = Collector::new();
+        let guard = collector.enter();
+        let mut s = Store::new();
+        let mut policy = DefaultPolicy::<u64>::new(100, 10, std::ptr::null());
+
+        // deadlines far enough apart to land in three different buckets
+        for i in 0..3u64 {
+            let (key, conflict) = i.to_hash();
+            let expiration = now() - Duration::from_millis(100 * (i + 1));
+            policy.add(key, 1, &guard);
+            s.set(Node::new(key, conflict, Shared::boxed(i, &collector), Some(expiration)), &guard);
+        }
+        let (key, conflict) = 3u64.to_hash();
+        s.set(Node::new(key, conflict, Shared::boxed(3, &collector), None), &guard);
+        assert_eq!(s.em.expiring_before(now(), &guard).len(), 3);
+
+        s.clean_up(&mut policy, &guard);
+        assert_eq!(s.len(), 1);
+        assert_eq!(s.get(key, Some(conflict), &guard), Some(&3));
+        // the drained buckets are gone
+        assert!(s.em.expiring_before(now() + Duration::from_secs(60), &guard).is_empty());
+    }
+
+    #[test]
+    fn test_clear_large() {
+        let collector = Collector::new();
+        let guard = collector.enter();
+        let mut s = Store::new();
+
+        const N: u64 = 2_000_000;
+        for i in 0..N {
+            // every tenth entry expires, long after the test is over
+            let expiration = (i % 10 == 0).then(|| now() + Duration::from_secs(600));
+            s.set(Node::new(i, 0, Shared::boxed(i, &collector), expiration), &guard);
+        }
+        assert_eq!(s.len(), N as usize);
+        assert_eq!(s.em.expiring_before(now() + Duration::from_secs(3600), &guard).len(), (N / 10) as usize);
+
+        s.clear(&guard);
+        assert_eq!(s.len(), 0);
+        assert_eq!(s.data.len(), NUM_SHARDS);
+        assert!(s.em.expiring_before(now() + Duration::from_secs(3600), &guard).is_empty());
+        for i in (0..N).step_by(1000) {
+            assert_eq!(s.get(i, None, &guard), None);
+        }
+
+        // the store is still usable
+        s.set(Node::new(1, 0, Shared::boxed(7, &collector), None), &guard);
+        assert_eq!(s.get(1, None, &guard), Some(&7));
+    }
+
+    #[test]
+    fn test_deep_clone() {
+        let collector = Collector::new();
+        let guard = collector.enter();
+        let mut s = Store::new();
+        let mut policy = DefaultPolicy::<u64>::new(100, 10, std::ptr::null());
+
+        for i in 0..4u64 {
+            let (key, conflict) = i.to_hash();
+            let expiration = if i % 2 == 0 { Some(now() + Duration::from_millis(10)) } else { None };
+            policy.add(key, 1, &guard);
+            s.set(Node::new(key, conflict, Shared::boxed(i, &collector), expiration), &guard);
+        }
+
+        let mut c = s.deep_clone(&guard);
+        assert_eq!(c.data.len(), s.data.len());
+        assert_eq!(c.len(), 4);
+
+        std::thread::sleep(Duration::from_millis(20));
+        c.clean_up(&mut policy, &guard);
+        assert_eq!(c.len(), 2);
+        for i in 0..4u64 {
+            let (key, conflict) = i.to_hash();
+            let v = c.get(key, Some(conflict), &guard);
+            assert_eq!(v, if i % 2 == 0 { None } else { Some(&i) });
+        }
+
+        // the original is untouched and still owns its values
+        assert_eq!(s.len(), 4);
+        let (key, conflict) = 1u64.to_hash();
+        assert_eq!(s.get(key, Some(conflict), &guard), Some(&1));
+    }
+
     #[test]
     fn test_set_clear() {
         let collector = Collector::new();
@@ -327,7 +966,7 @@ mod tests {
         let mut s = Store::new();
 
         for i in 0..20 {
-            let (key, confilict) = key_to_hash(&i);
+            let (key, confilict) = i.to_hash();
             let value = Shared::boxed(i + 2, &collector);
             let node = Node::new(key, confilict, value, None);
 
@@ -336,8 +975,8 @@ mod tests {
         s.clear(&guard);
 
         for i in 0..20 {
-            let (key, confilict) = key_to_hash(&i);
-            let v = s.get(key, confilict, &guard);
+            let (key, confilict) = i.to_hash();
+            let v = s.get(key, Some(confilict), &guard);
             assert_eq!(v, None)
         }
     }
@@ -349,17 +988,17 @@ mod tests {
         let mut s = Store::new();
 
         for i in 0..20 {
-            let (key, confilict) = key_to_hash(&i);
+            let (key, confilict) = i.to_hash();
             let value = Shared::boxed(i + 2, &collector);
             let node = Node::new(key, confilict, value, None);
 
             s.set(node, &guard);
-            let v = s.get(key, confilict, &guard);
+            let v = s.get(key, Some(confilict), &guard);
             assert_eq!(v, Some(&(i + 2)))
         }
 
         for i in 0..20 {
-            let (key, conflict) = key_to_hash(&i);
+            let (key, conflict) = i.to_hash();
             let value = Shared::boxed(i + 4, &collector);
             let item = Item {
                 flag: ItemNew,
@@ -370,11 +1009,37 @@ mod tests {
                 expiration: None,
             };
             s.update(&item, &guard);
-            let v = s.get(key, conflict, &guard);
+            let v = s.get(key, Some(conflict), &guard);
             assert_eq!(v, Some(&(i + 4)))
         }
     }
 
+    #[test]
+    fn test_set_keep_existing() {
+        let collector = Collector::new();
+        let guard = collector.enter();
+        let mut s = Store::new();
+        s.on_conflict = ConflictPolicy::KeepExisting;
+
+        let (key, conflict) = 1.to_hash();
+        s.set(Node::new(key, conflict, Shared::boxed(1, &collector), None), &guard);
+        s.set(Node::new(key, conflict, Shared::boxed(2, &collector), None), &guard);
+        assert_eq!(s.get(key, Some(conflict), &guard), Some(&1));
+
+        let value = Shared::boxed(3, &collector);
+        let item = Item {
+            flag: ItemNew,
+            key,
+            conflict,
+            value: value.into(),
+            cost: 0,
+            expiration: None,
+        };
+        assert!(s.update(&item, &guard));
+        assert_eq!(s.get(key, Some(conflict), &guard), Some(&1));
+        let _ = unsafe { value.into_box() };
+    }
+
     #[test]
     fn test_set_collision() {
         let collector = Collector::new();
@@ -384,14 +1049,14 @@ mod tests {
 
         let node = Node::new(1, 0, value, None);
         s.data.get_mut(1).unwrap().insert(1, node);
-        let v = s.get(1, 1, &guard);
+        let v = s.get(1, Some(1), &guard);
         assert_eq!(v, None);
 
 
         let value = Shared::boxed(2, &collector);
         let node = Node::new(1, 1, value, None);
         s.set(node, &guard);
-        let v = s.get(1, 0, &guard);
+        let v = s.get(1, Some(0), &guard);
         assert_ne!(v, Some(&2));
         let item = Item {
             flag: ItemNew,
@@ -403,8 +1068,8 @@ mod tests {
         };
         assert_eq!(s.update(&item, &guard), false);
 
-        s.del(&1, &1, &guard);
-        let v = s.get(1, 0, &guard);
+        s.del(&1, Some(1), &guard);
+        let v = s.get(1, Some(0), &guard);
         assert_eq!(v, Some(&1));
     }
 }
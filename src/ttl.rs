@@ -26,6 +26,14 @@ pub type OnEvict<V> = fn (u64, u64, V, i64);
 /// TODO: find the optimal value or make it configurable.
 const BUCKET_DURATION_SECS: i64 = 5;
 
+/// now returns the current time as a duration since the unix epoch. Expiration
+/// deadlines are stored in the same representation.
+pub(crate) fn now() -> Duration {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .expect("Duration since UNIX_EPOCH failed")
+}
+
 impl ExpirationMap {
     pub fn new() -> Self {
         ExpirationMap {
@@ -35,66 +43,68 @@ impl ExpirationMap {
     }
 
     fn storage_bucket(&self, t: Duration) -> i64 {
-        return (t.as_millis() as i64 / BUCKET_DURATION_SECS) as i64;
+        (t.as_millis() as i64 / BUCKET_DURATION_SECS) + 1
+    }
+
+    fn cleanup_bucket(&self, t: Duration) -> i64 {
+        // The bucket to cleanup is always behind the storage bucket by one so that
+        // no elements in that bucket (which might not have expired yet) are deleted.
+        self.storage_bucket(t) - 1
     }
 
     pub fn update<'g>(&'g self, key: u64, conflict: u64, old_expiration_time: Duration, new_exp_time: Duration, guard: &'g Guard) {
         let buckets = self.buckets.load(Ordering::SeqCst, guard);
-        let lock  = self.lock.lock();
-        loop {
-            if buckets.is_null() || !unsafe { buckets.deref() }.is_empty() {
-                drop(lock);
-                return;
-            }
+        let lock = self.lock.lock();
+        if buckets.is_null() {
+            drop(lock);
+            return;
+        }
 
-            let old_bucket_num = self.storage_bucket(old_expiration_time);
+        let old_bucket_num = self.storage_bucket(old_expiration_time);
 
-            let buckets = unsafe { buckets.as_ptr() };
-            let buckets = unsafe { buckets.as_mut().unwrap() };
-            match buckets.get_mut(&old_bucket_num) {
-                None => {}
-                Some(old_bucket) => {
-                    old_bucket.remove(&key);
-                }
+        let buckets = unsafe { buckets.as_ptr() };
+        let buckets = unsafe { buckets.as_mut().unwrap() };
+        match buckets.get_mut(&old_bucket_num) {
+            None => {}
+            Some(old_bucket) => {
+                old_bucket.remove(&key);
             }
+        }
 
-            let new_bucket_num = self.storage_bucket(new_exp_time);
-
-            match buckets.get_mut(&new_bucket_num) {
-                None => {
-                    let b = Bucket::new();
-                    buckets.insert(new_bucket_num, b);
-                }
-                Some(b) => {
-                    b.insert(key, conflict);
-                }
-            };
-            drop(lock);
-            break;
+        let new_bucket_num = self.storage_bucket(new_exp_time);
+        buckets.entry(new_bucket_num).or_insert_with(Bucket::new).insert(key, conflict);
+        drop(lock);
+    }
+
+    /// clear forgets every deadline.
+    pub fn clear<'g>(&'g self, guard: &'g Guard) {
+        let buckets = self.buckets.load(Ordering::SeqCst, guard);
+        let lock = self.lock.lock();
+        if let Some(buckets) = unsafe { buckets.as_ptr().as_mut() } {
+            buckets.clear();
         }
+        drop(lock);
     }
+
     pub fn del<'g>(&'g self, key: &u64, expiration: Duration, guard: &'g Guard) {
         let buckets = self.buckets.load(Ordering::SeqCst, guard);
-        loop {
-            if buckets.is_null() || !unsafe { buckets.deref() }.is_empty() {
-                return;
-            }
-
+        let lock = self.lock.lock();
+        if buckets.is_null() {
+            drop(lock);
+            return;
+        }
 
-            let buckets = unsafe { buckets.as_ptr() };
-            let buckets = unsafe { buckets.as_mut().unwrap() };
-            let bucket_num = self.storage_bucket(expiration);
+        let buckets = unsafe { buckets.as_ptr() };
+        let buckets = unsafe { buckets.as_mut().unwrap() };
+        let bucket_num = self.storage_bucket(expiration);
 
-            match buckets.get_mut(&bucket_num) {
-                None => {}
-                Some(b) => {
-                    b.remove(key);
-                }
+        match buckets.get_mut(&bucket_num) {
+            None => {}
+            Some(b) => {
+                b.remove(key);
             }
-            break;
-
-            break;
         }
+        drop(lock);
     }
 
     pub fn add<'g>(&'g self, key: u64, conflict: u64, expiration: Duration, guard: &'g Guard) {
@@ -102,69 +112,86 @@ impl ExpirationMap {
         if expiration.is_zero() {
             return;
         }
-        let mut buckets = self.buckets.load(Ordering::SeqCst, guard);
-        let lock  = self.lock.lock();
-        loop {
-            if buckets.is_null() || !unsafe { buckets.deref() }.is_empty() {
-                buckets = self.init_buckets(guard);
-            }
+        let lock = self.lock.lock();
+        let buckets = self.init_buckets(guard);
+
+        let buckets = unsafe { buckets.as_ptr() };
+        let buckets = unsafe { buckets.as_mut().unwrap() };
+        let bucket_num = self.storage_bucket(expiration);
+        buckets.entry(bucket_num).or_insert_with(Bucket::new).insert(key, conflict);
+        drop(lock);
+    }
 
-            let buckets = unsafe { buckets.as_ptr() };
-            let buckets = unsafe { buckets.as_mut().unwrap() };
-            let bucket_num = self.storage_bucket(expiration);
-
-            match buckets.get_mut(&bucket_num) {
-                None => {
-                    let b = Bucket::new();
-                    buckets.insert(bucket_num, b);
-                }
-                Some(b) => {
-                    b.insert(key, conflict);
-                }
+    /// compact drops the buckets that no longer hold a key, which update and del
+    /// leave behind, and returns how many buckets are left.
+    pub(crate) fn compact<'g>(&'g self, guard: &'g Guard) -> usize {
+        let buckets = self.buckets.load(Ordering::SeqCst, guard);
+        let lock = self.lock.lock();
+        let left = match unsafe { buckets.as_ptr().as_mut() } {
+            Some(buckets) => {
+                buckets.retain(|_, b| !b.is_empty());
+                buckets.shrink_to_fit();
+                buckets.len()
             }
-            drop(lock);
-            break;
-        }
+            None => 0,
+        };
+        drop(lock);
+        left
     }
-    fn init_buckets<'g>(&'g self, guard: &'g Guard) -> Shared<'g, HashMap<i64, Bucket>> {
-        loop {
-            let mut table = self.buckets.load(Ordering::SeqCst, guard);
-            if !table.is_null() && !unsafe { table.deref() }.is_empty() {
-                break table;
-            }
 
+    fn init_buckets<'g>(&'g self, guard: &'g Guard) -> Shared<'g, HashMap<i64, Bucket>> {
+        let mut table = self.buckets.load(Ordering::SeqCst, guard);
+        if table.is_null() {
             table = Shared::boxed(HashMap::new(), guard.collector().unwrap());
             self.buckets.store(table, Ordering::SeqCst);
+        }
+        table
+    }
 
-            break table;
+    /// expiring_before returns the keys of every bucket that may hold a deadline
+    /// up to t. The buckets are coarse, callers check the exact deadlines.
+    pub(crate) fn expiring_before<'g>(&'g self, t: Duration, guard: &'g Guard) -> Vec<(u64, u64)> {
+        let buckets = self.buckets.load(Ordering::SeqCst, guard);
+        let lock = self.lock.lock();
+        if buckets.is_null() {
+            drop(lock);
+            return vec![];
         }
+
+        let buckets = unsafe { buckets.deref() };
+        let bucket_num = self.storage_bucket(t);
+        let keys = buckets.iter()
+            .filter(|(num, _)| **num <= bucket_num)
+            .flat_map(|(_, b)| b.iter().map(|(k, c)| (*k, *c)))
+            .collect();
+        drop(lock);
+        keys
     }
 
+    /// cleanup drains every bucket that expired by now and returns their keys, so
+    /// a janitor that ticks late catches up in one pass. Keys that were given a
+    /// later deadline have already moved to another bucket.
     pub(crate) fn cleanup<'g, V>(&'g self, _policy: &mut DefaultPolicy<V>, _f: Option<OnEvict<&V>>, guard: &'g Guard) -> HashMap<u64,u64>{
         let buckets = self.buckets.load(Ordering::SeqCst, guard);
         let mut items_in_store = HashMap::new();
-        loop {
-            if buckets.is_null() || !unsafe { buckets.deref() }.is_empty() {
-                break items_in_store;
-            }
+        let lock = self.lock.lock();
+        if buckets.is_null() {
+            drop(lock);
+            return items_in_store;
+        }
+
+        let buckets = unsafe { buckets.as_ptr() };
+        let keys = unsafe { buckets.as_mut().unwrap() };
 
-            let buckets = unsafe { buckets.as_ptr() };
-            let keys = unsafe { buckets.as_mut().unwrap() };
-
-            let d = time::SystemTime::now();
-            let now = d.elapsed().unwrap();
-            let bucket_num = self.storage_bucket(now);
-            match keys.get_mut(&bucket_num) {
-                None => {
-                    break items_in_store;
-                }
-                Some(maps) => {
-                    for (key, confilct) in &*maps {
-                        items_in_store.insert(*key,*confilct);
-                    }
-                    break items_in_store;
-                }
+        let bucket_num = self.cleanup_bucket(now());
+        keys.retain(|num, maps| {
+            if *num > bucket_num {
+                return true;
             }
-        }
+            items_in_store.extend(maps.drain());
+            false
+        });
+        drop(lock);
+        items_in_store
     }
-}
\ No newline at end of file
+}
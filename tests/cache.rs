@@ -127,6 +127,7 @@ fn test_cache_with_ttl() {
 
     thread::sleep(std::time::Duration::from_secs(5));
     drop(g);
+    let guard = cache.guard();
     for i in 0..ITER {
         assert_eq!(cache.get(&i, &guard), None)
     }